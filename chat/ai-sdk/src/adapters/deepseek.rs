@@ -28,127 +28,184 @@
 //!   "system_fingerprint": "fp_3a5770e1b4_prod0225"
 //! }
 
-use crate::AiService;
-use crate::Message;
-use reqwest::Client;
-use serde::Deserialize;
-use serde::Serialize;
+use crate::{AiService, CompletionArgs, OpenAiCompatAdapter, ResponseFormat};
+use crate::{Completion, CompletionStream, Message, Tool};
 
+/// the deepseek api speaks the openai protocol, only its models and the args they
+/// support differ
 #[derive(Debug, Clone)]
-pub struct DeepSeekAdapter {
-    host: String,
-    api_key: String,
-    model: String,
-    client: Client,
-}
+pub struct DeepSeekAdapter(OpenAiCompatAdapter);
 
-#[derive(Debug, Clone, Serialize)]
-pub struct DeepSeekChatCompletionRequest {
-    pub model: String,
-    pub messages: Vec<DeepSeekMessage>,
-}
+pub const DEEPSEEK_BASE_URL: &str = "https://api.deepseek.com";
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct DeepSeekMessage {
-    pub role: String,
-    pub content: String,
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct DeepSeekChatCompletionResponse {
-    pub id: String,
-    pub object: String,
-    pub created: i64,
-    pub model: String,
-    pub choices: Vec<DeepSeekChoice>,
-    pub usage: DeepSeekUsage,
-    pub system_fingerprint: String,
-}
+/// models served by the deepseek api
+pub const DEEPSEEK_MODELS: [&str; 2] = ["deepseek-chat", "deepseek-reasoner"];
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct DeepSeekChoice {
-    pub index: u32,
-    pub message: DeepSeekMessage,
-    pub logprobs: Option<i64>,
-    pub finish_reason: String,
-}
+impl DeepSeekAdapter {
+    pub fn new(api_key: impl Into<String>, model: impl Into<String>) -> Self {
+        Self(OpenAiCompatAdapter::new(
+            DEEPSEEK_BASE_URL,
+            Some(api_key.into()),
+            model,
+        ))
+    }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct DeepSeekUsage {
-    pub prompt_tokens: u32,
-    pub completion_tokens: u32,
-    pub total_tokens: u32,
-    pub prompt_tokens_details: DeepSeekPromptTokensDetails,
-    pub prompt_cache_hit_tokens: u32,
-    pub prompt_cache_miss_tokens: u32,
-}
+    /// send the args with every request
+    pub fn with_args(self, args: CompletionArgs) -> Self {
+        Self(self.0.with_args(request_args(args)))
+    }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct DeepSeekPromptTokensDetails {
-    pub cached_tokens: u32,
+    /// check the model is served by deepseek and supports the args. The reasoner
+    /// doesn't take sampling options nor a response format. Only JSON objects are
+    /// supported natively, a schema is sent as such and enforced by validation, see
+    /// [`AiService::complete_json`]
+    pub fn check_args(model: &str, args: &CompletionArgs) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            DEEPSEEK_MODELS.contains(&model),
+            "model {model} is not supported by deepseek, use one of {}",
+            DEEPSEEK_MODELS.join(", ")
+        );
+        args.validate()?;
+        anyhow::ensure!(
+            args.max_tokens.is_none_or(|n| n <= 8192),
+            "max_tokens must be at most 8192"
+        );
+        anyhow::ensure!(
+            args.stop.len() <= 16,
+            "at most 16 stop sequences are supported"
+        );
+        if model == "deepseek-reasoner" {
+            anyhow::ensure!(
+                args.temperature.is_none() && args.top_p.is_none(),
+                "deepseek-reasoner doesn't support temperature nor top_p"
+            );
+            anyhow::ensure!(
+                args.response_format.is_none(),
+                "deepseek-reasoner doesn't support response_format"
+            );
+        }
+        Ok(())
+    }
 }
 
-impl DeepSeekAdapter {
-    pub fn new(api_key: impl Into<String>, model: impl Into<String>) -> Self {
-        Self {
-            host: "https://api.deepseek.com".to_string(),
-            api_key: api_key.into(),
-            model: model.into(),
-            client: Client::new(),
-        }
+/// a JSON schema is asked for as a JSON object
+fn request_args(mut args: CompletionArgs) -> CompletionArgs {
+    if args.json() {
+        args.response_format = Some(ResponseFormat::JsonObject);
     }
+    args
 }
 
 impl AiService for DeepSeekAdapter {
-    async fn complete(&self, messages: &[Message]) -> anyhow::Result<String> {
-        let request = DeepSeekChatCompletionRequest {
-            model: self.model.clone(),
-            messages: messages.iter().map(|m| m.into()).collect(),
-        };
-        let url = format!("{}/chat/completions", self.host);
-        let response = self
-            .client
-            .post(url)
-            .header("Authorization", format!("Bearer {}", self.api_key))
-            .json(&request)
-            .send()
-            .await?;
-        let mut data = response.json::<DeepSeekChatCompletionResponse>().await?;
-        let content = data
-            .choices
-            .pop()
-            .ok_or(anyhow::anyhow!("No response"))?
-            .message
-            .content;
-        Ok(content)
+    async fn complete(&self, messages: &[Message]) -> anyhow::Result<Completion> {
+        self.0.complete(messages).await
     }
-}
 
-impl From<&Message> for DeepSeekMessage {
-    fn from(value: &Message) -> Self {
-        Self {
-            role: value.role.to_string(),
-            content: value.content.clone(),
-        }
+    async fn complete_stream(&self, messages: &[Message]) -> anyhow::Result<CompletionStream> {
+        self.0.complete_stream(messages).await
+    }
+
+    async fn complete_with_tools(
+        &self,
+        messages: &[Message],
+        tools: &[Tool],
+    ) -> anyhow::Result<Completion> {
+        self.0.complete_with_tools(messages, tools).await
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::Role;
     use crate::get_deepseek_api_key;
+    use crate::{CompletionChunk, OpenAiChatCompletionResponse, Role, Usage};
+    use futures::TryStreamExt;
 
     #[ignore]
     #[tokio::test]
     async fn deepseek_complete_should_work() {
-        let api_key = get_deepseek_api_key();
+        let api_key = get_deepseek_api_key().expect("DEEPSEEK_KEY must be set");
         let adapter = DeepSeekAdapter::new(api_key, "deepseek-chat");
-        let messages = vec![Message {
-            role: Role::User,
-            content: "中国最长的河流是".to_string(),
-        }];
+        let messages = vec![Message::new(Role::User, "中国最长的河流是")];
         let responnse = adapter.complete(&messages).await.unwrap();
-        assert!(responnse.len() > 0)
+        assert!(!responnse.message.content.is_empty());
+        assert!(responnse.usage.total_tokens() > 0)
+    }
+
+    #[ignore]
+    #[tokio::test]
+    async fn deepseek_complete_stream_should_work() {
+        let api_key = get_deepseek_api_key().expect("DEEPSEEK_KEY must be set");
+        let adapter = DeepSeekAdapter::new(api_key, "deepseek-chat");
+        let messages = vec![Message::user("中国最长的河流是")];
+        let stream = adapter.complete_stream(&messages).await.unwrap();
+        let chunks: Vec<CompletionChunk> = stream.try_collect().await.unwrap();
+        assert!(chunks.len() > 1);
+        assert!(matches!(chunks.last(), Some(CompletionChunk::Usage(_))))
+    }
+
+    #[test]
+    fn deepseek_usage_should_count_cache_hits() {
+        let response: OpenAiChatCompletionResponse = serde_json::from_value(serde_json::json!({
+            "id": "a6250b7d-ed69-4e1e-983e-8b501d807403",
+            "object": "chat.completion",
+            "created": 1741052005,
+            "model": "deepseek-chat",
+            "choices": [{
+                "index": 0,
+                "message": { "role": "assistant", "content": "长江" },
+                "logprobs": null,
+                "finish_reason": "stop"
+            }],
+            "usage": {
+                "prompt_tokens": 48,
+                "completion_tokens": 13,
+                "total_tokens": 61,
+                "prompt_cache_hit_tokens": 32,
+                "prompt_cache_miss_tokens": 16
+            },
+            "system_fingerprint": "fp_3a5770e1b4_prod0225"
+        }))
+        .unwrap();
+        let usage: Usage = response.usage.unwrap().into();
+        assert_eq!(usage.cache_hit_tokens, 32);
+    }
+
+    #[test]
+    fn deepseek_check_args_should_work() {
+        let args = CompletionArgs {
+            temperature: Some(1.3),
+            stop: vec!["END".to_string()],
+            ..Default::default()
+        };
+        assert!(DeepSeekAdapter::check_args("deepseek-chat", &args).is_ok());
+        assert!(DeepSeekAdapter::check_args("gpt-4o", &args).is_err());
+        assert!(DeepSeekAdapter::check_args("deepseek-reasoner", &args).is_err());
+        let args = CompletionArgs {
+            top_p: Some(1.5),
+            ..Default::default()
+        };
+        assert!(DeepSeekAdapter::check_args("deepseek-chat", &args).is_err());
+        let args = CompletionArgs {
+            max_tokens: Some(4096),
+            ..Default::default()
+        };
+        assert!(DeepSeekAdapter::check_args("deepseek-reasoner", &args).is_ok());
+
+        // only json objects are supported, the schema is checked on the answer
+        let json_schema = crate::JsonSchema {
+            name: "labels".to_string(),
+            schema: serde_json::json!({ "type": "object" }),
+            strict: None,
+        };
+        let args = CompletionArgs {
+            response_format: Some(ResponseFormat::JsonSchema { json_schema }),
+            ..Default::default()
+        };
+        assert!(DeepSeekAdapter::check_args("deepseek-chat", &args).is_ok());
+        assert_eq!(
+            request_args(args).response_format,
+            Some(ResponseFormat::JsonObject)
+        );
     }
 }
@@ -0,0 +1,321 @@
+//! deterministic adapter for tests and local development, no network involved.
+//! It echoes the last user message, answers with scripted responses or looks the
+//! answer up in a JSON fixture file, and records the prompts it received.
+//!
+//! a fixture file is a list of prompt/response pairs, the first pair whose prompt is
+//! contained in the last user message wins:
+//! [{"prompt": "hello", "response": "你好"}, {"prompt": "", "response": "fallback"}]
+//!
+//! [`MockEmbedder`] is its counterpart for embeddings.
+
+use crate::{
+    AiService, Completion, CompletionArgs, CompletionChunk, CompletionStream,
+    DEFAULT_EMBEDDING_BATCH, EmbeddingService, Message, Role, Usage, normalize,
+};
+use futures::{StreamExt, stream};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MockResponse {
+    /// echo the content of the last user message
+    Echo,
+    /// answer with the responses in turn, starting over when exhausted
+    Script(Vec<String>),
+    /// answer from a JSON fixture file, read on every call
+    Fixture(PathBuf),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MockFixture {
+    pub prompt: String,
+    pub response: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct MockAdapter {
+    response: MockResponse,
+    args: CompletionArgs,
+    /// prompts received, shared by the clones of the adapter
+    prompts: Arc<Mutex<Vec<Vec<Message>>>>,
+}
+
+impl MockAdapter {
+    pub fn new(response: MockResponse) -> Self {
+        Self {
+            response,
+            args: CompletionArgs::default(),
+            prompts: Default::default(),
+        }
+    }
+
+    /// the args aren't used to answer, only kept to check they're forwarded
+    pub fn with_args(self, args: CompletionArgs) -> Self {
+        Self { args, ..self }
+    }
+
+    pub fn args(&self) -> &CompletionArgs {
+        &self.args
+    }
+
+    /// any model name is a valid mock, see [`MockAdapter::from_model`]
+    pub fn check_args(_model: &str, args: &CompletionArgs) -> anyhow::Result<()> {
+        args.validate()
+    }
+
+    pub fn echo() -> Self {
+        Self::new(MockResponse::Echo)
+    }
+
+    pub fn script<T: Into<String>>(responses: impl IntoIterator<Item = T>) -> Self {
+        Self::new(MockResponse::Script(
+            responses.into_iter().map(Into::into).collect(),
+        ))
+    }
+
+    pub fn fixture(path: impl Into<PathBuf>) -> Self {
+        Self::new(MockResponse::Fixture(path.into()))
+    }
+
+    /// build from the model name of an agent: `echo` (or empty), `fixture:<path>`, or
+    /// the scripted responses separated by `|`
+    pub fn from_model(model: &str) -> Self {
+        match model {
+            "" | "echo" => Self::echo(),
+            _ => match model.strip_prefix("fixture:") {
+                Some(path) => Self::fixture(path),
+                None => Self::script(model.split('|')),
+            },
+        }
+    }
+
+    /// the messages of every call received so far, in order
+    pub fn prompts(&self) -> Vec<Vec<Message>> {
+        self.prompts.lock().unwrap().clone()
+    }
+
+    fn answer(&self, messages: &[Message]) -> anyhow::Result<String> {
+        let call = {
+            let mut prompts = self.prompts.lock().unwrap();
+            prompts.push(messages.to_vec());
+            prompts.len() - 1
+        };
+        let last = messages
+            .iter()
+            .rev()
+            .find(|m| m.role == Role::User)
+            .map(|m| m.content.as_str())
+            .unwrap_or_default();
+        match &self.response {
+            MockResponse::Echo => Ok(last.to_string()),
+            MockResponse::Script(responses) if responses.is_empty() => {
+                Err(anyhow::anyhow!("mock script is empty"))
+            }
+            MockResponse::Script(responses) => Ok(responses[call % responses.len()].clone()),
+            MockResponse::Fixture(path) => {
+                let fixtures: Vec<MockFixture> = serde_json::from_slice(&std::fs::read(path)?)?;
+                fixtures
+                    .into_iter()
+                    .find(|f| last.contains(&f.prompt))
+                    .map(|f| f.response)
+                    .ok_or_else(|| anyhow::anyhow!("no fixture matches: {last}"))
+            }
+        }
+    }
+}
+
+impl AiService for MockAdapter {
+    async fn complete(&self, messages: &[Message]) -> anyhow::Result<Completion> {
+        let answer = self.answer(messages)?;
+        let usage = Usage::new(prompt_tokens(messages), count_tokens(&answer));
+        Ok(Completion::new(Message::assistant(answer), usage))
+    }
+
+    /// stream the answer word by word
+    async fn complete_stream(&self, messages: &[Message]) -> anyhow::Result<CompletionStream> {
+        let completion = self.complete(messages).await?;
+        let deltas: Vec<_> = completion
+            .message
+            .content
+            .split_inclusive(' ')
+            .map(|delta| Ok(CompletionChunk::Delta(delta.to_string())))
+            .chain([Ok(CompletionChunk::Usage(completion.usage))])
+            .collect();
+        Ok(stream::iter(deltas).boxed())
+    }
+}
+
+/// deterministic embedder: every word, or CJK character, is hashed to a dimension,
+/// so texts sharing words are similar. Records the size of the batches it embeds
+#[derive(Debug, Clone)]
+pub struct MockEmbedder {
+    dimensions: usize,
+    batch_size: usize,
+    batches: Arc<Mutex<Vec<usize>>>,
+}
+
+impl MockEmbedder {
+    pub fn new(dimensions: usize) -> Self {
+        Self {
+            dimensions,
+            batch_size: DEFAULT_EMBEDDING_BATCH,
+            batches: Default::default(),
+        }
+    }
+
+    pub fn with_batch_size(self, batch_size: usize) -> Self {
+        Self { batch_size, ..self }
+    }
+
+    /// the size of every batch embedded so far, in order
+    pub fn batches(&self) -> Vec<usize> {
+        self.batches.lock().unwrap().clone()
+    }
+
+    /// unit vector of the words of the text, zero for a text without words
+    pub fn embed_text(&self, text: &str) -> Vec<f32> {
+        let mut vector = vec![0.0; self.dimensions];
+        if self.dimensions == 0 {
+            return vector;
+        }
+        for word in words(text) {
+            // FNV-1a, stable across runs and platforms
+            let hash = word.bytes().fold(0xcbf29ce484222325u64, |h, b| {
+                (h ^ b as u64).wrapping_mul(0x100000001b3)
+            });
+            vector[(hash % self.dimensions as u64) as usize] += 1.0;
+        }
+        normalize(&mut vector);
+        vector
+    }
+}
+
+impl EmbeddingService for MockEmbedder {
+    fn model(&self) -> &str {
+        "mock"
+    }
+
+    fn dimensions(&self) -> usize {
+        self.dimensions
+    }
+
+    fn batch_size(&self) -> usize {
+        self.batch_size
+    }
+
+    async fn embed_batch(&self, input: &[String]) -> anyhow::Result<Vec<Vec<f32>>> {
+        self.batches.lock().unwrap().push(input.len());
+        Ok(input.iter().map(|text| self.embed_text(text)).collect())
+    }
+}
+
+/// lowercased ascii words, other letters (e.g. CJK) one by one
+fn words(text: &str) -> Vec<String> {
+    let mut words = vec![];
+    let mut word = String::new();
+    for c in text.chars().flat_map(char::to_lowercase) {
+        if c.is_ascii_alphanumeric() {
+            word.push(c);
+            continue;
+        }
+        if !word.is_empty() {
+            words.push(std::mem::take(&mut word));
+        }
+        if c.is_alphanumeric() {
+            words.push(c.to_string());
+        }
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    words
+}
+
+/// whitespace separated words stand in for tokens
+fn count_tokens(content: &str) -> u32 {
+    content.split_whitespace().count() as u32
+}
+
+fn prompt_tokens(messages: &[Message]) -> u32 {
+    messages.iter().map(|m| count_tokens(&m.content)).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::TryStreamExt;
+
+    #[tokio::test]
+    async fn mock_adapter_should_echo_and_record_prompts() {
+        let adapter = MockAdapter::from_model("echo");
+        let messages = [Message::system("be nice"), Message::user("hello world")];
+        let completion = adapter.clone().complete(&messages).await.unwrap();
+        assert_eq!(completion.message.content, "hello world");
+        assert_eq!(completion.usage, Usage::new(4, 2));
+
+        let prompts = adapter.prompts();
+        assert_eq!(prompts.len(), 1);
+        assert_eq!(prompts[0][0].content, "be nice");
+    }
+
+    #[tokio::test]
+    async fn mock_adapter_should_follow_script() {
+        let adapter = MockAdapter::from_model("first answer|second");
+        let messages = [Message::user("hi")];
+        let chunks: Vec<CompletionChunk> = adapter
+            .complete_stream(&messages)
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(
+            chunks,
+            vec![
+                CompletionChunk::Delta("first ".to_string()),
+                CompletionChunk::Delta("answer".to_string()),
+                CompletionChunk::Usage(Usage::new(1, 2)),
+            ]
+        );
+        for expected in ["second", "first answer"] {
+            let completion = adapter.complete(&messages).await.unwrap();
+            assert_eq!(completion.message.content, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn mock_adapter_should_answer_from_fixture() {
+        let fixtures = [
+            MockFixture {
+                prompt: "hello".to_string(),
+                response: "你好".to_string(),
+            },
+            MockFixture {
+                prompt: "".to_string(),
+                response: "?".to_string(),
+            },
+        ];
+        let path = std::env::temp_dir().join(format!("mock-{}.json", std::process::id()));
+        std::fs::write(&path, serde_json::to_vec(&fixtures).unwrap()).unwrap();
+
+        let adapter = MockAdapter::from_model(&format!("fixture:{}", path.display()));
+        let answer = adapter.complete(&[Message::user("hello")]).await.unwrap();
+        assert_eq!(answer.message.content, "你好");
+        let answer = adapter.complete(&[Message::user("bye")]).await.unwrap();
+        assert_eq!(answer.message.content, "?");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn mock_embedder_should_be_deterministic() {
+        let embedder = MockEmbedder::new(64);
+        let a = embedder.embed_text("The match was great");
+        assert_eq!(a, embedder.embed_text("the MATCH, was great!"));
+        let b = embedder.embed_text("great match");
+        let c = embedder.embed_text("tomorrow's weather");
+        assert!(crate::cosine_similarity(&a, &b) > crate::cosine_similarity(&a, &c));
+        assert_eq!(words("长江 river"), ["长", "江", "river"]);
+        assert!(embedder.embed_text("").iter().all(|x| *x == 0.0));
+    }
+}
@@ -1,3 +1,9 @@
 mod deepseek;
+mod mock;
+mod ollama;
+mod openai;
 
 pub use deepseek::*;
+pub use mock::*;
+pub use ollama::*;
+pub use openai::*;
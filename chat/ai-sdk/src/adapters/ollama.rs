@@ -0,0 +1,395 @@
+//! ollama native `/api/chat` adapter, the response is NDJSON, one object per line
+//! {"model":"llama3.2","created_at":"2025-03-10T02:13:17.5Z","message":{"role":"assistant","content":"长江"},"done":false}
+//! {"model":"llama3.2","created_at":"2025-03-10T02:13:17.6Z","message":{"role":"assistant","content":""},"done":true,"done_reason":"stop","prompt_eval_count":26,"eval_count":2}
+//!
+//! when `stream` is false, the whole response is a single line with `done: true`.
+//! [`OllamaEmbedder`] calls `/api/embed`.
+
+use crate::Message;
+use crate::{AiService, CompletionArgs, EmbeddingService};
+use crate::{Completion, CompletionChunk, CompletionStream, Usage};
+use crate::{check_status, http_client, line_stream};
+use futures::{StreamExt, TryStreamExt};
+use reqwest::Client;
+use serde::Deserialize;
+use serde::Serialize;
+
+#[derive(Debug, Clone)]
+pub struct OllamaAdapter {
+    host: String,
+    model: String,
+    args: CompletionArgs,
+    client: Client,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct OllamaChatRequest {
+    pub model: String,
+    pub messages: Vec<OllamaMessage>,
+    pub stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub options: Option<OllamaOptions>,
+    /// `"json"` to constrain the answer to valid JSON, or the JSON schema it has to match
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<serde_json::Value>,
+}
+
+/// sampling options, ollama calls the max tokens `num_predict`
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct OllamaOptions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub num_predict: Option<u32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub stop: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OllamaMessage {
+    pub role: String,
+    #[serde(default)]
+    pub content: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OllamaChatResponse {
+    pub model: String,
+    #[serde(default)]
+    pub created_at: String,
+    #[serde(default)]
+    pub message: Option<OllamaMessage>,
+    pub done: bool,
+    #[serde(default)]
+    pub done_reason: Option<String>,
+    #[serde(default)]
+    pub prompt_eval_count: Option<u32>,
+    #[serde(default)]
+    pub eval_count: Option<u32>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct OllamaError {
+    error: String,
+}
+
+impl OllamaAdapter {
+    pub fn new(host: impl Into<String>, model: impl Into<String>) -> Self {
+        let host: String = host.into();
+        Self {
+            host: host.trim_end_matches('/').to_string(),
+            model: model.into(),
+            args: CompletionArgs::default(),
+            client: http_client(),
+        }
+    }
+
+    /// send the args with every request
+    pub fn with_args(self, args: CompletionArgs) -> Self {
+        Self { args, ..self }
+    }
+
+    /// check the args, the model has to be pulled on the host so any name goes
+    pub fn check_args(model: &str, args: &CompletionArgs) -> anyhow::Result<()> {
+        anyhow::ensure!(!model.is_empty(), "ollama needs a model name");
+        args.validate()
+    }
+
+    async fn send(&self, messages: &[Message], stream: bool) -> anyhow::Result<reqwest::Response> {
+        let request = OllamaChatRequest {
+            model: self.model.clone(),
+            messages: messages.iter().map(|m| m.into()).collect(),
+            stream,
+            options: OllamaOptions::from_args(&self.args),
+            format: match self.args.schema() {
+                Some(schema) => Some(schema.clone()),
+                None => self.args.json().then(|| "json".into()),
+            },
+        };
+        let url = format!("{}/api/chat", self.host);
+        let response = self.client.post(url).json(&request).send().await?;
+        check_status(response, |body| {
+            serde_json::from_str::<OllamaError>(&body)
+                .map(|e| format!("ollama error: {}", e.error))
+                .unwrap_or(body)
+        })
+        .await
+    }
+}
+
+impl AiService for OllamaAdapter {
+    async fn complete(&self, messages: &[Message]) -> anyhow::Result<Completion> {
+        let body = self.send(messages, false).await?.text().await?;
+        let mut content = String::new();
+        let mut usage = None;
+        for line in body.lines().filter(|l| !l.trim().is_empty()) {
+            let chunk = parse_ndjson_line(line)?;
+            if let Some(message) = &chunk.message {
+                content.push_str(&message.content);
+            }
+            usage = chunk.done.then(|| chunk.usage());
+        }
+        let usage = usage.ok_or(anyhow::anyhow!("No response"))?;
+        Ok(Completion::new(Message::assistant(content), usage))
+    }
+
+    async fn complete_stream(&self, messages: &[Message]) -> anyhow::Result<CompletionStream> {
+        let response = self.send(messages, true).await?;
+        let deltas = line_stream(response.bytes_stream())
+            .try_filter_map(|line| async move {
+                if line.trim().is_empty() {
+                    return Ok(None);
+                }
+                let chunk = parse_ndjson_line(&line)?;
+                Ok(Some(chunk))
+            })
+            .try_filter_map(|chunk| async move {
+                // the final chunk carries no content, only the token counts
+                if chunk.done {
+                    return Ok(Some(CompletionChunk::Usage(chunk.usage())));
+                }
+                let delta = chunk.message.map(|m| m.content);
+                Ok(delta.filter(|d| !d.is_empty()).map(CompletionChunk::Delta))
+            });
+        Ok(deltas.boxed())
+    }
+}
+
+/// embedder for `/api/embed`, the model has to be pulled on the host
+#[derive(Debug, Clone)]
+pub struct OllamaEmbedder {
+    host: String,
+    model: String,
+    dimensions: usize,
+    client: Client,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct OllamaEmbedRequest {
+    pub model: String,
+    pub input: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OllamaEmbedResponse {
+    pub model: String,
+    pub embeddings: Vec<Vec<f32>>,
+}
+
+impl OllamaEmbedder {
+    pub fn new(host: impl Into<String>, model: impl Into<String>, dimensions: usize) -> Self {
+        let host: String = host.into();
+        Self {
+            host: host.trim_end_matches('/').to_string(),
+            model: model.into(),
+            dimensions,
+            client: http_client(),
+        }
+    }
+}
+
+impl EmbeddingService for OllamaEmbedder {
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn dimensions(&self) -> usize {
+        self.dimensions
+    }
+
+    async fn embed_batch(&self, input: &[String]) -> anyhow::Result<Vec<Vec<f32>>> {
+        let request = OllamaEmbedRequest {
+            model: self.model.clone(),
+            input: input.to_vec(),
+        };
+        let url = format!("{}/api/embed", self.host);
+        let response = self.client.post(url).json(&request).send().await?;
+        let response = check_status(response, |body| {
+            serde_json::from_str::<OllamaError>(&body)
+                .map(|e| format!("ollama error: {}", e.error))
+                .unwrap_or(body)
+        })
+        .await?;
+        Ok(response.json::<OllamaEmbedResponse>().await?.embeddings)
+    }
+}
+
+impl OllamaOptions {
+    fn from_args(args: &CompletionArgs) -> Option<Self> {
+        let options = Self {
+            temperature: args.temperature,
+            top_p: args.top_p,
+            num_predict: args.max_tokens,
+            stop: args.stop.clone(),
+        };
+        let empty = options.temperature.is_none()
+            && options.top_p.is_none()
+            && options.num_predict.is_none()
+            && options.stop.is_empty();
+        (!empty).then_some(options)
+    }
+}
+
+impl OllamaChatResponse {
+    fn usage(&self) -> Usage {
+        Usage::new(
+            self.prompt_eval_count.unwrap_or_default(),
+            self.eval_count.unwrap_or_default(),
+        )
+    }
+}
+
+/// parse a single NDJSON line of the `/api/chat` response
+pub(crate) fn parse_ndjson_line(line: &str) -> anyhow::Result<OllamaChatResponse> {
+    if let Ok(e) = serde_json::from_str::<OllamaError>(line) {
+        return Err(anyhow::anyhow!("ollama error: {}", e.error));
+    }
+    Ok(serde_json::from_str(line)?)
+}
+
+impl From<&Message> for OllamaMessage {
+    fn from(value: &Message) -> Self {
+        Self {
+            role: value.role.to_string(),
+            content: value.content.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_server;
+    use axum::{Json, Router, http::StatusCode, routing::post};
+    use serde_json::{Value, json};
+
+    async fn chat(Json(body): Json<Value>) -> (StatusCode, String) {
+        let model = body["model"].as_str().unwrap_or_default();
+        if model == "missing" {
+            let error = json!({ "error": "model \"missing\" not found, try pulling it first" });
+            return (StatusCode::NOT_FOUND, error.to_string());
+        }
+        let question = body["messages"][1]["content"].as_str().unwrap_or_default();
+        let lines = [
+            json!({ "model": model, "message": { "role": "assistant", "content": "echo: " }, "done": false }),
+            json!({ "model": model, "message": { "role": "assistant", "content": question }, "done": false }),
+            json!({ "model": model, "message": { "role": "assistant", "content": "" }, "done": true, "done_reason": "stop", "prompt_eval_count": 26, "eval_count": 2 }),
+        ];
+        // non-streaming responses only contain the final, aggregated object
+        let body = if body["stream"] == json!(true) {
+            lines.map(|l| l.to_string()).join("\n")
+        } else {
+            let content = format!("echo: {question}");
+            json!({ "model": model, "message": { "role": "assistant", "content": content }, "done": true, "prompt_eval_count": 26, "eval_count": 4 })
+                .to_string()
+        };
+        (StatusCode::OK, body)
+    }
+
+    #[tokio::test]
+    async fn ollama_complete_should_work() {
+        let app = Router::new().route("/api/chat", post(chat));
+        let host = mock_server(app).await;
+        let adapter = OllamaAdapter::new(host, "llama3.2");
+        let messages = vec![
+            Message::system("be brief"),
+            Message::user("中国最长的河流是"),
+        ];
+        let response = adapter.complete(&messages).await.unwrap();
+        assert_eq!(response.message.content, "echo: 中国最长的河流是");
+        assert_eq!(response.usage, Usage::new(26, 4));
+    }
+
+    #[tokio::test]
+    async fn ollama_complete_stream_should_work() {
+        let app = Router::new().route("/api/chat", post(chat));
+        let host = mock_server(app).await;
+        let adapter = OllamaAdapter::new(host, "llama3.2");
+        let messages = vec![Message::system("be brief"), Message::user("长江")];
+        let stream = adapter.complete_stream(&messages).await.unwrap();
+        let chunks: Vec<CompletionChunk> = stream.try_collect().await.unwrap();
+        assert_eq!(
+            chunks,
+            vec![
+                CompletionChunk::Delta("echo: ".to_string()),
+                CompletionChunk::Delta("长江".to_string()),
+                CompletionChunk::Usage(Usage::new(26, 2)),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn ollama_missing_model_should_fail() {
+        let app = Router::new().route("/api/chat", post(chat));
+        let host = mock_server(app).await;
+        let adapter = OllamaAdapter::new(host, "missing");
+        let err = adapter
+            .complete(&[Message::user("hello")])
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("not found"));
+    }
+
+    #[tokio::test]
+    async fn ollama_should_forward_args() {
+        async fn options(Json(body): Json<Value>) -> String {
+            let content = format!("{}|{}", body["options"], body["format"]);
+            json!({ "model": body["model"], "message": { "role": "assistant", "content": content }, "done": true })
+                .to_string()
+        }
+
+        let app = Router::new().route("/api/chat", post(options));
+        let host = mock_server(app).await;
+        let adapter = OllamaAdapter::new(&host, "llama3.2");
+        let response = adapter.complete(&[Message::user("hi")]).await.unwrap();
+        assert_eq!(response.message.content, "null|null");
+
+        let args = CompletionArgs {
+            temperature: Some(0.5),
+            max_tokens: Some(64),
+            stop: vec!["\n\n".to_string()],
+            response_format: Some(crate::ResponseFormat::JsonObject),
+            ..Default::default()
+        };
+        let adapter = OllamaAdapter::new(host, "llama3.2").with_args(args);
+        let response = adapter.complete(&[Message::user("hi")]).await.unwrap();
+        assert_eq!(
+            response.message.content,
+            r#"{"num_predict":64,"stop":["\n\n"],"temperature":0.5}|"json""#
+        );
+        let json_schema = crate::JsonSchema {
+            name: "labels".to_string(),
+            schema: serde_json::json!({ "type": "object" }),
+            strict: None,
+        };
+        let args = CompletionArgs {
+            response_format: Some(crate::ResponseFormat::JsonSchema { json_schema }),
+            ..Default::default()
+        };
+        let adapter = adapter.with_args(args);
+        let response = adapter.complete(&[Message::user("hi")]).await.unwrap();
+        assert_eq!(response.message.content, r#"null|{"type":"object"}"#);
+    }
+
+    #[tokio::test]
+    async fn ollama_embedder_should_work() {
+        async fn embed(Json(body): Json<Value>) -> String {
+            let embeddings: Vec<Value> = body["input"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|text| json!([text.as_str().unwrap().len(), 1]))
+                .collect();
+            json!({ "model": body["model"], "embeddings": embeddings }).to_string()
+        }
+
+        let app = Router::new().route("/api/embed", post(embed));
+        let host = mock_server(app).await;
+        let embedder = OllamaEmbedder::new(host, "nomic-embed-text", 2);
+        let input = ["hi".to_string(), "hello".to_string()];
+        let vectors = embedder.embed(&input).await.unwrap();
+        assert_eq!(vectors, [[2.0, 1.0], [5.0, 1.0]]);
+    }
+}
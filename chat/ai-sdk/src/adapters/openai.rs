@@ -0,0 +1,644 @@
+//! OpenAI compatible `/v1/chat/completions` adapter, works with OpenAI, vLLM,
+//! llama.cpp server, LM Studio and any other server speaking the same protocol.
+//! [`OpenAiCompatEmbedder`] calls `/v1/embeddings` of the same servers.
+//!
+//! openai response
+//! {
+//!   "id": "chatcmpl-123",
+//!   "object": "chat.completion",
+//!   "created": 1741052005,
+//!   "model": "gpt-4o-mini",
+//!   "choices": [
+//!     {
+//!       "index": 0,
+//!       "message": {
+//!         "role": "assistant",
+//!         "content": "Hello there, how may I assist you today?"
+//!       },
+//!       "finish_reason": "stop"
+//!     }
+//!   ],
+//!   "usage": {
+//!     "prompt_tokens": 9,
+//!     "completion_tokens": 12,
+//!     "total_tokens": 21
+//!   }
+//! }
+
+use crate::Message;
+use crate::{AiService, CompletionArgs, EmbeddingService};
+use crate::{Completion, CompletionChunk, CompletionStream, Usage};
+use crate::{Tool, ToolCall};
+use crate::{check_status, http_client, line_stream, sse_json_stream};
+use futures::{StreamExt, TryStreamExt, stream};
+use reqwest::Client;
+use serde::Deserialize;
+use serde::Serialize;
+
+#[derive(Debug, Clone)]
+pub struct OpenAiCompatAdapter {
+    base_url: String,
+    api_key: Option<String>,
+    model: String,
+    args: CompletionArgs,
+    client: Client,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct OpenAiChatCompletionRequest {
+    pub model: String,
+    pub messages: Vec<OpenAiMessage>,
+    pub stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream_options: Option<OpenAiStreamOptions>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tools: Vec<OpenAiTool>,
+    #[serde(flatten)]
+    pub args: CompletionArgs,
+}
+
+/// ask for a final chunk carrying the usage when streaming
+#[derive(Debug, Clone, Serialize)]
+pub struct OpenAiStreamOptions {
+    pub include_usage: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OpenAiMessage {
+    pub role: String,
+    #[serde(default)]
+    pub content: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<OpenAiToolCall>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+}
+
+/// tool definition, `{"type": "function", "function": {...}}`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OpenAiTool {
+    pub r#type: String,
+    pub function: OpenAiFunction,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OpenAiFunction {
+    pub name: String,
+    pub description: String,
+    pub parameters: serde_json::Value,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OpenAiToolCall {
+    pub id: String,
+    pub r#type: String,
+    pub function: OpenAiFunctionCall,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OpenAiFunctionCall {
+    pub name: String,
+    #[serde(default)]
+    pub arguments: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OpenAiChatCompletionResponse {
+    #[serde(default)]
+    pub id: String,
+    #[serde(default)]
+    pub object: String,
+    #[serde(default)]
+    pub created: i64,
+    #[serde(default)]
+    pub model: String,
+    pub choices: Vec<OpenAiChoice>,
+    #[serde(default)]
+    pub usage: Option<OpenAiUsage>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OpenAiChoice {
+    pub index: u32,
+    pub message: OpenAiMessage,
+    #[serde(default)]
+    pub finish_reason: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OpenAiChatCompletionChunk {
+    #[serde(default)]
+    pub id: String,
+    pub choices: Vec<OpenAiChunkChoice>,
+    #[serde(default)]
+    pub usage: Option<OpenAiUsage>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OpenAiChunkChoice {
+    pub index: u32,
+    pub delta: OpenAiDelta,
+    #[serde(default)]
+    pub finish_reason: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OpenAiDelta {
+    #[serde(default)]
+    pub role: Option<String>,
+    #[serde(default)]
+    pub content: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OpenAiUsage {
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+    #[serde(default)]
+    pub total_tokens: u32,
+    #[serde(default)]
+    pub prompt_tokens_details: Option<OpenAiPromptTokensDetails>,
+    /// cached prompt tokens as reported by deepseek
+    #[serde(default)]
+    pub prompt_cache_hit_tokens: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OpenAiPromptTokensDetails {
+    #[serde(default)]
+    pub cached_tokens: u32,
+}
+
+impl OpenAiCompatAdapter {
+    /// Create a new adapter. `base_url` is the prefix before `/chat/completions`,
+    /// e.g. `https://api.openai.com/v1` or `http://localhost:8000/v1`.
+    pub fn new(
+        base_url: impl Into<String>,
+        api_key: Option<String>,
+        model: impl Into<String>,
+    ) -> Self {
+        let base_url: String = base_url.into();
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            api_key: api_key.filter(|key| !key.is_empty()),
+            model: model.into(),
+            args: CompletionArgs::default(),
+            client: http_client(),
+        }
+    }
+
+    /// send the args with every request
+    pub fn with_args(self, args: CompletionArgs) -> Self {
+        Self { args, ..self }
+    }
+
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+
+    /// check the args are valid for the protocol. Any model name goes, an empty
+    /// one stands for the server's configured model
+    pub fn check_args(_model: &str, args: &CompletionArgs) -> anyhow::Result<()> {
+        args.validate()?;
+        anyhow::ensure!(
+            args.stop.len() <= 4,
+            "at most 4 stop sequences are supported"
+        );
+        Ok(())
+    }
+
+    async fn send(
+        &self,
+        messages: &[Message],
+        tools: &[Tool],
+        stream: bool,
+    ) -> anyhow::Result<reqwest::Response> {
+        let request = OpenAiChatCompletionRequest {
+            model: self.model.clone(),
+            messages: messages.iter().map(|m| m.into()).collect(),
+            stream,
+            stream_options: stream.then_some(OpenAiStreamOptions {
+                include_usage: true,
+            }),
+            tools: tools.iter().map(|t| t.into()).collect(),
+            args: self.args.clone(),
+        };
+        let url = format!("{}/chat/completions", self.base_url);
+        let mut builder = self.client.post(url).json(&request);
+        if let Some(api_key) = &self.api_key {
+            builder = builder.bearer_auth(api_key);
+        }
+        check_status(builder.send().await?, |body| body).await
+    }
+}
+
+impl AiService for OpenAiCompatAdapter {
+    async fn complete(&self, messages: &[Message]) -> anyhow::Result<Completion> {
+        self.complete_with_tools(messages, &[]).await
+    }
+
+    async fn complete_stream(&self, messages: &[Message]) -> anyhow::Result<CompletionStream> {
+        let response = self.send(messages, &[], true).await?;
+        let lines = line_stream(response.bytes_stream());
+        let chunks = sse_json_stream::<OpenAiChatCompletionChunk, _>(lines)
+            .map_ok(|chunk| stream::iter(chunk.into_chunks().into_iter().map(Ok)))
+            .try_flatten();
+        Ok(chunks.boxed())
+    }
+
+    async fn complete_with_tools(
+        &self,
+        messages: &[Message],
+        tools: &[Tool],
+    ) -> anyhow::Result<Completion> {
+        let response = self.send(messages, tools, false).await?;
+        let mut data = response.json::<OpenAiChatCompletionResponse>().await?;
+        let message = data
+            .choices
+            .pop()
+            .ok_or(anyhow::anyhow!("No response"))?
+            .message;
+        let usage = data.usage.map(Into::into).unwrap_or_default();
+        Ok(Completion::new(message.into(), usage))
+    }
+}
+
+/// embedder for `/v1/embeddings`
+#[derive(Debug, Clone)]
+pub struct OpenAiCompatEmbedder {
+    base_url: String,
+    api_key: Option<String>,
+    model: String,
+    dimensions: usize,
+    client: Client,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct OpenAiEmbeddingRequest {
+    pub model: String,
+    pub input: Vec<String>,
+    /// shortens the vectors, only supported by the `text-embedding-3` models
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dimensions: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OpenAiEmbeddingResponse {
+    pub data: Vec<OpenAiEmbedding>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OpenAiEmbedding {
+    pub index: usize,
+    pub embedding: Vec<f32>,
+}
+
+impl OpenAiCompatEmbedder {
+    /// `base_url` is the prefix before `/embeddings`, see [`OpenAiCompatAdapter::new`]
+    pub fn new(
+        base_url: impl Into<String>,
+        api_key: Option<String>,
+        model: impl Into<String>,
+        dimensions: usize,
+    ) -> Self {
+        let base_url: String = base_url.into();
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            api_key: api_key.filter(|key| !key.is_empty()),
+            model: model.into(),
+            dimensions,
+            client: http_client(),
+        }
+    }
+}
+
+impl EmbeddingService for OpenAiCompatEmbedder {
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn dimensions(&self) -> usize {
+        self.dimensions
+    }
+
+    async fn embed_batch(&self, input: &[String]) -> anyhow::Result<Vec<Vec<f32>>> {
+        let request = OpenAiEmbeddingRequest {
+            model: self.model.clone(),
+            input: input.to_vec(),
+            dimensions: self
+                .model
+                .starts_with("text-embedding-3")
+                .then_some(self.dimensions),
+        };
+        let url = format!("{}/embeddings", self.base_url);
+        let mut builder = self.client.post(url).json(&request);
+        if let Some(api_key) = &self.api_key {
+            builder = builder.bearer_auth(api_key);
+        }
+        let response = check_status(builder.send().await?, |body| body).await?;
+        let mut data = response.json::<OpenAiEmbeddingResponse>().await?.data;
+        // the order of the inputs is given by the index
+        data.sort_by_key(|e| e.index);
+        Ok(data.into_iter().map(|e| e.embedding).collect())
+    }
+}
+
+impl OpenAiChatCompletionChunk {
+    /// the content delta and, on the last chunk, the usage
+    pub(crate) fn into_chunks(mut self) -> Vec<CompletionChunk> {
+        let delta = self.choices.pop().and_then(|c| c.delta.content);
+        let delta = delta.filter(|d| !d.is_empty()).map(CompletionChunk::Delta);
+        let usage = self.usage.map(|u| CompletionChunk::Usage(u.into()));
+        delta.into_iter().chain(usage).collect()
+    }
+}
+
+impl From<OpenAiUsage> for Usage {
+    fn from(value: OpenAiUsage) -> Self {
+        Self {
+            prompt_tokens: value.prompt_tokens,
+            completion_tokens: value.completion_tokens,
+            cache_hit_tokens: value
+                .prompt_cache_hit_tokens
+                .or(value.prompt_tokens_details.map(|d| d.cached_tokens))
+                .unwrap_or_default(),
+        }
+    }
+}
+
+impl From<&Message> for OpenAiMessage {
+    fn from(value: &Message) -> Self {
+        // assistant messages with tool calls may have no content
+        let content = if value.content.is_empty() && !value.tool_calls.is_empty() {
+            None
+        } else {
+            Some(value.content.clone())
+        };
+        Self {
+            role: value.role.to_string(),
+            content,
+            tool_calls: value.tool_calls.iter().map(|c| c.into()).collect(),
+            tool_call_id: value.tool_call_id.clone(),
+        }
+    }
+}
+
+impl From<OpenAiMessage> for Message {
+    fn from(value: OpenAiMessage) -> Self {
+        let tool_calls = value.tool_calls.into_iter().map(|c| c.into()).collect();
+        Message::tool_calls(value.content.unwrap_or_default(), tool_calls)
+    }
+}
+
+impl From<&Tool> for OpenAiTool {
+    fn from(value: &Tool) -> Self {
+        Self {
+            r#type: "function".to_string(),
+            function: OpenAiFunction {
+                name: value.name.clone(),
+                description: value.description.clone(),
+                parameters: value.parameters.clone(),
+            },
+        }
+    }
+}
+
+impl From<&ToolCall> for OpenAiToolCall {
+    fn from(value: &ToolCall) -> Self {
+        Self {
+            id: value.id.clone(),
+            r#type: "function".to_string(),
+            function: OpenAiFunctionCall {
+                name: value.name.clone(),
+                arguments: value.arguments.clone(),
+            },
+        }
+    }
+}
+
+impl From<OpenAiToolCall> for ToolCall {
+    fn from(value: OpenAiToolCall) -> Self {
+        Self {
+            id: value.id,
+            name: value.function.name,
+            arguments: value.function.arguments,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_server;
+    use axum::{
+        Json, Router,
+        http::HeaderMap,
+        response::{IntoResponse, Response},
+        routing::post,
+    };
+    use serde_json::{Value, json};
+
+    async fn completions(headers: HeaderMap, Json(body): Json<Value>) -> Response {
+        if body["stream"] == json!(true) {
+            let question = body["messages"][0]["content"].as_str().unwrap_or_default();
+            let mut events: Vec<String> = ["", "Hello", ", ", question]
+                .iter()
+                .map(|d| {
+                    let chunk = json!({
+                        "id": "chatcmpl-1",
+                        "choices": [{ "index": 0, "delta": { "content": d }, "finish_reason": null }]
+                    });
+                    format!("data: {chunk}\n\n")
+                })
+                .collect();
+            assert_eq!(body["stream_options"]["include_usage"], true);
+            let usage = json!({
+                "id": "chatcmpl-1",
+                "choices": [],
+                "usage": { "prompt_tokens": 3, "completion_tokens": 3, "total_tokens": 6 }
+            });
+            events.push(format!("data: {usage}\n\n"));
+            events.push("data: [DONE]\n\n".to_string());
+            return ([("content-type", "text/event-stream")], events.concat()).into_response();
+        }
+
+        let auth = headers
+            .get("authorization")
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        let content = format!(
+            "{}|{}|{}",
+            body["model"].as_str().unwrap_or_default(),
+            body["messages"][0]["content"].as_str().unwrap_or_default(),
+            auth
+        );
+        Json(json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion",
+            "created": 1741052005,
+            "model": body["model"],
+            "choices": [{
+                "index": 0,
+                "message": { "role": "assistant", "content": content },
+                "finish_reason": "stop"
+            }],
+            "usage": {
+                "prompt_tokens": 3,
+                "completion_tokens": 5,
+                "total_tokens": 8,
+                "prompt_tokens_details": { "cached_tokens": 2 }
+            }
+        }))
+        .into_response()
+    }
+
+    #[tokio::test]
+    async fn openai_compat_complete_should_work() {
+        let app = Router::new().route("/v1/chat/completions", post(completions));
+        let base_url = mock_server(app).await;
+        let adapter = OpenAiCompatAdapter::new(
+            format!("{base_url}/v1/"),
+            Some("sk-test".to_string()),
+            "qwen2.5",
+        );
+        let response = adapter.complete(&[Message::user("hello")]).await.unwrap();
+        assert_eq!(response.message.content, "qwen2.5|hello|Bearer sk-test");
+        assert_eq!(
+            response.usage,
+            Usage {
+                prompt_tokens: 3,
+                completion_tokens: 5,
+                cache_hit_tokens: 2
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn openai_compat_without_api_key_should_work() {
+        let app = Router::new().route("/v1/chat/completions", post(completions));
+        let base_url = mock_server(app).await;
+        let adapter = OpenAiCompatAdapter::new(format!("{base_url}/v1"), None, "llama3");
+        let response = adapter.complete(&[Message::user("hi")]).await.unwrap();
+        assert_eq!(response.message.content, "llama3|hi|");
+    }
+
+    /// call `get_weather` first, then answer with the tool result
+    async fn tool_completions(Json(body): Json<Value>) -> Json<Value> {
+        let messages = body["messages"].as_array().unwrap();
+        let last = messages.last().unwrap();
+        let message = if last["role"] == "tool" {
+            // the assistant tool call message must be sent back before the result
+            let call = &messages[messages.len() - 2];
+            assert_eq!(call["role"], "assistant");
+            assert_eq!(call["content"], Value::Null);
+            assert_eq!(call["tool_calls"][0]["function"]["name"], "get_weather");
+            assert_eq!(last["tool_call_id"], "call_1");
+            json!({ "role": "assistant", "content": format!("It is {}", last["content"].as_str().unwrap()) })
+        } else {
+            assert_eq!(body["tools"][0]["type"], "function");
+            assert_eq!(body["tools"][0]["function"]["name"], "get_weather");
+            json!({
+                "role": "assistant",
+                "content": null,
+                "tool_calls": [{
+                    "id": "call_1",
+                    "type": "function",
+                    "function": { "name": "get_weather", "arguments": "{\"city\":\"Tokyo\"}" }
+                }]
+            })
+        };
+        Json(json!({
+            "choices": [{ "index": 0, "message": message, "finish_reason": "stop" }]
+        }))
+    }
+
+    #[tokio::test]
+    async fn openai_compat_complete_with_tools_should_work() {
+        let app = Router::new().route("/v1/chat/completions", post(tool_completions));
+        let base_url = mock_server(app).await;
+        let adapter = OpenAiCompatAdapter::new(format!("{base_url}/v1"), None, "gpt-4o-mini");
+        let tools = vec![Tool::new(
+            "get_weather",
+            "Get the weather of a city",
+            json!({
+                "type": "object",
+                "properties": { "city": { "type": "string" } },
+                "required": ["city"]
+            }),
+        )];
+        let mut messages = vec![Message::user("How is the weather in Tokyo?")];
+        let reply = adapter
+            .complete_with_tools(&messages, &tools)
+            .await
+            .unwrap()
+            .message;
+        assert_eq!(reply.tool_calls.len(), 1);
+        let call = reply.tool_calls[0].clone();
+        let args: Value = call.parse_arguments().unwrap();
+        assert_eq!(args["city"], "Tokyo");
+
+        messages.push(reply);
+        messages.push(Message::tool(call.id, "sunny"));
+        let reply = adapter
+            .complete_with_tools(&messages, &tools)
+            .await
+            .unwrap()
+            .message;
+        assert!(reply.tool_calls.is_empty());
+        assert_eq!(reply.content, "It is sunny");
+    }
+
+    #[tokio::test]
+    async fn openai_compat_complete_stream_should_work() {
+        let app = Router::new().route("/v1/chat/completions", post(completions));
+        let base_url = mock_server(app).await;
+        let adapter = OpenAiCompatAdapter::new(format!("{base_url}/v1"), None, "llama3");
+        let stream = adapter
+            .complete_stream(&[Message::user("world")])
+            .await
+            .unwrap();
+        let chunks: Vec<CompletionChunk> = stream.try_collect().await.unwrap();
+        assert_eq!(
+            chunks,
+            vec![
+                CompletionChunk::Delta("Hello".to_string()),
+                CompletionChunk::Delta(", ".to_string()),
+                CompletionChunk::Delta("world".to_string()),
+                CompletionChunk::Usage(Usage::new(3, 3)),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn openai_embedder_should_keep_input_order() {
+        async fn embeddings(Json(body): Json<Value>) -> Json<Value> {
+            assert_eq!(body["dimensions"], 3);
+            let data: Vec<Value> = body["input"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .enumerate()
+                .rev()
+                .map(|(i, text)| {
+                    let len = text.as_str().unwrap().len() as f32;
+                    json!({ "index": i, "embedding": [len, 0.0, 1.0] })
+                })
+                .collect();
+            Json(json!({ "object": "list", "data": data }))
+        }
+
+        let app = Router::new().route("/v1/embeddings", post(embeddings));
+        let host = mock_server(app).await;
+        let embedder =
+            OpenAiCompatEmbedder::new(format!("{host}/v1"), None, "text-embedding-3-small", 3);
+        let input = ["a".to_string(), "abc".to_string()];
+        let vectors = embedder.embed(&input).await.unwrap();
+        assert_eq!(vectors, [[1.0, 0.0, 1.0], [3.0, 0.0, 1.0]]);
+
+        // the server answering with other dimensions is an error
+        let embedder =
+            OpenAiCompatEmbedder::new(format!("{host}/v1"), None, "text-embedding-3-small", 4);
+        assert!(embedder.embed(&input).await.is_err());
+    }
+}
@@ -0,0 +1,123 @@
+//! text embeddings, for semantic search and retrieval. Every embedder has a fixed
+//! number of dimensions, checked on each answer so vectors always fit the store
+//! they're written to, see [`EmbeddingService::check_dimensions`].
+
+use crate::{MockEmbedder, OllamaEmbedder, OpenAiCompatEmbedder};
+use enum_dispatch::enum_dispatch;
+
+/// texts per request unless the embedder says otherwise
+pub const DEFAULT_EMBEDDING_BATCH: usize = 64;
+
+#[derive(Debug, Clone)]
+#[enum_dispatch(EmbeddingService)]
+pub enum EmbeddingAdapter {
+    OpenAiCompat(OpenAiCompatEmbedder),
+    Ollama(OllamaEmbedder),
+    Mock(MockEmbedder),
+}
+
+#[allow(async_fn_in_trait)]
+#[enum_dispatch]
+pub trait EmbeddingService {
+    fn model(&self) -> &str;
+
+    /// length of the vectors
+    fn dimensions(&self) -> usize;
+
+    /// max texts per request, [`EmbeddingService::embed`] splits longer inputs
+    fn batch_size(&self) -> usize {
+        DEFAULT_EMBEDDING_BATCH
+    }
+
+    /// embed texts in a single request
+    async fn embed_batch(&self, input: &[String]) -> anyhow::Result<Vec<Vec<f32>>>;
+
+    /// one vector per text, in order, sent in batches of `batch_size`
+    async fn embed(&self, input: &[String]) -> anyhow::Result<Vec<Vec<f32>>> {
+        let mut vectors = Vec::with_capacity(input.len());
+        for batch in input.chunks(self.batch_size().max(1)) {
+            let embedded = self.embed_batch(batch).await?;
+            anyhow::ensure!(
+                embedded.len() == batch.len(),
+                "expected {} embeddings, got {}",
+                batch.len(),
+                embedded.len()
+            );
+            for vector in &embedded {
+                self.check_dimensions(vector.len())?;
+            }
+            vectors.extend(embedded);
+        }
+        Ok(vectors)
+    }
+
+    /// check vectors of `size` dimensions are the ones of this embedder, e.g. the
+    /// vector size of the table they're stored in
+    fn check_dimensions(&self, size: usize) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            size == self.dimensions(),
+            "{} has {} dimensions, got {size}",
+            self.model(),
+            self.dimensions()
+        );
+        Ok(())
+    }
+}
+
+/// dimensions of well known embedding models
+pub fn known_dimensions(model: &str) -> Option<usize> {
+    let model = model.split(':').next().unwrap_or(model);
+    match model {
+        "text-embedding-3-small" | "text-embedding-ada-002" => Some(1536),
+        "text-embedding-3-large" => Some(3072),
+        "nomic-embed-text" => Some(768),
+        "mxbai-embed-large" | "bge-m3" => Some(1024),
+        "all-minilm" => Some(384),
+        _ => None,
+    }
+}
+
+/// scale the vector to unit length, so the dot product of two vectors is their
+/// cosine similarity. Zero vectors are kept as is
+pub fn normalize(vector: &mut [f32]) {
+    let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        vector.iter_mut().for_each(|x| *x /= norm);
+    }
+}
+
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm = norm(a) * norm(b);
+    if norm > 0.0 { dot / norm } else { 0.0 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn embed_should_split_batches_and_check_dimensions() {
+        let embedder = MockEmbedder::new(8).with_batch_size(2);
+        let input: Vec<String> = ["a", "b", "c"].map(String::from).to_vec();
+        let vectors = embedder.embed(&input).await.unwrap();
+        assert_eq!(vectors.len(), 3);
+        assert_eq!(embedder.batches(), vec![2, 1]);
+        assert!(embedder.check_dimensions(8).is_ok());
+        assert!(embedder.check_dimensions(1536).is_err());
+
+        let embedder = EmbeddingAdapter::from(embedder);
+        assert_eq!(embedder.dimensions(), 8);
+        assert_eq!(known_dimensions("nomic-embed-text:latest"), Some(768));
+    }
+
+    #[test]
+    fn cosine_similarity_should_work() {
+        let mut a = vec![3.0, 4.0];
+        normalize(&mut a);
+        assert_eq!(a, [0.6, 0.8]);
+        assert!((cosine_similarity(&a, &[6.0, 8.0]) - 1.0).abs() < 1e-6);
+        assert_eq!(cosine_similarity(&a, &[0.0, 0.0]), 0.0);
+    }
+}
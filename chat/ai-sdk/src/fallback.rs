@@ -0,0 +1,137 @@
+//! try a list of providers in order, the first one answering wins. The index of the
+//! provider that answered is reported in `Completion::provider`, streams start with a
+//! `CompletionChunk::Provider` chunk.
+
+use crate::{AiService, Completion, CompletionChunk, CompletionStream, Message, Tool};
+use futures::{StreamExt, stream};
+use std::future::Future;
+use tracing::{info, warn};
+
+#[derive(Debug, Clone)]
+pub struct FallbackAdapter<S> {
+    providers: Vec<S>,
+}
+
+impl<S: AiService> FallbackAdapter<S> {
+    pub fn new(providers: Vec<S>) -> Self {
+        Self { providers }
+    }
+
+    pub fn providers(&self) -> &[S] {
+        &self.providers
+    }
+
+    pub fn providers_mut(&mut self) -> &mut [S] {
+        &mut self.providers
+    }
+
+    async fn call<'a, T, F, Fut>(&'a self, mut f: F) -> anyhow::Result<(usize, T)>
+    where
+        F: FnMut(&'a S) -> Fut,
+        Fut: Future<Output = anyhow::Result<T>> + 'a,
+    {
+        let mut last_err = None;
+        for (i, provider) in self.providers.iter().enumerate() {
+            match f(provider).await {
+                Ok(value) => {
+                    if i > 0 {
+                        info!("answered by fallback provider {i}");
+                    }
+                    return Ok((i, value));
+                }
+                Err(e) => {
+                    warn!("provider {i} failed: {e}");
+                    last_err = Some(e);
+                }
+            }
+        }
+        Err(last_err.unwrap_or_else(|| anyhow::anyhow!("no provider configured")))
+    }
+}
+
+impl<S: AiService> AiService for FallbackAdapter<S> {
+    async fn complete(&self, messages: &[Message]) -> anyhow::Result<Completion> {
+        let (provider, completion) = self.call(|p| p.complete(messages)).await?;
+        Ok(completion.with_provider(provider))
+    }
+
+    /// falls back only if the stream can't be established
+    async fn complete_stream(&self, messages: &[Message]) -> anyhow::Result<CompletionStream> {
+        let (provider, chunks) = self.call(|p| p.complete_stream(messages)).await?;
+        let provider = stream::once(async move { Ok(CompletionChunk::Provider(provider)) });
+        Ok(provider.chain(chunks).boxed())
+    }
+
+    async fn complete_with_tools(
+        &self,
+        messages: &[Message],
+        tools: &[Tool],
+    ) -> anyhow::Result<Completion> {
+        let (provider, completion) = self
+            .call(|p| p.complete_with_tools(messages, tools))
+            .await?;
+        Ok(completion.with_provider(provider))
+    }
+}
+
+impl<S: AiService> From<S> for FallbackAdapter<S> {
+    fn from(provider: S) -> Self {
+        Self::new(vec![provider])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AiAdapter, OllamaAdapter, OpenAiCompatAdapter, mock_server};
+    use axum::{Json, Router, routing::post};
+    use futures::TryStreamExt;
+    use serde_json::{Value, json};
+
+    async fn chat(Json(body): Json<Value>) -> String {
+        let content = format!("{} answered", body["model"].as_str().unwrap());
+        json!({ "model": body["model"], "message": { "role": "assistant", "content": content }, "done": true })
+            .to_string()
+    }
+
+    async fn fallback_adapter() -> FallbackAdapter<AiAdapter> {
+        let host = mock_server(Router::new().route("/api/chat", post(chat))).await;
+        // nothing listens on port 1
+        let down = OpenAiCompatAdapter::new("http://127.0.0.1:1/v1", None, "gpt-4o-mini");
+        FallbackAdapter::new(vec![
+            down.into(),
+            OllamaAdapter::new(&host, "llama3.2").into(),
+            OllamaAdapter::new(&host, "qwen2.5").into(),
+        ])
+    }
+
+    #[tokio::test]
+    async fn fallback_adapter_should_use_next_provider() {
+        let adapter = fallback_adapter().await;
+        let completion = adapter.complete(&[Message::user("hi")]).await.unwrap();
+        assert_eq!(completion.message.content, "llama3.2 answered");
+        assert_eq!(completion.provider, 1);
+    }
+
+    #[tokio::test]
+    async fn fallback_adapter_stream_should_report_provider() {
+        let adapter = fallback_adapter().await;
+        let stream = adapter
+            .complete_stream(&[Message::user("hi")])
+            .await
+            .unwrap();
+        let chunks: Vec<CompletionChunk> = stream.try_collect().await.unwrap();
+        assert_eq!(chunks[0], CompletionChunk::Provider(1));
+    }
+
+    #[tokio::test]
+    async fn fallback_adapter_should_fail_when_all_providers_fail() {
+        let down = OpenAiCompatAdapter::new("http://127.0.0.1:1/v1", None, "gpt-4o-mini");
+        let adapter = FallbackAdapter::new(vec![down]);
+        assert!(adapter.complete(&[Message::user("hi")]).await.is_err());
+
+        let adapter = FallbackAdapter::<OpenAiCompatAdapter>::new(vec![]);
+        let err = adapter.complete(&[Message::user("hi")]).await.unwrap_err();
+        assert!(err.to_string().contains("no provider"));
+    }
+}
@@ -0,0 +1,174 @@
+//! structured output: the answer is parsed as JSON and checked against a JSON schema.
+//! Providers supporting a response format get it with the request (see the adapters),
+//! the format is also asked for in the prompt so the others follow it too. Invalid
+//! answers are sent back to the model with the reason, up to `JSON_RETRIES` times.
+
+use crate::{AiService, Completion, Message, Role, Usage};
+use serde_json::Value;
+
+/// extra calls when the answer isn't valid JSON or doesn't match the schema
+pub const JSON_RETRIES: usize = 2;
+
+/// a completion and its answer parsed as JSON
+#[derive(Debug, Clone)]
+pub struct JsonCompletion {
+    pub value: Value,
+    /// the last call, with the usage of all calls
+    pub completion: Completion,
+}
+
+/// no valid JSON after the retries
+#[derive(Debug, Clone)]
+pub struct InvalidJsonError {
+    /// answer to the original messages, before any retry
+    pub answer: String,
+    pub reason: String,
+    /// usage of all calls
+    pub usage: Usage,
+    /// index of the provider of the last call, see `FallbackAdapter`
+    pub provider: usize,
+}
+
+/// the answer parsed as JSON, with markdown code fences removed, checked against the
+/// schema if there is one
+pub fn parse_json(answer: &str, schema: Option<&Value>) -> anyhow::Result<Value> {
+    let answer = answer.trim();
+    let json = answer
+        .strip_prefix("```json")
+        .or_else(|| answer.strip_prefix("```"))
+        .and_then(|s| s.strip_suffix("```"))
+        .unwrap_or(answer);
+    let value: Value =
+        serde_json::from_str(json).map_err(|e| anyhow::anyhow!("not valid JSON: {e}"))?;
+    if let Some(schema) = schema {
+        let validator = jsonschema::validator_for(schema)
+            .map_err(|e| anyhow::anyhow!("invalid json schema: {e}"))?;
+        if let Err(e) = validator.validate(&value) {
+            anyhow::bail!("doesn't match the schema: {e} at {}", e.instance_path);
+        }
+    }
+    Ok(value)
+}
+
+/// check the schema is a valid JSON schema
+pub fn validate_schema(schema: &Value) -> anyhow::Result<()> {
+    jsonschema::validator_for(schema).map_err(|e| anyhow::anyhow!("invalid json schema: {e}"))?;
+    Ok(())
+}
+
+pub(crate) async fn complete_json<S: AiService + ?Sized>(
+    service: &S,
+    messages: &[Message],
+    schema: Option<&Value>,
+) -> anyhow::Result<JsonCompletion> {
+    let mut messages = with_instruction(messages, schema);
+    let mut usage = Usage::default();
+    let mut answer = None;
+    for attempt in 0..=JSON_RETRIES {
+        let completion = service.complete(&messages).await?;
+        usage += completion.usage;
+        let content = completion.message.content.clone();
+        let reason = match parse_json(&content, schema) {
+            Ok(value) => {
+                let completion = Completion {
+                    usage,
+                    ..completion
+                };
+                return Ok(JsonCompletion { value, completion });
+            }
+            Err(e) => e.to_string(),
+        };
+        let answer = answer.get_or_insert_with(|| content.clone());
+        if attempt == JSON_RETRIES {
+            return Err(InvalidJsonError {
+                answer: answer.clone(),
+                reason,
+                usage,
+                provider: completion.provider,
+            }
+            .into());
+        }
+        messages.push(Message::assistant(content));
+        messages.push(Message::user(format!(
+            "Your answer is {reason}. Answer again with the JSON only."
+        )));
+    }
+    unreachable!("the last attempt returns")
+}
+
+/// ask for JSON in the system prompt, a system message is added if there is none
+fn with_instruction(messages: &[Message], schema: Option<&Value>) -> Vec<Message> {
+    let instruction = match schema {
+        Some(schema) => format!("Answer only with JSON matching this JSON schema: {schema}"),
+        None => "Answer only with a JSON object.".to_string(),
+    };
+    let mut messages = messages.to_vec();
+    match messages.iter_mut().find(|m| m.role == Role::System) {
+        Some(system) => system.content = format!("{}\n\n{instruction}", system.content),
+        None => messages.insert(0, Message::system(instruction)),
+    }
+    messages
+}
+
+impl std::fmt::Display for InvalidJsonError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "answer is {}", self.reason)
+    }
+}
+
+impl std::error::Error for InvalidJsonError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MockAdapter;
+    use serde_json::json;
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": { "labels": { "type": "array", "items": { "type": "string" } } },
+            "required": ["labels"]
+        })
+    }
+
+    #[test]
+    fn parse_json_should_check_schema() {
+        let schema = schema();
+        let value = parse_json("```json\n{\"labels\": [\"spam\"]}\n```", Some(&schema)).unwrap();
+        assert_eq!(value, json!({ "labels": ["spam"] }));
+        assert!(parse_json("spam", None).is_err());
+        let err = parse_json(r#"{"labels": "spam"}"#, Some(&schema)).unwrap_err();
+        assert!(err.to_string().contains("/labels"));
+        assert!(validate_schema(&json!({ "type": "nothing" })).is_err());
+    }
+
+    #[tokio::test]
+    async fn complete_json_should_retry_invalid_answers() {
+        let schema = schema();
+        let mock = MockAdapter::script(["spam", r#"{"labels": ["spam"]}"#]);
+        let ret = mock
+            .complete_json(&[Message::user("label it")], Some(&schema))
+            .await
+            .unwrap();
+        assert_eq!(ret.value, json!({ "labels": ["spam"] }));
+        // "spam" is 1 token, the JSON 2
+        assert_eq!(ret.completion.usage.completion_tokens, 3);
+
+        let prompts = mock.prompts();
+        assert_eq!(prompts.len(), 2);
+        assert_eq!(prompts[0][0].role, Role::System);
+        assert!(prompts[0][0].content.contains("\"required\":[\"labels\"]"));
+        let retry = prompts[1].last().unwrap();
+        assert!(retry.content.starts_with("Your answer is not valid JSON"));
+
+        let mock = MockAdapter::script(["spam"]);
+        let err = mock
+            .complete_json(&[Message::user("label it")], Some(&schema))
+            .await
+            .unwrap_err();
+        let err = err.downcast::<InvalidJsonError>().unwrap();
+        assert_eq!(err.answer, "spam");
+        assert_eq!(mock.prompts().len(), JSON_RETRIES + 1);
+    }
+}
@@ -1,34 +1,172 @@
 mod adapters;
+mod embedding;
+mod fallback;
+mod json;
+mod resilience;
 mod util;
 
 pub use adapters::*;
+pub use embedding::*;
 use enum_dispatch::enum_dispatch;
+pub use fallback::*;
+use futures::stream::{self, BoxStream, StreamExt};
+pub use json::*;
+pub use resilience::*;
+use serde::{Deserialize, Serialize};
+use std::ops::AddAssign;
 pub use util::*;
 
-#[derive(Debug)]
+/// stream of completion chunks, see [`CompletionChunk`]
+pub type CompletionStream = BoxStream<'static, anyhow::Result<CompletionChunk>>;
+
+#[derive(Debug, Clone)]
 #[enum_dispatch(AiService)]
 pub enum AiAdapter {
     DeepSeek(DeepSeekAdapter),
+    OpenAiCompat(OpenAiCompatAdapter),
+    Ollama(OllamaAdapter),
+    Mock(MockAdapter),
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Role {
     User,
     Assistant,
     System,
+    Tool,
 }
 
 #[derive(Debug, Clone)]
 pub struct Message {
     pub role: Role,
     pub content: String,
+    /// tool calls requested by the assistant
+    pub tool_calls: Vec<ToolCall>,
+    /// for `Role::Tool`, the id of the tool call this message is the result of
+    pub tool_call_id: Option<String>,
+}
+
+/// a tool the model may call, `parameters` is the JSON schema of its arguments
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Tool {
+    pub name: String,
+    pub description: String,
+    pub parameters: serde_json::Value,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ToolCall {
+    pub id: String,
+    pub name: String,
+    /// JSON encoded arguments, as generated by the model
+    pub arguments: String,
+}
+
+/// sampling options sent with every request of an adapter, unset ones are left to
+/// the provider's defaults
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct CompletionArgs {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+    /// sequences where the provider stops generating
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stop: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response_format: Option<ResponseFormat>,
+}
+
+/// `{"type": "json_object"}` asks the model to answer with valid JSON,
+/// `{"type": "json_schema", "json_schema": {"name": .., "schema": {..}}}` with JSON
+/// matching the schema
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseFormat {
+    #[default]
+    Text,
+    JsonObject,
+    JsonSchema {
+        json_schema: JsonSchema,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JsonSchema {
+    pub name: String,
+    pub schema: serde_json::Value,
+    /// only answers matching the schema exactly, for providers supporting it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub strict: Option<bool>,
+}
+
+/// token usage reported by the provider for a single call
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Usage {
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+    /// prompt tokens served from the provider's context cache, part of `prompt_tokens`
+    pub cache_hit_tokens: u32,
+}
+
+/// the assistant message together with the usage of the call
+#[derive(Debug, Clone)]
+pub struct Completion {
+    pub message: Message,
+    pub usage: Usage,
+    /// index of the provider that answered, see `FallbackAdapter`
+    pub provider: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CompletionChunk {
+    /// content delta, concatenated they form the whole completion
+    Delta(String),
+    /// usage of the call, sent at the end of the stream if the provider reports it
+    Usage(Usage),
+    /// index of the provider that answered, see `FallbackAdapter`
+    Provider(usize),
 }
 
 #[allow(async_fn_in_trait)]
 #[enum_dispatch]
 pub trait AiService {
-    async fn complete(&self, messages: &[Message]) -> anyhow::Result<String>;
-    // other functions...
+    async fn complete(&self, messages: &[Message]) -> anyhow::Result<Completion>;
+
+    /// stream the completion as token deltas. Adapters without streaming support
+    /// yield the whole completion as a single delta.
+    async fn complete_stream(&self, messages: &[Message]) -> anyhow::Result<CompletionStream> {
+        let completion = self.complete(messages).await?;
+        let chunks = [
+            Ok(CompletionChunk::Delta(completion.message.content)),
+            Ok(CompletionChunk::Usage(completion.usage)),
+        ];
+        Ok(stream::iter(chunks).boxed())
+    }
+
+    /// complete with tools the model may call. The returned assistant message either
+    /// carries the answer or the tool calls to run. Adapters without tool support
+    /// ignore the tools and answer directly.
+    async fn complete_with_tools(
+        &self,
+        messages: &[Message],
+        _tools: &[Tool],
+    ) -> anyhow::Result<Completion> {
+        self.complete(messages).await
+    }
+
+    /// complete with the answer parsed as JSON, matching `schema` if set. The answer
+    /// is validated and retried if invalid, see [`JSON_RETRIES`]; an
+    /// [`InvalidJsonError`] is returned if it never is.
+    async fn complete_json(
+        &self,
+        messages: &[Message],
+        schema: Option<&serde_json::Value>,
+    ) -> anyhow::Result<JsonCompletion> {
+        json::complete_json(self, messages, schema).await
+    }
 }
 
 impl std::fmt::Display for Role {
@@ -37,6 +175,7 @@ impl std::fmt::Display for Role {
             Role::User => write!(f, "user"),
             Role::Assistant => write!(f, "assistant"),
             Role::System => write!(f, "system"),
+            Role::Tool => write!(f, "tool"),
         }
     }
 }
@@ -46,6 +185,8 @@ impl Message {
         Self {
             role,
             content: content.into(),
+            tool_calls: vec![],
+            tool_call_id: None,
         }
     }
 
@@ -60,6 +201,140 @@ impl Message {
     pub fn system(content: impl Into<String>) -> Self {
         Self::new(Role::System, content)
     }
+
+    /// assistant message requesting tool calls
+    pub fn tool_calls(content: impl Into<String>, tool_calls: Vec<ToolCall>) -> Self {
+        Self {
+            tool_calls,
+            ..Self::new(Role::Assistant, content)
+        }
+    }
+
+    /// result of the tool call `tool_call_id`
+    pub fn tool(tool_call_id: impl Into<String>, content: impl Into<String>) -> Self {
+        Self {
+            tool_call_id: Some(tool_call_id.into()),
+            ..Self::new(Role::Tool, content)
+        }
+    }
+}
+
+impl Usage {
+    pub fn new(prompt_tokens: u32, completion_tokens: u32) -> Self {
+        Self {
+            prompt_tokens,
+            completion_tokens,
+            cache_hit_tokens: 0,
+        }
+    }
+
+    pub fn total_tokens(&self) -> u32 {
+        self.prompt_tokens + self.completion_tokens
+    }
+}
+
+impl AddAssign for Usage {
+    fn add_assign(&mut self, rhs: Self) {
+        self.prompt_tokens += rhs.prompt_tokens;
+        self.completion_tokens += rhs.completion_tokens;
+        self.cache_hit_tokens += rhs.cache_hit_tokens;
+    }
+}
+
+impl CompletionArgs {
+    /// check the ranges every provider agrees on, adapters check their own limits
+    /// on top, see e.g. [`DeepSeekAdapter::check_args`]
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if let Some(t) = self.temperature {
+            anyhow::ensure!(
+                (0.0..=2.0).contains(&t),
+                "temperature must be between 0 and 2"
+            );
+        }
+        if let Some(p) = self.top_p {
+            anyhow::ensure!(
+                p > 0.0 && p <= 1.0,
+                "top_p must be greater than 0 and at most 1"
+            );
+        }
+        anyhow::ensure!(
+            self.max_tokens != Some(0),
+            "max_tokens must be greater than 0"
+        );
+        anyhow::ensure!(
+            self.stop.iter().all(|s| !s.is_empty()),
+            "stop sequences must not be empty"
+        );
+        if let Some(schema) = self.schema() {
+            validate_schema(schema)?;
+        }
+        Ok(())
+    }
+
+    /// whether the model is asked to answer with JSON
+    pub fn json(&self) -> bool {
+        matches!(
+            self.response_format,
+            Some(ResponseFormat::JsonObject | ResponseFormat::JsonSchema { .. })
+        )
+    }
+
+    /// the schema the answer has to match, if any
+    pub fn schema(&self) -> Option<&serde_json::Value> {
+        match &self.response_format {
+            Some(ResponseFormat::JsonSchema { json_schema }) => Some(&json_schema.schema),
+            _ => None,
+        }
+    }
+}
+
+impl Completion {
+    pub fn new(message: Message, usage: Usage) -> Self {
+        Self {
+            message,
+            usage,
+            provider: 0,
+        }
+    }
+
+    pub fn with_provider(self, provider: usize) -> Self {
+        Self { provider, ..self }
+    }
+}
+
+impl CompletionChunk {
+    pub fn into_delta(self) -> Option<String> {
+        match self {
+            CompletionChunk::Delta(delta) => Some(delta),
+            CompletionChunk::Usage(_) | CompletionChunk::Provider(_) => None,
+        }
+    }
+}
+
+impl Tool {
+    pub fn new(
+        name: impl Into<String>,
+        description: impl Into<String>,
+        parameters: serde_json::Value,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            description: description.into(),
+            parameters,
+        }
+    }
+}
+
+impl ToolCall {
+    /// parse the arguments generated by the model
+    pub fn parse_arguments<T: serde::de::DeserializeOwned>(&self) -> anyhow::Result<T> {
+        let arguments = if self.arguments.trim().is_empty() {
+            "{}"
+        } else {
+            &self.arguments
+        };
+        Ok(serde_json::from_str(arguments)?)
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,480 @@
+//! retry, timeout and circuit breaker around any `AiService`
+//!
+//! - every request is bounded by `timeout`
+//! - transient failures (timeouts, connection errors, 429 and 5xx) are retried with
+//!   exponential backoff, a `Retry-After` delay sent by the provider takes precedence
+//! - after `failure_threshold` failed calls in a row the circuit opens and calls fail
+//!   fast for `open_duration`, then a single trial call decides whether it closes again
+//!
+//! the state is kept in a shared [`Resilience`] so it survives the adapter and can be
+//! observed through [`Resilience::metrics`].
+
+use crate::{AiService, Completion, CompletionStream, HttpStatusError, Message, Tool};
+use serde::{Deserialize, Serialize};
+use std::{
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tracing::warn;
+
+#[derive(Debug, Clone)]
+pub struct ResilienceConfig {
+    pub timeout: Duration,
+    pub max_retries: u32,
+    pub base_delay: Duration,
+    /// longest delay between retries, a longer `Retry-After` gives up instead
+    pub max_delay: Duration,
+    pub failure_threshold: u32,
+    pub open_duration: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ResilienceMetrics {
+    pub calls: u64,
+    pub successes: u64,
+    pub failures: u64,
+    pub retries: u64,
+    pub timeouts: u64,
+    /// calls rejected without reaching the provider because the circuit was open
+    pub rejected: u64,
+    pub consecutive_failures: u32,
+    pub state: CircuitState,
+}
+
+/// the provider didn't answer within the timeout or its circuit is open
+#[derive(Debug)]
+pub struct UnavailableError(String);
+
+/// circuit breaker state and metrics, shared by all adapters of the same agent
+#[derive(Debug)]
+pub struct Resilience {
+    config: ResilienceConfig,
+    inner: Mutex<ResilienceInner>,
+}
+
+#[derive(Debug)]
+struct ResilienceInner {
+    metrics: ResilienceMetrics,
+    opened_at: Option<Instant>,
+    trial_in_flight: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct ResilientAdapter<S> {
+    inner: S,
+    resilience: Arc<Resilience>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Failure {
+    /// worth retrying, with the delay asked for by the provider
+    Transient(Option<Duration>),
+    Permanent,
+}
+
+impl Default for ResilienceConfig {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(60),
+            max_retries: 3,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            failure_threshold: 5,
+            open_duration: Duration::from_secs(30),
+        }
+    }
+}
+
+impl Default for ResilienceMetrics {
+    fn default() -> Self {
+        Self {
+            calls: 0,
+            successes: 0,
+            failures: 0,
+            retries: 0,
+            timeouts: 0,
+            rejected: 0,
+            consecutive_failures: 0,
+            state: CircuitState::Closed,
+        }
+    }
+}
+
+impl Resilience {
+    pub fn new(config: ResilienceConfig) -> Self {
+        Self {
+            config,
+            inner: Mutex::new(ResilienceInner {
+                metrics: ResilienceMetrics::default(),
+                opened_at: None,
+                trial_in_flight: false,
+            }),
+        }
+    }
+
+    pub fn config(&self) -> &ResilienceConfig {
+        &self.config
+    }
+
+    pub fn metrics(&self) -> ResilienceMetrics {
+        let mut inner = self.inner.lock().unwrap();
+        inner.refresh_state(self.config.open_duration);
+        inner.metrics.clone()
+    }
+
+    /// whether a call may go through, an open circuit only lets a single trial call
+    /// through once `open_duration` has passed
+    fn try_acquire(&self) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        inner.metrics.calls += 1;
+        inner.refresh_state(self.config.open_duration);
+        let allowed = match inner.metrics.state {
+            CircuitState::Closed => true,
+            CircuitState::HalfOpen if !inner.trial_in_flight => {
+                inner.trial_in_flight = true;
+                true
+            }
+            _ => false,
+        };
+        if !allowed {
+            inner.metrics.rejected += 1;
+        }
+        allowed
+    }
+
+    fn on_retry(&self, timeout: bool) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.metrics.retries += 1;
+        if timeout {
+            inner.metrics.timeouts += 1;
+        }
+    }
+
+    fn on_success(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.metrics.successes += 1;
+        inner.metrics.consecutive_failures = 0;
+        inner.metrics.state = CircuitState::Closed;
+        inner.opened_at = None;
+        inner.trial_in_flight = false;
+    }
+
+    fn on_failure(&self, timeout: bool, transient: bool) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.metrics.failures += 1;
+        if timeout {
+            inner.metrics.timeouts += 1;
+        }
+        let trial = std::mem::take(&mut inner.trial_in_flight);
+        // a bad request says nothing about the health of the provider
+        if !transient {
+            return;
+        }
+        inner.metrics.consecutive_failures += 1;
+        if trial || inner.metrics.consecutive_failures >= self.config.failure_threshold {
+            if inner.metrics.state != CircuitState::Open {
+                warn!(
+                    "circuit opened after {} failures",
+                    inner.metrics.consecutive_failures
+                );
+            }
+            inner.metrics.state = CircuitState::Open;
+            inner.opened_at = Some(Instant::now());
+        }
+    }
+
+    fn backoff(&self, attempt: u32) -> Duration {
+        let delay = self.config.base_delay.saturating_mul(1 << attempt.min(16));
+        delay.min(self.config.max_delay)
+    }
+}
+
+impl ResilienceInner {
+    fn refresh_state(&mut self, open_duration: Duration) {
+        if let (CircuitState::Open, Some(opened_at)) = (self.metrics.state, self.opened_at)
+            && opened_at.elapsed() >= open_duration
+        {
+            self.metrics.state = CircuitState::HalfOpen;
+        }
+    }
+}
+
+impl<S: AiService> ResilientAdapter<S> {
+    pub fn new(inner: S, resilience: Arc<Resilience>) -> Self {
+        Self { inner, resilience }
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn resilience(&self) -> &Arc<Resilience> {
+        &self.resilience
+    }
+
+    /// share the circuit breaker state of another adapter
+    pub fn with_resilience(self, resilience: Arc<Resilience>) -> Self {
+        Self { resilience, ..self }
+    }
+
+    async fn call<T, F, Fut>(&self, mut f: F) -> anyhow::Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = anyhow::Result<T>>,
+    {
+        if !self.resilience.try_acquire() {
+            return Err(
+                UnavailableError("circuit open, provider is unavailable".to_string()).into(),
+            );
+        }
+
+        let config = self.resilience.config();
+        let mut attempt = 0;
+        loop {
+            let (err, timeout) = match tokio::time::timeout(config.timeout, f()).await {
+                Ok(Ok(value)) => {
+                    self.resilience.on_success();
+                    return Ok(value);
+                }
+                Ok(Err(e)) => (e, false),
+                Err(_) => (
+                    UnavailableError(format!("request timed out after {:?}", config.timeout))
+                        .into(),
+                    true,
+                ),
+            };
+
+            let failure = if timeout {
+                Failure::Transient(None)
+            } else {
+                classify(&err)
+            };
+            let delay = match failure {
+                Failure::Transient(retry_after) if attempt < config.max_retries => {
+                    let delay = retry_after.unwrap_or_else(|| self.resilience.backoff(attempt));
+                    (delay <= config.max_delay).then_some(delay)
+                }
+                _ => None,
+            };
+            let Some(delay) = delay else {
+                self.resilience
+                    .on_failure(timeout, matches!(failure, Failure::Transient(_)));
+                return Err(err);
+            };
+
+            warn!("attempt {} failed, retry in {delay:?}: {err}", attempt + 1);
+            self.resilience.on_retry(timeout);
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+}
+
+impl<S: AiService> AiService for ResilientAdapter<S> {
+    async fn complete(&self, messages: &[Message]) -> anyhow::Result<Completion> {
+        self.call(|| self.inner.complete(messages)).await
+    }
+
+    /// only establishing the stream is retried, a stream failing midway is not
+    async fn complete_stream(&self, messages: &[Message]) -> anyhow::Result<CompletionStream> {
+        self.call(|| self.inner.complete_stream(messages)).await
+    }
+
+    async fn complete_with_tools(
+        &self,
+        messages: &[Message],
+        tools: &[Tool],
+    ) -> anyhow::Result<Completion> {
+        self.call(|| self.inner.complete_with_tools(messages, tools))
+            .await
+    }
+}
+
+impl<S: AiService> From<S> for ResilientAdapter<S> {
+    fn from(inner: S) -> Self {
+        Self::new(
+            inner,
+            Arc::new(Resilience::new(ResilienceConfig::default())),
+        )
+    }
+}
+
+/// whether the call may succeed later: the provider was unavailable, rate limited
+/// or failed on its side
+pub fn is_transient(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<UnavailableError>().is_some()
+        || matches!(classify(err), Failure::Transient(_))
+}
+
+impl std::fmt::Display for UnavailableError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for UnavailableError {}
+
+fn classify(err: &anyhow::Error) -> Failure {
+    if let Some(e) = err.downcast_ref::<HttpStatusError>() {
+        return if e.is_transient() {
+            Failure::Transient(e.retry_after)
+        } else {
+            Failure::Permanent
+        };
+    }
+    match err.downcast_ref::<reqwest::Error>() {
+        Some(e) if e.is_timeout() || e.is_connect() || e.is_request() => Failure::Transient(None),
+        _ => Failure::Permanent,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{OpenAiCompatAdapter, mock_server};
+    use axum::{
+        Json, Router,
+        extract::State,
+        http::StatusCode,
+        response::{IntoResponse, Response},
+        routing::post,
+    };
+    use serde_json::json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// fail the first `fail` calls with `status`, then answer
+    #[derive(Clone)]
+    struct Flaky {
+        hits: Arc<AtomicUsize>,
+        fail: usize,
+        status: StatusCode,
+    }
+
+    async fn completions(State(flaky): State<Flaky>) -> Response {
+        let hit = flaky.hits.fetch_add(1, Ordering::SeqCst);
+        if hit < flaky.fail {
+            return (flaky.status, [("retry-after", "0")], "try again").into_response();
+        }
+        Json(json!({
+            "choices": [{ "index": 0, "message": { "role": "assistant", "content": "ok" } }]
+        }))
+        .into_response()
+    }
+
+    async fn slow() -> &'static str {
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        "{}"
+    }
+
+    fn config() -> ResilienceConfig {
+        ResilienceConfig {
+            timeout: Duration::from_millis(100),
+            max_retries: 2,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(10),
+            failure_threshold: 2,
+            open_duration: Duration::from_millis(50),
+        }
+    }
+
+    async fn flaky_adapter(
+        fail: usize,
+        status: StatusCode,
+    ) -> (ResilientAdapter<OpenAiCompatAdapter>, Arc<AtomicUsize>) {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let flaky = Flaky {
+            hits: hits.clone(),
+            fail,
+            status,
+        };
+        let app = Router::new()
+            .route("/v1/chat/completions", post(completions))
+            .with_state(flaky);
+        let base_url = mock_server(app).await;
+        let inner = OpenAiCompatAdapter::new(format!("{base_url}/v1"), None, "llama3");
+        let adapter = ResilientAdapter::new(inner, Arc::new(Resilience::new(config())));
+        (adapter, hits)
+    }
+
+    #[tokio::test]
+    async fn resilient_adapter_should_retry_transient_errors() {
+        let (adapter, hits) = flaky_adapter(2, StatusCode::TOO_MANY_REQUESTS).await;
+        let completion = adapter.complete(&[Message::user("hi")]).await.unwrap();
+        assert_eq!(completion.message.content, "ok");
+        assert_eq!(hits.load(Ordering::SeqCst), 3);
+
+        let metrics = adapter.resilience().metrics();
+        assert_eq!(metrics.calls, 1);
+        assert_eq!(metrics.successes, 1);
+        assert_eq!(metrics.retries, 2);
+        assert_eq!(metrics.state, CircuitState::Closed);
+    }
+
+    #[tokio::test]
+    async fn resilient_adapter_should_not_retry_client_errors() {
+        let (adapter, hits) = flaky_adapter(1, StatusCode::BAD_REQUEST).await;
+        let err = adapter.complete(&[Message::user("hi")]).await.unwrap_err();
+        assert!(err.to_string().contains("400"));
+        assert!(!is_transient(&err));
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+
+        let metrics = adapter.resilience().metrics();
+        assert_eq!(metrics.failures, 1);
+        assert_eq!(metrics.consecutive_failures, 0);
+    }
+
+    #[tokio::test]
+    async fn resilient_adapter_should_open_circuit() {
+        let (adapter, hits) = flaky_adapter(usize::MAX, StatusCode::BAD_GATEWAY).await;
+        for _ in 0..2 {
+            let err = adapter.complete(&[Message::user("hi")]).await.unwrap_err();
+            assert!(is_transient(&err));
+        }
+        // 2 calls with 2 retries each
+        assert_eq!(hits.load(Ordering::SeqCst), 6);
+        assert_eq!(adapter.resilience().metrics().state, CircuitState::Open);
+
+        // rejected without reaching the provider
+        let err = adapter.complete(&[Message::user("hi")]).await.unwrap_err();
+        assert!(err.to_string().contains("circuit open"));
+        assert!(is_transient(&err));
+        assert_eq!(hits.load(Ordering::SeqCst), 6);
+
+        // after open_duration a single trial call goes through, failing reopens
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(adapter.resilience().metrics().state, CircuitState::HalfOpen);
+        assert!(adapter.complete(&[Message::user("hi")]).await.is_err());
+        assert_eq!(hits.load(Ordering::SeqCst), 9);
+
+        let metrics = adapter.resilience().metrics();
+        assert_eq!(metrics.state, CircuitState::Open);
+        assert_eq!(metrics.rejected, 1);
+        assert_eq!(metrics.failures, 3);
+    }
+
+    #[tokio::test]
+    async fn resilient_adapter_should_time_out() {
+        let app = Router::new().route("/v1/chat/completions", post(slow));
+        let base_url = mock_server(app).await;
+        let inner = OpenAiCompatAdapter::new(format!("{base_url}/v1"), None, "llama3");
+        let config = ResilienceConfig {
+            max_retries: 0,
+            ..config()
+        };
+        let adapter: ResilientAdapter<_> =
+            ResilientAdapter::new(inner, Arc::new(Resilience::new(config)));
+        let err = adapter.complete(&[Message::user("hi")]).await.unwrap_err();
+        assert!(err.to_string().contains("timed out"));
+        assert!(is_transient(&err));
+        assert_eq!(adapter.resilience().metrics().timeouts, 1);
+    }
+}
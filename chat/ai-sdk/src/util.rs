@@ -1,8 +1,166 @@
 use dotenv::dotenv;
+use futures::{Stream, StreamExt, TryStreamExt, stream};
+use reqwest::{Client, Response, StatusCode, header::RETRY_AFTER};
+use serde::de::DeserializeOwned;
+use std::time::Duration;
 
-pub fn get_deepseek_api_key() -> String {
+const DEFAULT_OPENAI_COMPAT_BASE_URL: &str = "https://api.openai.com/v1";
+const DEFAULT_OPENAI_COMPAT_MODEL: &str = "gpt-4o-mini";
+const DEFAULT_OLLAMA_HOST: &str = "http://localhost:11434";
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// non-success response of a provider, kept typed so callers can decide to retry
+#[derive(Debug)]
+pub struct HttpStatusError {
+    pub status: StatusCode,
+    /// delay asked for by the `Retry-After` header
+    pub retry_after: Option<Duration>,
+    pub message: String,
+}
+
+/// api key of deepseek, `None` if `DEEPSEEK_KEY` isn't set
+pub fn get_deepseek_api_key() -> Option<String> {
+    dotenv().ok();
+    std::env::var("DEEPSEEK_KEY").ok()
+}
+
+/// base url of the openai compatible server, e.g. `http://localhost:8000/v1` for vLLM
+pub fn get_openai_compat_base_url() -> String {
+    dotenv().ok();
+    std::env::var("OPENAI_COMPAT_BASE_URL")
+        .unwrap_or_else(|_| DEFAULT_OPENAI_COMPAT_BASE_URL.to_string())
+}
+
+/// api key of the openai compatible server, local servers usually don't need one
+pub fn get_openai_compat_api_key() -> Option<String> {
+    dotenv().ok();
+    std::env::var("OPENAI_COMPAT_KEY").ok()
+}
+
+/// model used when the agent doesn't specify one
+pub fn get_openai_compat_model() -> String {
+    dotenv().ok();
+    std::env::var("OPENAI_COMPAT_MODEL").unwrap_or_else(|_| DEFAULT_OPENAI_COMPAT_MODEL.to_string())
+}
+
+/// host of the ollama server
+pub fn get_ollama_host() -> String {
     dotenv().ok();
-    std::env::var("DEEPSEEK_KEY").expect("DEEPSEEK_KEY must be set")
+    std::env::var("OLLAMA_HOST").unwrap_or_else(|_| DEFAULT_OLLAMA_HOST.to_string())
+}
+
+/// http client shared by the adapters. Only connecting is bounded here, a streamed
+/// completion may legitimately take minutes, see `ResilientAdapter` for request timeouts
+pub(crate) fn http_client() -> Client {
+    Client::builder()
+        .connect_timeout(CONNECT_TIMEOUT)
+        .build()
+        .expect("http client should build")
+}
+
+/// turn a non-success response into an `HttpStatusError`, `parse_message` extracts the
+/// error message from the body
+pub(crate) async fn check_status(
+    response: Response,
+    parse_message: impl FnOnce(String) -> String,
+) -> anyhow::Result<Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    // only the delay-seconds form is supported, http dates are ignored
+    let retry_after = response
+        .headers()
+        .get(RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .map(Duration::from_secs);
+    let body = response.text().await.unwrap_or_default();
+    Err(HttpStatusError {
+        status,
+        retry_after,
+        message: parse_message(body),
+    }
+    .into())
+}
+
+impl HttpStatusError {
+    /// rate limited or server side failure, worth trying again
+    pub fn is_transient(&self) -> bool {
+        self.status == StatusCode::TOO_MANY_REQUESTS || self.status.is_server_error()
+    }
+}
+
+impl std::fmt::Display for HttpStatusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "http error ({}): {}", self.status, self.message)
+    }
+}
+
+impl std::error::Error for HttpStatusError {}
+
+/// split a byte stream into lines, a line is only yielded once it is complete so
+/// multi-byte characters split across chunks are kept intact
+pub(crate) fn line_stream<S, B, E>(bytes: S) -> impl Stream<Item = anyhow::Result<String>> + Send
+where
+    S: Stream<Item = Result<B, E>> + Send,
+    B: AsRef<[u8]>,
+    E: Into<anyhow::Error>,
+{
+    stream::unfold(
+        (Box::pin(bytes), Vec::new(), false),
+        |(mut bytes, mut buf, mut eof)| async move {
+            loop {
+                if let Some(pos) = buf.iter().position(|b| *b == b'\n') {
+                    let line: Vec<u8> = buf.drain(..=pos).collect();
+                    let line = String::from_utf8_lossy(&line).trim_end().to_string();
+                    return Some((Ok(line), (bytes, buf, eof)));
+                }
+                if eof {
+                    if buf.is_empty() {
+                        return None;
+                    }
+                    let line = String::from_utf8_lossy(&buf).trim_end().to_string();
+                    buf.clear();
+                    return Some((Ok(line), (bytes, buf, eof)));
+                }
+                match bytes.next().await {
+                    Some(Ok(chunk)) => buf.extend_from_slice(chunk.as_ref()),
+                    Some(Err(e)) => {
+                        buf.clear();
+                        return Some((Err(e.into()), (bytes, buf, true)));
+                    }
+                    None => eof = true,
+                }
+            }
+        },
+    )
+}
+
+/// parse the `data:` payloads of a server-sent events stream as json, until `[DONE]`
+pub(crate) fn sse_json_stream<T, S>(lines: S) -> impl Stream<Item = anyhow::Result<T>> + Send
+where
+    T: DeserializeOwned,
+    S: Stream<Item = anyhow::Result<String>> + Send,
+{
+    lines
+        .try_filter_map(|line| async move {
+            let data = line.strip_prefix("data:").map(|d| d.trim().to_string());
+            Ok(data.filter(|d| !d.is_empty()))
+        })
+        .try_take_while(|data| futures::future::ready(Ok(data != "[DONE]")))
+        .and_then(|data| async move { Ok(serde_json::from_str::<T>(&data)?) })
+}
+
+/// start a mock http server with the given router, return its base url
+#[cfg(test)]
+pub(crate) async fn mock_server(app: axum::Router) -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        axum::serve(listener, app).await.unwrap();
+    });
+    format!("http://{addr}")
 }
 
 #[cfg(test)]
@@ -12,6 +170,39 @@ mod tests {
     #[test]
     fn get_deepseek_api_key_should_work() {
         let api_key = get_deepseek_api_key();
-        assert!(api_key.len() > 0)
+        assert!(api_key.is_some_and(|key| !key.is_empty()))
+    }
+
+    #[tokio::test]
+    async fn line_stream_should_keep_utf8_across_chunks() {
+        let text = "长江\n黄河\r\nlast";
+        let bytes = text.as_bytes();
+        // split inside the first multi-byte character
+        let chunks = vec![
+            bytes[..2].to_vec(),
+            bytes[2..9].to_vec(),
+            bytes[9..].to_vec(),
+        ];
+        let input = stream::iter(chunks.into_iter().map(Ok::<_, anyhow::Error>));
+        let lines: Vec<String> = line_stream(input).try_collect().await.unwrap();
+        assert_eq!(lines, vec!["长江", "黄河", "last"]);
+    }
+
+    #[tokio::test]
+    async fn sse_json_stream_should_stop_at_done() {
+        let lines = [
+            ": keep-alive",
+            "data: {\"n\": 1}",
+            "",
+            "data:{\"n\": 2}",
+            "data: [DONE]",
+            "data: {\"n\": 3}",
+        ];
+        let input = stream::iter(lines.map(|l| Ok(l.to_string())));
+        let values: Vec<serde_json::Value> = sse_json_stream(input).try_collect().await.unwrap();
+        assert_eq!(
+            values,
+            vec![serde_json::json!({"n": 1}), serde_json::json!({"n": 2})]
+        );
     }
 }
@@ -1,8 +1,7 @@
 mod middlewares;
 mod util;
 
-use chrono::{DateTime, Utc};
-use jwt_simple::reexports::serde_json;
+use chrono::{DateTime, NaiveTime, Utc};
 pub use middlewares::*;
 use serde::{Deserialize, Serialize};
 use sqlx::FromRow;
@@ -15,23 +14,106 @@ pub trait Agent: std::fmt::Debug {
     async fn process(&self, msg: &str, ctx: &AgentContext) -> Result<AgentDecision, AgentError>;
 }
 
-#[derive(Debug, Clone)]
-pub struct AgentContext {}
+/// what an agent knows about the conversation the message is sent to
+#[derive(Debug, Clone, Default)]
+pub struct AgentContext {
+    pub chat_id: i64,
+    pub chat_name: Option<String>,
+    /// author of the message being processed
+    pub sender: Option<ChatUser>,
+    pub members: Vec<ChatUser>,
+    /// bot users of the chat's agents
+    pub bots: Vec<ChatUser>,
+    /// latest messages of the chat before the one being processed, oldest first
+    pub history: Vec<Message>,
+    /// chunks retrieved for a knowledge agent, most relevant first
+    pub knowledge: Vec<KnowledgeChunk>,
+}
+
+/// a chunk of the knowledge base and where it comes from, cited in the answers
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct KnowledgeChunk {
+    /// e.g. `message 12` or the url of a file
+    pub source: String,
+    pub content: String,
+    pub score: f32,
+}
 
 impl AgentContext {
     pub fn new() -> Self {
-        Self {}
+        Self::default()
+    }
+
+    pub fn member(&self, id: i64) -> Option<&ChatUser> {
+        self.members.iter().find(|m| m.id == id)
+    }
+
+    /// the member or agent bot who wrote a message
+    pub fn author(&self, id: i64) -> Option<&ChatUser> {
+        self.member(id)
+            .or_else(|| self.bots.iter().find(|b| b.id == id))
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[serde(tag = "decision", content = "content", rename_all = "snake_case")]
 pub enum AgentDecision {
     Modify(String),
     Reply(String),
     Delete,
+    /// classification by a tap agent
+    Labels(Vec<Label>),
     None,
 }
 
+/// a label a tap agent put on a message, e.g. `spam`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct Label {
+    pub name: String,
+    /// confidence between 0 and 1, if the model gave one
+    #[serde(default)]
+    pub score: Option<f32>,
+}
+
+/// what happens to a labelled message, in increasing severity
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Serialize,
+    Deserialize,
+    sqlx::Type,
+    ToSchema,
+)]
+#[sqlx(type_name = "label_action", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum LabelAction {
+    /// only stored
+    #[default]
+    None,
+    /// listed for admin review
+    Flag,
+    /// stored but not shown to the chat
+    Hide,
+    /// rejected
+    Delete,
+}
+
+/// policy rule of a tap agent
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct LabelRule {
+    pub label: String,
+    pub action: LabelAction,
+    /// labels scored below are ignored, as are labels without a score
+    #[serde(default)]
+    pub min_score: Option<f32>,
+}
+
 #[derive(Error, Debug)]
 pub enum AgentError {
     #[error("Network error: {0}")]
@@ -68,6 +150,10 @@ pub struct ChatUser {
     pub id: i64,
     pub fullname: String,
     pub email: String,
+    /// speaks for an agent
+    #[sqlx(default)]
+    #[serde(default)]
+    pub is_bot: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Default, Deserialize, PartialEq, sqlx::Type, ToSchema)]
@@ -100,9 +186,30 @@ pub struct Message {
     #[sqlx(default)]
     pub modified_content: Option<String>,
     pub files: Vec<String>,
+    /// the message this one replies to
+    #[sqlx(default)]
+    #[serde(default)]
+    pub reply_to: Option<i64>,
+    /// hidden by a tap agent's policy
+    #[sqlx(default)]
+    #[serde(default)]
+    pub hidden: bool,
     pub created_at: DateTime<Utc>,
 }
 
+/// partial content of a message which is still being generated, e.g. by a reply agent
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, ToSchema)]
+pub struct MessageDelta {
+    /// id of the message being generated
+    pub id: i64,
+    pub chat_id: i64,
+    pub delta: String,
+    pub done: bool,
+    /// the reply failed and its message was removed
+    #[serde(default)]
+    pub failed: bool,
+}
+
 #[derive(Debug, Clone, Default, ToSchema, Serialize, Deserialize, PartialEq, sqlx::Type)]
 #[sqlx(type_name = "agent_type", rename_all = "snake_case")]
 pub enum AgentType {
@@ -113,6 +220,9 @@ pub enum AgentType {
     Reply,
     #[serde(alias = "tap", alias = "Tap")]
     Tap,
+    /// answers from the chunks of a collection closest to the message, citing them
+    #[serde(alias = "knowledge", alias = "Knowledge")]
+    Knowledge,
 }
 
 #[derive(Debug, Clone, Default, ToSchema, Serialize, Deserialize, PartialEq, sqlx::Type)]
@@ -121,6 +231,13 @@ pub enum AdapterType {
     #[default]
     #[serde(alias = "deepseek", alias = "DeepSeek")]
     Deepseek,
+    #[serde(alias = "openai_compat", alias = "OpenAiCompat", alias = "openai")]
+    OpenaiCompat,
+    #[serde(alias = "ollama", alias = "Ollama")]
+    Ollama,
+    /// deterministic adapter without network access, see `ai_sdk::MockAdapter`
+    #[serde(alias = "mock", alias = "Mock")]
+    Mock,
 }
 
 #[derive(Debug, Clone, FromRow, ToSchema, Serialize, Deserialize, PartialEq)]
@@ -131,12 +248,176 @@ pub struct ChatAgent {
     pub r#type: AgentType,
     pub adapter: AdapterType,
     pub model: String,
+    /// template of the system prompt, a proxy without user prompt sends it as
+    /// the user turn
     pub prompt: String,
-    pub args: serde_json::Value, // TODO: change to custom type
+    /// template of the user turn, the message itself when empty
+    pub user_prompt: String,
+    #[sqlx(json)]
+    pub args: AgentArgs,
+    /// providers tried in order when the primary adapter/model fails
+    #[sqlx(json)]
+    pub fallbacks: Vec<AgentProvider>,
+    /// agents of a chat run in ascending priority, ties by id
+    pub priority: i32,
+    /// bot user the agent's replies are sent as
+    pub bot_id: Option<i64>,
+    /// which messages the agent is evaluated on
+    #[sqlx(json)]
+    pub trigger: AgentTrigger,
+    /// actions taken on the labels of a tap agent
+    #[sqlx(json)]
+    pub policy: Vec<LabelRule>,
+    /// disabled agents aren't evaluated on messages
+    pub enabled: bool,
+    /// max calls per minute, failed ones included, `None` means unlimited
+    pub rate_limit: Option<i32>,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
 }
 
+/// model options of an agent, checked against its adapters when the agent is saved
+#[derive(Debug, Clone, Default, ToSchema, Serialize, Deserialize, PartialEq)]
+pub struct AgentArgs {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+    /// sequences where the model stops generating
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stop: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response_format: Option<ResponseFormat>,
+    /// at most this many earlier messages of the chat are given as context, within
+    /// the server's history limits
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub history: Option<usize>,
+    /// built-in server tools a reply agent may call, e.g. `search_messages`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tools: Vec<String>,
+    /// where a knowledge agent looks for answers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub knowledge: Option<KnowledgeArgs>,
+}
+
+#[derive(Debug, Clone, Default, ToSchema, Serialize, Deserialize, PartialEq)]
+pub struct KnowledgeArgs {
+    #[serde(default)]
+    pub collection: KnowledgeCollection,
+    /// chunks given to the model, 5 by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<usize>,
+}
+
+/// a part of the workspace's knowledge base
+#[derive(Debug, Clone, Copy, Default, ToSchema, Serialize, Deserialize, PartialEq, sqlx::Type)]
+#[sqlx(type_name = "knowledge_collection", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum KnowledgeCollection {
+    /// documents of the workspace, e.g. runbooks
+    #[default]
+    Docs,
+    /// uploaded files added to the knowledge base of the workspace
+    Files,
+    /// messages of the agent's chat
+    ChatHistory,
+}
+
+#[derive(Debug, Clone, Default, ToSchema, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseFormat {
+    #[default]
+    Text,
+    /// answer with a JSON object
+    JsonObject,
+    /// answer with JSON matching the schema
+    JsonSchema { json_schema: JsonSchema },
+}
+
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize, PartialEq)]
+pub struct JsonSchema {
+    pub name: String,
+    #[schema(value_type = Object)]
+    pub schema: serde_json::Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub strict: Option<bool>,
+}
+
+#[derive(Debug, Clone, Default, ToSchema, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum AgentTrigger {
+    /// every message of the chat
+    #[default]
+    Always,
+    /// `@name` in the message, a `/name` prefix or a reply to one of its messages
+    Mention,
+    /// messages matching the regex
+    Keyword { pattern: String },
+    /// messages sent between the times of day (UTC), e.g. an out of office
+    /// responder. `from` after `to` wraps around midnight
+    Schedule { from: NaiveTime, to: NaiveTime },
+}
+
+/// urls and keys aren't accepted, they only come from chat.yml
+#[derive(Debug, Clone, Default, ToSchema, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct AgentProvider {
+    pub adapter: AdapterType,
+    pub model: String,
+    /// name of a server in `providers.servers` of chat.yml, the adapter's own
+    /// server and key are used unless set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server: Option<String>,
+}
+
+impl LabelRule {
+    pub fn matches(&self, label: &Label) -> bool {
+        self.label.eq_ignore_ascii_case(&label.name)
+            && match (self.min_score, label.score) {
+                (Some(min), Some(score)) => score >= min,
+                (Some(_), None) => false,
+                (None, _) => true,
+            }
+    }
+}
+
+impl ChatAgent {
+    /// the most severe action of the policy rules matching the label
+    pub fn label_action(&self, label: &Label) -> LabelAction {
+        self.policy
+            .iter()
+            .filter(|rule| rule.matches(label))
+            .map(|rule| rule.action)
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// the primary adapter/model followed by the fallbacks
+    pub fn providers(&self) -> Vec<AgentProvider> {
+        let primary = AgentProvider::new(self.adapter.clone(), self.model.clone());
+        std::iter::once(primary)
+            .chain(self.fallbacks.iter().cloned())
+            .collect()
+    }
+}
+
+impl AgentProvider {
+    pub fn new(adapter: AdapterType, model: impl Into<String>) -> Self {
+        Self {
+            adapter,
+            model: model.into(),
+            server: None,
+        }
+    }
+
+    pub fn with_server(mut self, server: impl Into<String>) -> Self {
+        self.server = Some(server.into());
+        self
+    }
+}
+
 impl User {
     pub fn new(id: i64, fullname: &str, email: &str) -> Self {
         Self {
@@ -1,5 +1,82 @@
-use ai_sdk::{get_deepseek_api_key, AiAdapter, AiService, DeepSeekAdapter};
-use chat_core::{AdapterType, Agent, AgentContext, AgentDecision, AgentType, ChatAgent};
+use crate::{config::unavailable, ProviderConfig, ProvidersConfig};
+use ai_sdk::{
+    get_deepseek_api_key, get_ollama_host, get_openai_compat_api_key, get_openai_compat_base_url,
+    get_openai_compat_model, parse_json, AiAdapter, AiService, Completion, CompletionArgs,
+    CompletionChunk, CompletionStream, DeepSeekAdapter, FallbackAdapter, InvalidJsonError,
+    MockAdapter, OllamaAdapter, OpenAiCompatAdapter, Resilience, ResilientAdapter, Usage,
+};
+use chat_core::{
+    AdapterType, Agent, AgentArgs, AgentContext, AgentDecision, AgentProvider, AgentType,
+    ChatAgent, KnowledgeChunk, Label, Message,
+};
+use futures::{stream, StreamExt};
+use serde_json::{json, Value};
+use std::sync::{Arc, LazyLock};
+
+mod args;
+mod template;
+mod tools;
+mod trigger;
+
+pub use args::{completion_args, validate_args};
+pub use template::{render_prompt, uses_message, validate_template};
+pub use tools::ChatTools;
+pub use trigger::{agent_triggered, validate_trigger};
+
+/// max rounds of tool calls before the reply agent has to answer
+const MAX_TOOL_ROUNDS: usize = 4;
+
+/// appended to the prompt of a tap agent so its answer can be parsed
+const TAP_INSTRUCTION: &str = r#"Answer only with JSON listing the labels which apply to the message, e.g. {"labels": [{"name": "spam", "score": 0.9}]}, or {"labels": []} if none does."#;
+
+/// appended to the prompt of a knowledge agent, followed by the numbered sources
+const KNOWLEDGE_INSTRUCTION: &str = "Answer using only the sources below and cite the ones you use by their number, e.g. [1]. If they don't contain the answer, say you don't know.";
+
+/// the answer of a tap agent: label names, or labels with a score
+static LABELS_SCHEMA: LazyLock<Value> = LazyLock::new(|| {
+    json!({
+        "type": "object",
+        "properties": {
+            "labels": {
+                "type": "array",
+                "items": {
+                    "anyOf": [
+                        { "type": "string" },
+                        {
+                            "type": "object",
+                            "properties": {
+                                "name": { "type": "string" },
+                                "score": { "type": "number" }
+                            },
+                            "required": ["name"]
+                        }
+                    ]
+                }
+            }
+        },
+        "required": ["labels"]
+    })
+});
+
+/// the agent's providers tried in order, each called with timeouts, retries and
+/// a circuit breaker
+pub type AgentAdapter = FallbackAdapter<ResilientAdapter<AiAdapter>>;
+
+/// tokens used by an agent call and the index of the provider that answered it
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CallUsage {
+    pub usage: Usage,
+    pub provider: usize,
+}
+
+/// the decision of an agent, the raw answer of the model it was taken from and the
+/// tokens it took
+#[derive(Debug, Clone)]
+pub struct AgentCall {
+    pub decision: AgentDecision,
+    pub usage: CallUsage,
+    pub response: String,
+}
 
 #[allow(unused)]
 #[derive(Debug)]
@@ -7,69 +84,487 @@ pub enum AgentVariant {
     Replay(ReplyAgent),
     Tap(TapAgent),
     Proxy(ProxyAgent),
+    Knowledge(KnowledgeAgent),
 }
 
 #[allow(unused)]
 #[derive(Debug)]
 pub struct ProxyAgent {
     pub name: String,
-    pub adapter: AiAdapter,
+    pub adapter: AgentAdapter,
     pub prompt: String,
-    pub args: serde_json::Value,
+    pub user_prompt: String,
+    pub args: AgentArgs,
 }
 
 #[allow(unused)]
 #[derive(Debug)]
 pub struct ReplyAgent {
     pub name: String,
-    pub adapter: AiAdapter,
+    pub adapter: AgentAdapter,
     pub prompt: String,
-    pub args: serde_json::Value,
+    pub user_prompt: String,
+    pub args: AgentArgs,
+    /// bot user the replies are sent as
+    pub bot_id: Option<i64>,
 }
 
 #[allow(unused)]
 #[derive(Debug)]
 pub struct TapAgent {
     pub name: String,
-    pub adapter: AiAdapter,
+    pub adapter: AgentAdapter,
+    pub prompt: String,
+    pub user_prompt: String,
+    pub args: AgentArgs,
+}
+
+#[allow(unused)]
+#[derive(Debug)]
+pub struct KnowledgeAgent {
+    pub name: String,
+    pub adapter: AgentAdapter,
     pub prompt: String,
-    pub args: serde_json::Value,
+    pub user_prompt: String,
+    pub args: AgentArgs,
 }
 
 impl Agent for ProxyAgent {
     async fn process(
         &self,
         msg: &str,
-        _ctx: &AgentContext,
+        ctx: &AgentContext,
     ) -> Result<chat_core::AgentDecision, chat_core::AgentError> {
-        let prompt = format!("{}: {}", self.prompt, msg);
-        let messages = vec![ai_sdk::Message::user(prompt)];
-        let response = self.adapter.complete(&messages).await?;
-        let decision = AgentDecision::Modify(response);
+        let (decision, _) = self.process_with_usage(msg, ctx).await?;
         Ok(decision)
     }
 }
 
+impl ProxyAgent {
+    pub async fn process_with_usage(
+        &self,
+        msg: &str,
+        ctx: &AgentContext,
+    ) -> Result<(AgentDecision, CallUsage), chat_core::AgentError> {
+        let call = self.call(msg, ctx).await?;
+        Ok((call.decision, call.usage))
+    }
+
+    pub async fn call(
+        &self,
+        msg: &str,
+        ctx: &AgentContext,
+    ) -> Result<AgentCall, chat_core::AgentError> {
+        let messages = self.messages(msg, ctx);
+        let completion = complete(&self.adapter, &messages, &self.args).await?;
+        let response = completion.message.content;
+        Ok(AgentCall {
+            decision: AgentDecision::Modify(response.clone()),
+            usage: CallUsage::new(completion.usage, completion.provider),
+            response,
+        })
+    }
+
+    /// the prompt as system message and the user prompt as user turn. Without user
+    /// prompt the prompt is the user turn, followed by the message unless it places
+    /// the message itself
+    pub fn messages(&self, msg: &str, ctx: &AgentContext) -> Vec<ai_sdk::Message> {
+        let history = recent_history(ctx, self.args.history);
+        let prompt = render_prompt(&self.prompt, msg, ctx, history);
+        if !self.user_prompt.is_empty() {
+            let user = render_prompt(&self.user_prompt, msg, ctx, history);
+            let mut messages = Vec::with_capacity(2);
+            if !prompt.is_empty() {
+                messages.push(ai_sdk::Message::system(prompt));
+            }
+            messages.push(ai_sdk::Message::user(user));
+            return messages;
+        }
+        let prompt = if uses_message(&self.prompt) {
+            prompt
+        } else {
+            format!("{prompt}: {msg}")
+        };
+        vec![ai_sdk::Message::user(prompt)]
+    }
+}
+
 impl Agent for ReplyAgent {
     async fn process(
         &self,
         msg: &str,
-        _ctx: &AgentContext,
+        ctx: &AgentContext,
     ) -> Result<chat_core::AgentDecision, chat_core::AgentError> {
-        let messages = vec![ai_sdk::Message::user(msg)];
-        let response = self.adapter.complete(&messages).await?;
-        let decision = AgentDecision::Reply(response);
+        let (decision, _) = self.process_with_usage(msg, ctx).await?;
         Ok(decision)
     }
 }
 
+impl ReplyAgent {
+    pub async fn process_with_usage(
+        &self,
+        msg: &str,
+        ctx: &AgentContext,
+    ) -> Result<(AgentDecision, CallUsage), chat_core::AgentError> {
+        let call = self.call(msg, ctx).await?;
+        Ok((call.decision, call.usage))
+    }
+
+    pub async fn call(
+        &self,
+        msg: &str,
+        ctx: &AgentContext,
+    ) -> Result<AgentCall, chat_core::AgentError> {
+        let messages = self.conversation(msg, ctx);
+        let completion = complete(&self.adapter, &messages, &self.args).await?;
+        let response = completion.message.content;
+        Ok(AgentCall {
+            decision: AgentDecision::Reply(response.clone()),
+            usage: CallUsage::new(completion.usage, completion.provider),
+            response,
+        })
+    }
+
+    /// stream the reply as content deltas instead of waiting for the whole completion.
+    /// When tools are enabled, run a bounded tool loop first, the answer is then
+    /// delivered as a single delta. The usage of the tool rounds is reported as
+    /// its own chunk. So is a JSON answer, which is validated as a whole first.
+    pub async fn process_stream(
+        &self,
+        msg: &str,
+        ctx: &AgentContext,
+        tools: &ChatTools,
+    ) -> Result<CompletionStream, chat_core::AgentError> {
+        let mut messages = self.conversation(msg, ctx);
+        if completion_args(&self.args).json() {
+            let completion = complete(&self.adapter, &messages, &self.args).await?;
+            return Ok(single_delta(completion, Usage::default()));
+        }
+        let definitions = tools.definitions(&self.args.tools);
+        if definitions.is_empty() {
+            return Ok(self.adapter.complete_stream(&messages).await?);
+        }
+
+        let mut usage = Usage::default();
+        for _ in 0..MAX_TOOL_ROUNDS {
+            let completion = self
+                .adapter
+                .complete_with_tools(&messages, &definitions)
+                .await?;
+            if completion.message.tool_calls.is_empty() {
+                return Ok(single_delta(completion, usage));
+            }
+            usage += completion.usage;
+            let reply = completion.message;
+            let calls = reply.tool_calls.clone();
+            messages.push(reply);
+            for call in calls {
+                let result = tools.call(&call).await;
+                messages.push(ai_sdk::Message::tool(call.id, result));
+            }
+        }
+
+        // out of tool rounds, answer with what has been gathered so far
+        let answer = self.adapter.complete_stream(&messages).await?;
+        let rounds = stream::once(async move { Ok(CompletionChunk::Usage(usage)) });
+        Ok(rounds.chain(answer).boxed())
+    }
+
+    /// the prompt as system message, then the chat history, cut to `args.history` messages
+    /// if set, and the incoming message, rendered with the user prompt if there is one.
+    /// The agent's own replies are assistant turns, everything else is a user turn,
+    /// labelled with the author's name when more than one member can talk to it.
+    pub fn conversation(&self, msg: &str, ctx: &AgentContext) -> Vec<ai_sdk::Message> {
+        let history = recent_history(ctx, self.args.history);
+        let mut messages = Vec::with_capacity(history.len() + 2);
+        let prompt = render_prompt(&self.prompt, msg, ctx, history);
+        if !prompt.is_empty() {
+            messages.push(ai_sdk::Message::system(prompt));
+        }
+        let sender_id = ctx.sender.as_ref().map(|u| u.id);
+        let labelled = ctx.members.len() > 1;
+        let label = |id: i64, content: &str| match ctx.author(id) {
+            Some(user) if labelled => format!("{}: {content}", user.fullname),
+            _ => content.to_string(),
+        };
+        for m in history {
+            let message = if Some(m.sender_id) == self.bot_id {
+                ai_sdk::Message::assistant(&m.content)
+            } else {
+                ai_sdk::Message::user(label(m.sender_id, &m.content))
+            };
+            messages.push(message);
+        }
+        let content = match (self.user_prompt.as_str(), sender_id) {
+            ("", Some(id)) => label(id, msg),
+            ("", None) => msg.to_string(),
+            (template, _) => render_prompt(template, msg, ctx, history),
+        };
+        messages.push(ai_sdk::Message::user(content));
+        messages
+    }
+}
+
+/// the completion as a stream of a single delta, its usage added to `usage`
+fn single_delta(completion: Completion, mut usage: Usage) -> CompletionStream {
+    usage += completion.usage;
+    let chunks = [
+        Ok(CompletionChunk::Provider(completion.provider)),
+        Ok(CompletionChunk::Delta(completion.message.content)),
+        Ok(CompletionChunk::Usage(usage)),
+    ];
+    stream::iter(chunks).boxed()
+}
+
+/// the latest `limit` messages of the context's history, all of them if unset
+fn recent_history(ctx: &AgentContext, limit: Option<usize>) -> &[Message] {
+    match limit {
+        Some(n) => &ctx.history[ctx.history.len().saturating_sub(n)..],
+        None => &ctx.history[..],
+    }
+}
+
+/// rough token count of the content: about 4 ascii characters per token, other
+/// characters (e.g. CJK) take a token each
+pub fn estimate_tokens(content: &str) -> usize {
+    let ascii = content.chars().filter(|c| c.is_ascii()).count();
+    let other = content.chars().count() - ascii;
+    ascii.div_ceil(4) + other
+}
+
 impl Agent for TapAgent {
     async fn process(
         &self,
-        _msg: &str,
-        _ctx: &AgentContext,
+        msg: &str,
+        ctx: &AgentContext,
     ) -> Result<chat_core::AgentDecision, chat_core::AgentError> {
-        Ok(AgentDecision::None)
+        let (decision, _) = self.process_with_usage(msg, ctx).await?;
+        Ok(decision)
+    }
+}
+
+impl TapAgent {
+    /// classify the message with the prompt, e.g. for toxicity, PII or spam
+    pub async fn process_with_usage(
+        &self,
+        msg: &str,
+        ctx: &AgentContext,
+    ) -> Result<(AgentDecision, CallUsage), chat_core::AgentError> {
+        let call = self.call(msg, ctx).await?;
+        Ok((call.decision, call.usage))
+    }
+
+    pub async fn call(
+        &self,
+        msg: &str,
+        ctx: &AgentContext,
+    ) -> Result<AgentCall, chat_core::AgentError> {
+        let messages = self.messages(msg, ctx);
+        let ret = self
+            .adapter
+            .complete_json(&messages, Some(&LABELS_SCHEMA))
+            .await;
+        let (labels, usage, response) = match ret {
+            Ok(json) => {
+                let completion = json.completion;
+                let usage = CallUsage::new(completion.usage, completion.provider);
+                (json_labels(json.value), usage, completion.message.content)
+            }
+            // the first answer is taken leniently
+            Err(e) => match e.downcast::<InvalidJsonError>() {
+                Ok(e) => {
+                    let usage = CallUsage::new(e.usage, e.provider);
+                    (parse_labels(&e.answer), usage, e.answer)
+                }
+                Err(e) => return Err(e.into()),
+            },
+        };
+        Ok(AgentCall {
+            decision: AgentDecision::Labels(labels),
+            usage,
+            response,
+        })
+    }
+
+    /// the prompt with the answer format as system message, then the message or
+    /// the user prompt
+    pub fn messages(&self, msg: &str, ctx: &AgentContext) -> Vec<ai_sdk::Message> {
+        let history = recent_history(ctx, self.args.history);
+        let prompt = render_prompt(&self.prompt, msg, ctx, history);
+        let system = format!("{prompt}\n\n{TAP_INSTRUCTION}");
+        let user = match self.user_prompt.as_str() {
+            "" => msg.to_string(),
+            template => render_prompt(template, msg, ctx, history),
+        };
+        vec![ai_sdk::Message::system(system), ai_sdk::Message::user(user)]
+    }
+}
+
+impl Agent for KnowledgeAgent {
+    async fn process(
+        &self,
+        msg: &str,
+        ctx: &AgentContext,
+    ) -> Result<chat_core::AgentDecision, chat_core::AgentError> {
+        let call = self.call(msg, ctx).await?;
+        Ok(call.decision)
+    }
+}
+
+impl KnowledgeAgent {
+    /// answer the message from the chunks in the context, the sources cited by the
+    /// answer are listed after it
+    pub async fn call(
+        &self,
+        msg: &str,
+        ctx: &AgentContext,
+    ) -> Result<AgentCall, chat_core::AgentError> {
+        let messages = self.messages(msg, ctx);
+        let completion = complete(&self.adapter, &messages, &self.args).await?;
+        let response = completion.message.content;
+        Ok(AgentCall {
+            decision: AgentDecision::Reply(cite_sources(&response, &ctx.knowledge)),
+            usage: CallUsage::new(completion.usage, completion.provider),
+            response,
+        })
+    }
+
+    /// the prompt and the numbered sources as system message, then the message or
+    /// the user prompt
+    pub fn messages(&self, msg: &str, ctx: &AgentContext) -> Vec<ai_sdk::Message> {
+        let history = recent_history(ctx, self.args.history);
+        let prompt = render_prompt(&self.prompt, msg, ctx, history);
+        let sources = match ctx.knowledge.as_slice() {
+            [] => "none".to_string(),
+            chunks => chunks
+                .iter()
+                .enumerate()
+                .map(|(i, chunk)| format!("[{}] {}\n{}", i + 1, chunk.source, chunk.content))
+                .collect::<Vec<_>>()
+                .join("\n\n"),
+        };
+        let system = format!("{prompt}\n\n{KNOWLEDGE_INSTRUCTION}\n\nSources:\n{sources}");
+        let user = match self.user_prompt.as_str() {
+            "" => msg.to_string(),
+            template => render_prompt(template, msg, ctx, history),
+        };
+        vec![
+            ai_sdk::Message::system(system.trim_start()),
+            ai_sdk::Message::user(user),
+        ]
+    }
+}
+
+/// the answer followed by the sources it cites as `[n]`, e.g. `[1] message 12`
+fn cite_sources(answer: &str, sources: &[KnowledgeChunk]) -> String {
+    let cited: Vec<String> = sources
+        .iter()
+        .enumerate()
+        .map(|(i, chunk)| (format!("[{}]", i + 1), chunk))
+        .filter(|(n, _)| answer.contains(n.as_str()))
+        .map(|(n, chunk)| format!("{n} {}", chunk.source))
+        .collect();
+    if cited.is_empty() {
+        return answer.to_string();
+    }
+    format!("{answer}\n\nSources:\n{}", cited.join("\n"))
+}
+
+/// complete the messages. When the args ask for JSON the answer is validated, retried
+/// if needed, and replaced by the parsed JSON
+async fn complete(
+    adapter: &AgentAdapter,
+    messages: &[ai_sdk::Message],
+    args: &AgentArgs,
+) -> anyhow::Result<Completion> {
+    let args = completion_args(args);
+    if !args.json() {
+        return adapter.complete(messages).await;
+    }
+    let json = adapter.complete_json(messages, args.schema()).await?;
+    let mut completion = json.completion;
+    completion.message.content = json.value.to_string();
+    Ok(completion)
+}
+
+/// labels from the tap's answer: JSON as instructed, a JSON list, or comma separated
+/// names as a last resort
+fn parse_labels(answer: &str) -> Vec<Label> {
+    match parse_json(answer, None) {
+        Ok(value) => json_labels(value),
+        Err(_) => json_labels(Value::Array(
+            answer
+                .split([',', '\n'])
+                .map(|name| Value::String(name.to_string()))
+                .collect(),
+        )),
+    }
+}
+
+/// labels of a `{"labels": [..]}` object or a list, given by name or as objects.
+/// Names are lowercased, `none` means no label
+fn json_labels(value: Value) -> Vec<Label> {
+    let items = match value {
+        Value::Object(mut object) => match object.remove("labels") {
+            Some(Value::Array(items)) => items,
+            _ => vec![],
+        },
+        Value::Array(items) => items,
+        _ => vec![],
+    };
+    items
+        .into_iter()
+        .filter_map(|item| match item {
+            Value::String(name) => Some(Label { name, score: None }),
+            item => serde_json::from_value(item).ok(),
+        })
+        .map(|label| Label {
+            name: label.name.trim().to_lowercase(),
+            ..label
+        })
+        .filter(|label| !label.name.is_empty() && label.name != "none")
+        .collect()
+}
+
+impl AgentVariant {
+    /// share the circuit breaker state of each provider across calls, `resilience`
+    /// gets the index of the provider
+    pub fn with_resilience(mut self, resilience: impl Fn(usize) -> Arc<Resilience>) -> Self {
+        let adapter = match &mut self {
+            AgentVariant::Replay(agent) => &mut agent.adapter,
+            AgentVariant::Tap(agent) => &mut agent.adapter,
+            AgentVariant::Proxy(agent) => &mut agent.adapter,
+            AgentVariant::Knowledge(agent) => &mut agent.adapter,
+        };
+        for (i, provider) in adapter.providers_mut().iter_mut().enumerate() {
+            *provider = provider.clone().with_resilience(resilience(i));
+        }
+        self
+    }
+
+    /// process the message, keeping the model's answer and the tokens it took
+    /// along with the decision
+    pub async fn call(
+        &self,
+        msg: &str,
+        ctx: &AgentContext,
+    ) -> Result<AgentCall, chat_core::AgentError> {
+        match self {
+            AgentVariant::Replay(agent) => agent.call(msg, ctx).await,
+            AgentVariant::Tap(agent) => agent.call(msg, ctx).await,
+            AgentVariant::Proxy(agent) => agent.call(msg, ctx).await,
+            AgentVariant::Knowledge(agent) => agent.call(msg, ctx).await,
+        }
+    }
+
+    /// the messages sent to the model for the message
+    pub fn messages(&self, msg: &str, ctx: &AgentContext) -> Vec<ai_sdk::Message> {
+        match self {
+            AgentVariant::Replay(agent) => agent.conversation(msg, ctx),
+            AgentVariant::Tap(agent) => agent.messages(msg, ctx),
+            AgentVariant::Proxy(agent) => agent.messages(msg, ctx),
+            AgentVariant::Knowledge(agent) => agent.messages(msg, ctx),
+        }
     }
 }
 
@@ -83,38 +578,115 @@ impl Agent for AgentVariant {
             AgentVariant::Replay(agent) => agent.process(msg, ctx).await,
             AgentVariant::Tap(agent) => agent.process(msg, ctx).await,
             AgentVariant::Proxy(agent) => agent.process(msg, ctx).await,
+            AgentVariant::Knowledge(agent) => agent.process(msg, ctx).await,
         }
     }
 }
 
-impl From<ChatAgent> for AgentVariant {
-    fn from(value: ChatAgent) -> Self {
-        let adapter = match value.adapter {
-            AdapterType::Deepseek => {
-                let api_key = get_deepseek_api_key();
-                DeepSeekAdapter::new(api_key, value.model).into()
-            }
-        };
-        match value.r#type {
+impl AgentVariant {
+    /// the agent calling its providers, with the servers and keys of `providers`.
+    /// Fails if a server or key is missing in the config
+    pub fn new(value: ChatAgent, config: &ProvidersConfig) -> Result<Self, chat_core::AgentError> {
+        let args = completion_args(&value.args);
+        let providers = value
+            .providers()
+            .into_iter()
+            .map(|provider| {
+                let server = config
+                    .resolve(&provider)
+                    .ok_or_else(|| anyhow::anyhow!(unavailable(&provider)))?;
+                let adapter = provider_adapter(provider, server, args.clone())?;
+                Ok(ResilientAdapter::from(adapter))
+            })
+            .collect::<Result<_, chat_core::AgentError>>()?;
+        let adapter = AgentAdapter::new(providers);
+        let agent = match value.r#type {
             AgentType::Reply => AgentVariant::Replay(ReplyAgent {
                 name: value.name,
-                adapter: adapter,
+                adapter,
                 prompt: value.prompt,
+                user_prompt: value.user_prompt,
                 args: value.args,
+                bot_id: value.bot_id,
             }),
             AgentType::Tap => AgentVariant::Tap(TapAgent {
                 name: value.name,
-                adapter: adapter,
+                adapter,
                 prompt: value.prompt,
+                user_prompt: value.user_prompt,
                 args: value.args,
             }),
             AgentType::Proxy => AgentVariant::Proxy(ProxyAgent {
                 name: value.name,
-                adapter: adapter,
+                adapter,
                 prompt: value.prompt,
+                user_prompt: value.user_prompt,
                 args: value.args,
             }),
+            AgentType::Knowledge => AgentVariant::Knowledge(KnowledgeAgent {
+                name: value.name,
+                adapter,
+                prompt: value.prompt,
+                user_prompt: value.user_prompt,
+                args: value.args,
+            }),
+        };
+        Ok(agent)
+    }
+}
+
+fn provider_adapter(
+    provider: AgentProvider,
+    server: ProviderConfig,
+    args: CompletionArgs,
+) -> anyhow::Result<AiAdapter> {
+    let adapter = match provider.adapter {
+        AdapterType::Deepseek => {
+            let Some(api_key) = server.api_key.or_else(get_deepseek_api_key) else {
+                anyhow::bail!(
+                    "no deepseek api key, set providers.deepseek.api_key or DEEPSEEK_KEY"
+                );
+            };
+            DeepSeekAdapter::new(api_key, provider.model)
+                .with_args(args)
+                .into()
+        }
+        AdapterType::OpenaiCompat => {
+            let model = if provider.model.is_empty() {
+                get_openai_compat_model()
+            } else {
+                provider.model
+            };
+            OpenAiCompatAdapter::new(
+                server.base_url.unwrap_or_else(get_openai_compat_base_url),
+                server.api_key.or_else(get_openai_compat_api_key),
+                model,
+            )
+            .with_args(args)
+            .into()
+        }
+        AdapterType::Ollama => {
+            let host = server.base_url.unwrap_or_else(get_ollama_host);
+            OllamaAdapter::new(host, provider.model)
+                .with_args(args)
+                .into()
         }
+        AdapterType::Mock => MockAdapter::from_model(&provider.model)
+            .with_args(args)
+            .into(),
+    };
+    Ok(adapter)
+}
+
+impl CallUsage {
+    pub fn new(usage: Usage, provider: usize) -> Self {
+        Self { usage, provider }
+    }
+}
+
+impl From<Usage> for CallUsage {
+    fn from(usage: Usage) -> Self {
+        Self::new(usage, 0)
     }
 }
 
@@ -136,35 +708,396 @@ impl From<TapAgent> for AgentVariant {
     }
 }
 
+impl From<KnowledgeAgent> for AgentVariant {
+    fn from(value: KnowledgeAgent) -> Self {
+        AgentVariant::Knowledge(value)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{models::CreateAgent, AppState};
-    use std::collections::HashMap;
+    use crate::{
+        models::{CreateAgent, CreateMessage, StepDecision},
+        AppState,
+    };
 
-    #[ignore]
     #[tokio::test]
     async fn agent_variant_should_work() -> anyhow::Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
         let input = CreateAgent::new(
-            AdapterType::Deepseek,
-            "deepseek-chat",
+            AdapterType::Mock,
+            "echo",
             "test",
             AgentType::Proxy,
             "You are a helpful assistant",
-            HashMap::<String, String>::new(),
+            AgentArgs::default(),
         );
         let agent = state
             .create_agent(input, 1)
             .await
             .expect("create chat failed");
-        let agent: AgentVariant = agent.into();
+        let agent = state.agent_variant(agent)?;
 
         let decision = agent.process("Hello", &AgentContext::new()).await?;
-        if let AgentDecision::Modify(_) = decision {
+        if let AgentDecision::Modify(content) = decision {
+            assert_eq!(content, "You are a helpful assistant: Hello");
             Ok(())
         } else {
             panic!("Expected Modify decision")
         }
     }
+
+    #[tokio::test]
+    async fn agent_variant_should_fail_without_server_or_key() -> anyhow::Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = CreateAgent::new(
+            AdapterType::Deepseek,
+            "deepseek-chat",
+            "test",
+            AgentType::Proxy,
+            "",
+            AgentArgs::default(),
+        );
+        let mut agent = state.create_agent(input, 1).await?;
+        if get_deepseek_api_key().is_none() {
+            let err = state.agent_variant(agent.clone()).unwrap_err();
+            assert!(err.to_string().contains("no deepseek api key"));
+            // the agent fails, the worker goes on
+            let input = CreateMessage::new("hello", vec![]);
+            let message = state.create_message(input, 1, 1, 1).await?;
+            state.run_agent_jobs().await?;
+            let steps = state.list_agent_steps(1, message.id as _).await?;
+            assert_eq!(steps[0].decision, StepDecision::Failed);
+        }
+
+        agent.fallbacks =
+            vec![AgentProvider::new(AdapterType::Ollama, "llama3.2").with_server("gone")];
+        let err = state.agent_variant(agent).unwrap_err();
+        assert!(err.to_string().contains("unknown server gone"));
+        Ok(())
+    }
+
+    #[test]
+    fn parse_labels_should_work() {
+        let label = |name: &str, score| Label {
+            name: name.to_string(),
+            score,
+        };
+        let labels = parse_labels(r#"{"labels": [{"name": "Spam", "score": 0.9}, "pii"]}"#);
+        assert_eq!(labels, [label("spam", Some(0.9)), label("pii", None)]);
+        let labels = parse_labels("```json\n[\"toxicity\"]\n```");
+        assert_eq!(labels, [label("toxicity", None)]);
+        assert_eq!(
+            parse_labels("spam, PII"),
+            [label("spam", None), label("pii", None)]
+        );
+        assert!(parse_labels("none").is_empty());
+        assert!(parse_labels(r#"{"labels": []}"#).is_empty());
+    }
+
+    #[tokio::test]
+    async fn agents_should_answer_with_valid_json() -> anyhow::Result<()> {
+        let mock = MockAdapter::script(["spam, I guess", r#"{"labels": ["Spam"]}"#]);
+        let tap = TapAgent {
+            name: "moderator".to_string(),
+            adapter: ResilientAdapter::from(AiAdapter::from(mock.clone())).into(),
+            prompt: "Label spam".to_string(),
+            user_prompt: "".to_string(),
+            args: AgentArgs::default(),
+        };
+        let call = tap.call("buy now", &AgentContext::new()).await?;
+        let labels = vec![Label {
+            name: "spam".to_string(),
+            score: None,
+        }];
+        assert!(matches!(call.decision, AgentDecision::Labels(l) if l == labels));
+        assert_eq!(mock.prompts().len(), 2);
+
+        // a proxy extracting data gets the parsed JSON as content
+        let json_schema = chat_core::JsonSchema {
+            name: "order".to_string(),
+            schema: json!({ "type": "object", "required": ["item"] }),
+            strict: None,
+        };
+        let args = AgentArgs {
+            response_format: Some(chat_core::ResponseFormat::JsonSchema { json_schema }),
+            ..Default::default()
+        };
+        let mock = MockAdapter::script([r#"{"count": 2}"#, "```json\n{\"item\": \"tea\"}\n```"]);
+        let proxy = ProxyAgent {
+            name: "extractor".to_string(),
+            adapter: ResilientAdapter::from(AiAdapter::from(mock.clone())).into(),
+            prompt: "Extract the order".to_string(),
+            user_prompt: "".to_string(),
+            args,
+        };
+        let call = proxy.call("two teas", &AgentContext::new()).await?;
+        assert!(matches!(call.decision, AgentDecision::Modify(c) if c == r#"{"item":"tea"}"#));
+        let retry = &mock.prompts()[1];
+        assert!(retry
+            .last()
+            .unwrap()
+            .content
+            .contains("doesn't match the schema"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reply_agent_stream_should_answer_with_valid_json() -> anyhow::Result<()> {
+        use futures::TryStreamExt;
+
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let mock = MockAdapter::script(["Sure, it's tea", r#"{"item": "tea"}"#]);
+        let agent = ReplyAgent {
+            name: "waiter".to_string(),
+            adapter: ResilientAdapter::from(AiAdapter::from(mock.clone())).into(),
+            prompt: "Answer with the order as JSON".to_string(),
+            user_prompt: "".to_string(),
+            args: AgentArgs {
+                response_format: Some(chat_core::ResponseFormat::JsonObject),
+                ..Default::default()
+            },
+            bot_id: None,
+        };
+        let tools = ChatTools::new(state, 1, 1);
+        let stream = agent
+            .process_stream("one tea please", &AgentContext::new(), &tools)
+            .await?;
+        let chunks: Vec<CompletionChunk> = stream.try_collect().await?;
+        assert_eq!(
+            chunks[1],
+            CompletionChunk::Delta(r#"{"item":"tea"}"#.to_string())
+        );
+        assert_eq!(mock.prompts().len(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reply_agent_tool_loop_should_work() -> anyhow::Result<()> {
+        use axum::{routing::post, Json, Router};
+        use futures::TryStreamExt;
+        use serde_json::{json, Value};
+
+        // ask for the chat members first, then answer with the tool result
+        async fn completions(Json(body): Json<Value>) -> Json<Value> {
+            let last = body["messages"].as_array().unwrap().last().unwrap().clone();
+            let message = if last["role"] == "tool" {
+                let users: Vec<Value> =
+                    serde_json::from_str(last["content"].as_str().unwrap()).unwrap_or_default();
+                json!({ "role": "assistant", "content": format!("{} members", users.len()) })
+            } else {
+                assert_eq!(body["tools"][0]["function"]["name"], "list_chat_members");
+                json!({
+                    "role": "assistant",
+                    "content": null,
+                    "tool_calls": [{
+                        "id": "call_0",
+                        "type": "function",
+                        "function": { "name": "list_chat_members", "arguments": "{}" }
+                    }]
+                })
+            };
+            Json(json!({
+                "choices": [{ "index": 0, "message": message }],
+                "usage": { "prompt_tokens": 10, "completion_tokens": 2 }
+            }))
+        }
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let app = Router::new().route("/v1/chat/completions", post(completions));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let args = AgentArgs {
+            tools: vec!["list_chat_members".to_string()],
+            ..Default::default()
+        };
+        let agent = ReplyAgent {
+            name: "counter".to_string(),
+            adapter: ResilientAdapter::from(AiAdapter::from(OpenAiCompatAdapter::new(
+                format!("http://{addr}/v1"),
+                None,
+                "llama3",
+            )))
+            .into(),
+            prompt: "".to_string(),
+            user_prompt: "".to_string(),
+            args,
+            bot_id: None,
+        };
+        let tools = ChatTools::new(state, 1, 1);
+        let stream = agent
+            .process_stream("how many people are here?", &AgentContext::new(), &tools)
+            .await?;
+        let chunks: Vec<CompletionChunk> = stream.try_collect().await?;
+        assert_eq!(
+            chunks,
+            vec![
+                CompletionChunk::Provider(0),
+                CompletionChunk::Delta("5 members".to_string()),
+                CompletionChunk::Usage(Usage::new(20, 4)),
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reply_agent_should_follow_conversation() -> anyhow::Result<()> {
+        use ai_sdk::Role;
+
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = CreateAgent::new(
+            AdapterType::Mock,
+            "Not much",
+            "friend",
+            AgentType::Reply,
+            "You are a friendly chat partner",
+            AgentArgs::default(),
+        );
+        let chat_agent = state.create_agent(input, 3).await?;
+        let bot_id = chat_agent.bot_id.expect("agent should have a bot");
+        let mock = MockAdapter::script(["Not much"]);
+        let agent = ReplyAgent {
+            name: chat_agent.name,
+            adapter: ResilientAdapter::from(AiAdapter::from(mock.clone())).into(),
+            prompt: chat_agent.prompt,
+            user_prompt: chat_agent.user_prompt,
+            args: Default::default(),
+            bot_id: Some(bot_id),
+        };
+
+        // the bot's messages are the agent's turns, both members talk to it
+        for (sender_id, content) in [(1, "hi"), (bot_id, "hello"), (2, "hey")] {
+            let input = CreateMessage::new(content, vec![]);
+            state.create_message(input, 3, sender_id as _, 1).await?;
+        }
+        let input = CreateMessage::new("what's up?", vec![]);
+        let message = state.create_message(input, 3, 1, 1).await?;
+        let ctx = state.agent_context(&message, 1).await?;
+        let (decision, _) = agent.process_with_usage("what's up?", &ctx).await?;
+        assert!(matches!(decision, AgentDecision::Reply(content) if content == "Not much"));
+        let turns: Vec<_> = mock.prompts()[0]
+            .iter()
+            .map(|m| (m.role.clone(), m.content.clone()))
+            .collect();
+        let expected = [
+            (Role::System, "You are a friendly chat partner"),
+            (Role::User, "nyh@gmail.com: hi"),
+            (Role::Assistant, "hello"),
+            (Role::User, "alice123: hey"),
+            (Role::User, "nyh@gmail.com: what's up?"),
+        ]
+        .map(|(role, content)| (role, content.to_string()));
+        assert_eq!(turns, expected);
+
+        // in group chats every member is labelled too
+        let input = CreateMessage::new("anyone?", vec![]);
+        let message = state.create_message(input, 1, 2, 1).await?;
+        let ctx = state.agent_context(&message, 1).await?;
+        let messages = agent.conversation("anyone?", &ctx);
+        assert_eq!(messages.len(), 12);
+        assert_eq!(messages[1].content, "nyh@gmail.com: Hello, world!");
+        assert_eq!(messages[2].content, "alice123: Hi, there!");
+        assert_eq!(messages[11].content, "alice123: anyone?");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ollama_agent_variant_should_work() -> anyhow::Result<()> {
+        use axum::{routing::post, Json, Router};
+        use serde_json::{json, Value};
+
+        async fn chat(Json(body): Json<Value>) -> String {
+            let content = body["messages"][0]["content"].as_str().unwrap_or_default();
+            json!({
+                "model": body["model"],
+                "message": { "role": "assistant", "content": content.to_uppercase() },
+                "done": true
+            })
+            .to_string()
+        }
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let app = Router::new().route("/api/chat", post(chat));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let providers = ollama_providers(addr);
+
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = CreateAgent::new(
+            AdapterType::Ollama,
+            "llama3.2",
+            "shouter",
+            AgentType::Proxy,
+            "Repeat loudly",
+            AgentArgs::default(),
+        );
+        let agent = state.create_agent(input, 1).await?;
+        assert_eq!(agent.adapter, AdapterType::Ollama);
+        let agent = AgentVariant::new(agent, &providers)?;
+
+        let decision = agent.process("hello", &AgentContext::new()).await?;
+        match decision {
+            AgentDecision::Modify(content) => assert_eq!(content, "REPEAT LOUDLY: HELLO"),
+            _ => panic!("Expected Modify decision"),
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn agent_should_fall_back_to_next_provider() -> anyhow::Result<()> {
+        use axum::{http::StatusCode, routing::post, Json, Router};
+        use serde_json::{json, Value};
+
+        async fn chat(Json(body): Json<Value>) -> (StatusCode, String) {
+            if body["model"] == "missing" {
+                let error = json!({ "error": "model \"missing\" not found" });
+                return (StatusCode::NOT_FOUND, error.to_string());
+            }
+            let message = json!({
+                "model": body["model"],
+                "message": { "role": "assistant", "content": "bonjour" },
+                "done": true,
+                "prompt_eval_count": 8,
+                "eval_count": 2
+            });
+            (StatusCode::OK, message.to_string())
+        }
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let app = Router::new().route("/api/chat", post(chat));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let providers = ollama_providers(addr);
+
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = CreateAgent {
+            fallbacks: vec![AgentProvider::new(AdapterType::Ollama, "llama3.2")],
+            ..CreateAgent::new(
+                AdapterType::Ollama,
+                "missing",
+                "translator",
+                AgentType::Proxy,
+                "Translate to French",
+                AgentArgs::default(),
+            )
+        };
+        let agent = state.create_agent(input, 1).await?;
+        assert_eq!(agent.providers().len(), 2);
+        let agent = AgentVariant::new(agent, &providers)?;
+
+        let call = agent.call("hello", &AgentContext::new()).await?;
+        assert!(matches!(call.decision, AgentDecision::Modify(content) if content == "bonjour"));
+        assert_eq!(call.usage, CallUsage::new(Usage::new(8, 2), 1));
+        Ok(())
+    }
+
+    /// the ollama server of the test, passed explicitly as tests share the env
+    fn ollama_providers(addr: std::net::SocketAddr) -> ProvidersConfig {
+        let mut providers = ProvidersConfig::default();
+        providers.ollama.base_url = Some(format!("http://{addr}"));
+        providers
+    }
 }
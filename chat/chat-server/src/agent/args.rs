@@ -0,0 +1,106 @@
+use super::tools::TOOLS;
+use ai_sdk::{CompletionArgs, DeepSeekAdapter, MockAdapter, OllamaAdapter, OpenAiCompatAdapter};
+use chat_core::{AdapterType, AgentArgs, AgentProvider, ResponseFormat};
+
+/// the model options of the args, sent with every request of the agent
+pub fn completion_args(args: &AgentArgs) -> CompletionArgs {
+    CompletionArgs {
+        temperature: args.temperature,
+        top_p: args.top_p,
+        max_tokens: args.max_tokens,
+        stop: args.stop.clone(),
+        response_format: args.response_format.as_ref().map(|format| match format {
+            ResponseFormat::Text => ai_sdk::ResponseFormat::Text,
+            ResponseFormat::JsonObject => ai_sdk::ResponseFormat::JsonObject,
+            ResponseFormat::JsonSchema { json_schema } => ai_sdk::ResponseFormat::JsonSchema {
+                json_schema: ai_sdk::JsonSchema {
+                    name: json_schema.name.clone(),
+                    schema: json_schema.schema.clone(),
+                    strict: json_schema.strict,
+                },
+            },
+        }),
+    }
+}
+
+/// check the tools are built-in ones, without a JSON answer, and every provider of
+/// the agent supports its model and the args
+pub fn validate_args(providers: &[AgentProvider], args: &AgentArgs) -> Result<(), String> {
+    if let Some(name) = args
+        .tools
+        .iter()
+        .find(|name| !TOOLS.contains(&name.as_str()))
+    {
+        return Err(format!(
+            "unknown tool {name}, expected one of {}",
+            TOOLS.join(", ")
+        ));
+    }
+    let completion = completion_args(args);
+    // the answer of a tool loop isn't validated
+    if !args.tools.is_empty() && completion.json() {
+        return Err("tools can't be used with a JSON response_format".to_string());
+    }
+    for provider in providers {
+        let model = provider.model.as_str();
+        let ret = match provider.adapter {
+            AdapterType::Deepseek => DeepSeekAdapter::check_args(model, &completion),
+            AdapterType::OpenaiCompat => OpenAiCompatAdapter::check_args(model, &completion),
+            AdapterType::Ollama => OllamaAdapter::check_args(model, &completion),
+            AdapterType::Mock => MockAdapter::check_args(model, &completion),
+        };
+        ret.map_err(|e| format!("invalid args for {model}: {e}"))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_args_should_check_every_provider() {
+        let args = AgentArgs {
+            temperature: Some(0.2),
+            max_tokens: Some(16000),
+            ..Default::default()
+        };
+        let ollama = AgentProvider::new(AdapterType::Ollama, "llama3.2");
+        let deepseek = AgentProvider::new(AdapterType::Deepseek, "deepseek-chat");
+        let providers = [ollama, deepseek];
+        assert!(validate_args(&providers[..1], &args).is_ok());
+        // deepseek caps max_tokens
+        let err = validate_args(&providers, &args).unwrap_err();
+        assert!(err.contains("deepseek-chat"));
+
+        let unknown = AgentProvider::new(AdapterType::Deepseek, "deepseek-coder");
+        assert!(validate_args(&[unknown], &AgentArgs::default()).is_err());
+        let args = AgentArgs {
+            temperature: Some(3.0),
+            ..Default::default()
+        };
+        let mock = AgentProvider::new(AdapterType::Mock, "echo");
+        assert!(validate_args(&[mock], &args).is_err());
+    }
+
+    #[test]
+    fn validate_args_should_reject_unknown_tools() {
+        let mock = AgentProvider::new(AdapterType::Mock, "echo");
+        let mut args = AgentArgs {
+            tools: vec![
+                "search_messages".to_string(),
+                "list_chat_members".to_string(),
+            ],
+            ..Default::default()
+        };
+        assert!(validate_args(std::slice::from_ref(&mock), &args).is_ok());
+        args.tools.push("search_mesages".to_string());
+        let err = validate_args(std::slice::from_ref(&mock), &args).unwrap_err();
+        assert!(err.contains("unknown tool search_mesages"));
+
+        args.tools.pop();
+        args.response_format = Some(ResponseFormat::JsonObject);
+        let err = validate_args(&[mock], &args).unwrap_err();
+        assert!(err.contains("JSON response_format"));
+    }
+}
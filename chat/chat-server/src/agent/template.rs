@@ -0,0 +1,114 @@
+use chat_core::{AgentContext, Message};
+use chrono::Utc;
+use minijinja::{context, Environment};
+
+/// variables a prompt template can use, besides the builtin functions
+const TEMPLATE_VARS: [&str; 5] = ["message", "sender", "chat", "history", "now"];
+
+/// check the template parses and only uses the known variables
+pub fn validate_template(template: &str) -> Result<(), String> {
+    let env = Environment::new();
+    let tmpl = env
+        .template_from_str(template)
+        .map_err(|e| format!("invalid prompt template: {e}"))?;
+    let mut unknown: Vec<_> = tmpl
+        .undeclared_variables(false)
+        .into_iter()
+        .filter(|name| !TEMPLATE_VARS.contains(&name.as_str()))
+        .filter(|name| env.globals().all(|(global, _)| global != name))
+        .collect();
+    if !unknown.is_empty() {
+        unknown.sort();
+        return Err(format!(
+            "unknown variables in prompt template: {}",
+            unknown.join(", ")
+        ));
+    }
+    Ok(())
+}
+
+/// whether the template places the message itself
+pub fn uses_message(template: &str) -> bool {
+    Environment::new()
+        .template_from_str(template)
+        .map(|tmpl| tmpl.undeclared_variables(false).contains("message"))
+        .unwrap_or(false)
+}
+
+/// render the template for the message. `history` is the part of the chat history
+/// the agent sees, one `name: content` line per message.
+/// Templates are checked when the agent is saved, one which still fails to render
+/// (e.g. saved before templates existed) is used as is
+pub fn render_prompt(template: &str, msg: &str, ctx: &AgentContext, history: &[Message]) -> String {
+    // plain prompts are kept byte for byte, rendering would drop a trailing newline
+    if !template.contains("{{") && !template.contains("{%") {
+        return template.to_string();
+    }
+    let history = history
+        .iter()
+        .map(|m| match ctx.author(m.sender_id) {
+            Some(user) => format!("{}: {}", user.fullname, m.content),
+            None => m.content.clone(),
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    let vars = context! {
+        message => msg,
+        sender => ctx.sender,
+        chat => context! { id => ctx.chat_id, name => ctx.chat_name },
+        history => history,
+        now => Utc::now().to_rfc3339(),
+    };
+    Environment::new()
+        .render_str(template, vars)
+        .unwrap_or_else(|_| template.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chat_core::ChatUser;
+
+    #[test]
+    fn render_prompt_should_work() {
+        let alice = ChatUser {
+            id: 2,
+            fullname: "alice".to_string(),
+            email: "alice@acme.org".to_string(),
+            is_bot: false,
+        };
+        let ctx = AgentContext {
+            chat_id: 1,
+            chat_name: Some("general".to_string()),
+            sender: Some(alice.clone()),
+            members: vec![alice],
+            ..Default::default()
+        };
+        let history = [Message {
+            id: 1,
+            chat_id: 1,
+            sender_id: 2,
+            content: "hi".to_string(),
+            modified_content: None,
+            files: vec![],
+            reply_to: None,
+            hidden: false,
+            created_at: Utc::now(),
+        }];
+        let template = "{{ sender.fullname }} in #{{ chat.name }}:\n{{ history }}\n{{ message }}";
+        assert_eq!(
+            render_prompt(template, "hello", &ctx, &history),
+            "alice in #general:\nalice: hi\nhello"
+        );
+        assert!(render_prompt("{{ now }}", "", &ctx, &[]).starts_with("20"));
+        assert_eq!(render_prompt("Translate:\n", "", &ctx, &[]), "Translate:\n");
+
+        assert!(validate_template(template).is_ok());
+        assert!(validate_template("{% for i in range(3) %}{{ i }}{% endfor %}").is_ok());
+        let err = validate_template("{{ sender.name }} {{ user }}").unwrap_err();
+        assert_eq!(err, "unknown variables in prompt template: user");
+        assert!(validate_template("{{ message").is_err());
+        assert!(uses_message("Translate {{ message }}"));
+        assert!(!uses_message("Translate"));
+    }
+}
@@ -0,0 +1,168 @@
+use crate::{AppError, AppState};
+use ai_sdk::{Tool, ToolCall};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::warn;
+
+const SEARCH_MESSAGES: &str = "search_messages";
+const LIST_CHAT_MEMBERS: &str = "list_chat_members";
+const DEFAULT_SEARCH_LIMIT: u64 = 10;
+/// names of the built-in tools, accepted in `AgentArgs::tools`
+pub(super) const TOOLS: [&str; 2] = [SEARCH_MESSAGES, LIST_CHAT_MEMBERS];
+
+/// built-in server tools a reply agent may call, scoped to a single chat
+#[derive(Debug, Clone)]
+pub struct ChatTools {
+    state: AppState,
+    chat_id: u64,
+    ws_id: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct SearchMessagesArgs {
+    query: String,
+    #[serde(default)]
+    limit: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+struct FoundMessage {
+    id: i64,
+    sender_id: i64,
+    content: String,
+    created_at: String,
+}
+
+impl ChatTools {
+    pub fn new(state: AppState, chat_id: u64, ws_id: u64) -> Self {
+        Self {
+            state,
+            chat_id,
+            ws_id,
+        }
+    }
+
+    /// definitions of the enabled tools, unknown names are ignored
+    pub fn definitions(&self, enabled: &[String]) -> Vec<Tool> {
+        enabled
+            .iter()
+            .filter_map(|name| match name.as_str() {
+                SEARCH_MESSAGES => Some(Tool::new(
+                    SEARCH_MESSAGES,
+                    "Search messages in this chat whose content contains the query, newest first",
+                    json!({
+                        "type": "object",
+                        "properties": {
+                            "query": { "type": "string", "description": "text to search for" },
+                            "limit": { "type": "integer", "description": "max number of messages, default 10" }
+                        },
+                        "required": ["query"]
+                    }),
+                )),
+                LIST_CHAT_MEMBERS => Some(Tool::new(
+                    LIST_CHAT_MEMBERS,
+                    "List the members of this chat with their id, full name and email",
+                    json!({ "type": "object", "properties": {} }),
+                )),
+                _ => {
+                    warn!("unknown tool {name} ignored");
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// run the tool call, errors are reported back to the model as the result
+    pub async fn call(&self, call: &ToolCall) -> String {
+        match self.try_call(call).await {
+            Ok(result) => result,
+            Err(e) => {
+                warn!("tool call {} failed: {e}", call.name);
+                json!({ "error": e.to_string() }).to_string()
+            }
+        }
+    }
+
+    async fn try_call(&self, call: &ToolCall) -> Result<String, AppError> {
+        let result = match call.name.as_str() {
+            SEARCH_MESSAGES => {
+                let args: SearchMessagesArgs = call
+                    .parse_arguments()
+                    .map_err(|e| AppError::ToolCallError(e.to_string()))?;
+                let limit = args.limit.unwrap_or(DEFAULT_SEARCH_LIMIT);
+                let messages = self
+                    .state
+                    .search_messages(self.chat_id, &args.query, limit)
+                    .await?;
+                let messages: Vec<FoundMessage> = messages
+                    .into_iter()
+                    .map(|m| FoundMessage {
+                        id: m.id,
+                        sender_id: m.sender_id,
+                        content: m.modified_content.unwrap_or(m.content),
+                        created_at: m.created_at.to_rfc3339(),
+                    })
+                    .collect();
+                serde_json::to_string(&messages)
+            }
+            LIST_CHAT_MEMBERS => {
+                let chat = self
+                    .state
+                    .get_chat_by_id(self.chat_id, self.ws_id)
+                    .await?
+                    .ok_or_else(|| AppError::NotFound("chat not found".to_string()))?;
+                let users = self.state.fetch_chat_user_by_ids(&chat.members).await?;
+                serde_json::to_string(&users)
+            }
+            name => return Err(AppError::ToolCallError(format!("unknown tool {name}"))),
+        };
+        result.map_err(|e| AppError::ToolCallError(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use chat_core::ChatUser;
+
+    fn tool_call(name: &str, arguments: &str) -> ToolCall {
+        ToolCall {
+            id: "call_0".to_string(),
+            name: name.to_string(),
+            arguments: arguments.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn chat_tools_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let tools = ChatTools::new(state, 1, 1);
+
+        let enabled = vec![
+            SEARCH_MESSAGES.to_string(),
+            "unknown".to_string(),
+            LIST_CHAT_MEMBERS.to_string(),
+        ];
+        let definitions = tools.definitions(&enabled);
+        assert_eq!(definitions.len(), 2);
+
+        let result = tools.call(&tool_call(LIST_CHAT_MEMBERS, "")).await;
+        let users: Vec<ChatUser> = serde_json::from_str(&result)?;
+        assert_eq!(users.len(), 5);
+
+        let result = tools
+            .call(&tool_call(
+                SEARCH_MESSAGES,
+                r#"{"query": "fine", "limit": 3}"#,
+            ))
+            .await;
+        let messages: Vec<serde_json::Value> = serde_json::from_str(&result)?;
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0]["content"], "I am fine, thank you!");
+
+        let result = tools.call(&tool_call(SEARCH_MESSAGES, "{}")).await;
+        assert!(result.contains("error"));
+        Ok(())
+    }
+}
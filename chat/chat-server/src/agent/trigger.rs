@@ -0,0 +1,169 @@
+use chat_core::{AgentTrigger, ChatAgent, Message};
+use regex::Regex;
+use std::sync::LazyLock;
+
+/// `@name` at the start of the content or after a whitespace, so emails don't count
+static MENTION: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?:^|\s)@([\w-]+)").expect("mention regex should be valid"));
+
+/// names an agent can be mentioned by, the `name` of `@name` and `/name`
+static HANDLE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^[\w-]+$").expect("handle regex should be valid"));
+
+/// names mentioned as `@name` in the content, lowercased, in order
+pub fn parse_mentions(content: &str) -> Vec<String> {
+    MENTION
+        .captures_iter(content)
+        .map(|c| c[1].to_lowercase())
+        .collect()
+}
+
+/// the name of a `/name` prefix, lowercased
+fn slash_command(content: &str) -> Option<String> {
+    let command = content.trim_start().strip_prefix('/')?;
+    let name = command.split_whitespace().next()?;
+    Some(name.to_lowercase())
+}
+
+/// whether the message triggers the agent. `replied_sender` is the sender of the
+/// message it replies to, if any, `keyword` the compiled pattern of a keyword trigger
+pub fn agent_triggered(
+    agent: &ChatAgent,
+    message: &Message,
+    replied_sender: Option<i64>,
+    keyword: Option<&Regex>,
+) -> bool {
+    match &agent.trigger {
+        AgentTrigger::Always => true,
+        AgentTrigger::Mention => {
+            let name = agent.name.to_lowercase();
+            parse_mentions(&message.content).contains(&name)
+                || slash_command(&message.content).as_ref() == Some(&name)
+                || (replied_sender.is_some() && replied_sender == agent.bot_id)
+        }
+        AgentTrigger::Keyword { .. } => keyword.is_some_and(|re| re.is_match(&message.content)),
+        AgentTrigger::Schedule { from, to } => {
+            let time = message.created_at.time();
+            if from <= to {
+                *from <= time && time < *to
+            } else {
+                time >= *from || time < *to
+            }
+        }
+    }
+}
+
+/// check the keyword pattern compiles and an agent triggered by mentions can be
+/// mentioned by its name
+pub fn validate_trigger(trigger: &AgentTrigger, name: &str) -> Result<(), String> {
+    match trigger {
+        AgentTrigger::Keyword { pattern } => {
+            Regex::new(pattern).map_err(|e| format!("invalid keyword pattern: {e}"))?;
+        }
+        AgentTrigger::Mention if !HANDLE.is_match(name) => {
+            return Err(format!(
+                "agent {name} can't be mentioned, use letters, digits, _ and - in its name"
+            ));
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{NaiveTime, TimeZone, Utc};
+
+    fn chat_agent(trigger: AgentTrigger) -> ChatAgent {
+        ChatAgent {
+            id: 1,
+            chat_id: 1,
+            name: "Helper".to_string(),
+            r#type: Default::default(),
+            adapter: Default::default(),
+            model: "echo".to_string(),
+            prompt: "".to_string(),
+            user_prompt: "".to_string(),
+            args: Default::default(),
+            fallbacks: vec![],
+            priority: 0,
+            bot_id: Some(10),
+            trigger,
+            policy: vec![],
+            enabled: true,
+            rate_limit: None,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        }
+    }
+
+    fn message(content: &str, hour: u32) -> Message {
+        Message {
+            id: 1,
+            chat_id: 1,
+            sender_id: 1,
+            content: content.to_string(),
+            modified_content: None,
+            files: vec![],
+            reply_to: None,
+            hidden: false,
+            created_at: Utc.with_ymd_and_hms(2025, 3, 19, hour, 0, 0).unwrap(),
+        }
+    }
+
+    #[test]
+    fn parse_mentions_should_work() {
+        let mentions = parse_mentions("@helper can you ask @Some-Bot? mail nyh@gmail.com");
+        assert_eq!(mentions, ["helper", "some-bot"]);
+    }
+
+    #[test]
+    fn mention_trigger_should_work() {
+        let agent = chat_agent(AgentTrigger::Mention);
+        let triggered =
+            |content, replied| agent_triggered(&agent, &message(content, 9), replied, None);
+        assert!(triggered("hi @helper", None));
+        assert!(triggered("/helper summarize", None));
+        assert!(!triggered("helper?", None));
+        assert!(!triggered("hi @helpers", None));
+        // a reply to one of the agent's messages
+        assert!(triggered("thanks", Some(10)));
+        assert!(!triggered("thanks", Some(2)));
+
+        // only names which can be written after the @
+        assert!(validate_trigger(&AgentTrigger::Mention, "Some-Bot_2").is_ok());
+        assert!(validate_trigger(&AgentTrigger::Mention, "Support Bot").is_err());
+        assert!(validate_trigger(&AgentTrigger::Mention, "bot.v2").is_err());
+        assert!(validate_trigger(&AgentTrigger::Always, "Support Bot").is_ok());
+    }
+
+    #[test]
+    fn keyword_and_schedule_triggers_should_work() {
+        let trigger = AgentTrigger::Keyword {
+            pattern: r"(?i)\binvoice\b".to_string(),
+        };
+        assert!(validate_trigger(&trigger, "Helper").is_ok());
+        let agent = chat_agent(trigger);
+        let keyword = Regex::new(r"(?i)\binvoice\b").ok();
+        let triggered =
+            |content| agent_triggered(&agent, &message(content, 9), None, keyword.as_ref());
+        assert!(triggered("where is my Invoice?"));
+        assert!(!triggered("invoices"));
+        // a pattern which doesn't compile matches nothing
+        assert!(!agent_triggered(&agent, &message("invoice", 9), None, None));
+        let trigger = AgentTrigger::Keyword {
+            pattern: "(".to_string(),
+        };
+        assert!(validate_trigger(&trigger, "Helper").is_err());
+
+        // out of office, 18:00 to 08:00
+        let agent = chat_agent(AgentTrigger::Schedule {
+            from: NaiveTime::from_hms_opt(18, 0, 0).unwrap(),
+            to: NaiveTime::from_hms_opt(8, 0, 0).unwrap(),
+        });
+        assert!(agent_triggered(&agent, &message("hi", 22), None, None));
+        assert!(agent_triggered(&agent, &message("hi", 7), None, None));
+        assert!(!agent_triggered(&agent, &message("hi", 9), None, None));
+    }
+}
@@ -1,12 +1,28 @@
-use std::{env, fs::File, path::PathBuf};
+use std::{collections::HashMap, env, fmt, fs::File, path::PathBuf};
 
+use ai_sdk::{
+    get_ollama_host, get_openai_compat_api_key, get_openai_compat_base_url, known_dimensions,
+    EmbeddingAdapter, MockEmbedder, OllamaEmbedder, OpenAiCompatEmbedder, Usage,
+};
 use anyhow::{bail, Result};
+use chat_core::{AdapterType, AgentProvider};
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ChatConfig {
     pub server: ServerConfig,
     pub auth: AuthConfig,
+    /// prices keyed by model name, calls to models without a price cost nothing
+    #[serde(default)]
+    pub pricing: HashMap<String, ModelPrice>,
+    #[serde(default)]
+    pub agent: AgentConfig,
+    /// embedding model of the semantic search, which is disabled without one
+    #[serde(default)]
+    pub embedding: Option<EmbeddingConfig>,
+    /// servers and keys of the adapters, the env vars are only fallbacks
+    #[serde(default)]
+    pub providers: ProvidersConfig,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -22,6 +38,210 @@ pub struct AuthConfig {
     pub pk: String,
 }
 
+/// how much of the chat history agents get to see
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AgentConfig {
+    /// max number of previous messages
+    #[serde(default = "default_history_messages")]
+    pub history_messages: usize,
+    /// max estimated tokens of the previous messages, the newest are kept
+    #[serde(default = "default_history_tokens")]
+    pub history_tokens: usize,
+    /// background workers processing the agent jobs
+    #[serde(default = "default_workers")]
+    pub workers: usize,
+    /// attempts of a job before it is dead-lettered
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: i32,
+    /// how often idle workers look for new jobs, in milliseconds
+    #[serde(default = "default_poll_interval")]
+    pub poll_interval: u64,
+    /// how long the taps may hold a message back before it's stored without their
+    /// labels, in milliseconds
+    #[serde(default = "default_tap_timeout")]
+    pub tap_timeout: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ProvidersConfig {
+    /// `DEEPSEEK_KEY` unless set
+    #[serde(default)]
+    pub deepseek: ProviderConfig,
+    /// `OPENAI_COMPAT_BASE_URL` and `OPENAI_COMPAT_KEY` unless set
+    #[serde(default)]
+    pub openai_compat: ProviderConfig,
+    /// `OLLAMA_HOST` unless set
+    #[serde(default)]
+    pub ollama: ProviderConfig,
+    /// other servers, agents pick them by name. The API never takes urls or keys,
+    /// so members can't send the keys elsewhere
+    #[serde(default)]
+    pub servers: HashMap<String, ProviderConfig>,
+    /// allow agents on the mock adapter, for tests and local development only
+    #[serde(default)]
+    pub mock: bool,
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct ProviderConfig {
+    #[serde(default)]
+    pub base_url: Option<String>,
+    #[serde(default)]
+    pub api_key: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmbeddingConfig {
+    pub adapter: AdapterType,
+    pub model: String,
+    /// length of the vectors, known models don't need it
+    #[serde(default)]
+    pub dimensions: Option<usize>,
+    /// defaults to the one of the adapter in `providers`
+    #[serde(default)]
+    pub base_url: Option<String>,
+    /// defaults to the one of the adapter in `providers`
+    #[serde(default)]
+    pub api_key: Option<String>,
+}
+
+/// price per million tokens
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ModelPrice {
+    pub prompt: f64,
+    pub completion: f64,
+    /// price of prompt tokens served from the provider cache, defaults to `prompt`
+    #[serde(default)]
+    pub cache_hit: Option<f64>,
+}
+
+impl ModelPrice {
+    pub fn cost(&self, usage: &Usage) -> f64 {
+        let cache_hit = usage.cache_hit_tokens.min(usage.prompt_tokens);
+        let cache_miss = usage.prompt_tokens - cache_hit;
+        let tokens = cache_miss as f64 * self.prompt
+            + cache_hit as f64 * self.cache_hit.unwrap_or(self.prompt)
+            + usage.completion_tokens as f64 * self.completion;
+        tokens / 1_000_000.0
+    }
+}
+
+impl Default for AgentConfig {
+    fn default() -> Self {
+        Self {
+            history_messages: default_history_messages(),
+            history_tokens: default_history_tokens(),
+            workers: default_workers(),
+            max_attempts: default_max_attempts(),
+            poll_interval: default_poll_interval(),
+            tap_timeout: default_tap_timeout(),
+        }
+    }
+}
+
+fn default_history_messages() -> usize {
+    20
+}
+
+fn default_history_tokens() -> usize {
+    2000
+}
+
+fn default_workers() -> usize {
+    4
+}
+
+fn default_max_attempts() -> i32 {
+    3
+}
+
+fn default_poll_interval() -> u64 {
+    500
+}
+
+fn default_tap_timeout() -> u64 {
+    5000
+}
+
+impl ProvidersConfig {
+    /// the server and key of the provider: the ones of its named server, or of its
+    /// adapter if it doesn't name one. `None` if the named server isn't configured,
+    /// or for mocks unless they are allowed
+    pub fn resolve(&self, provider: &AgentProvider) -> Option<ProviderConfig> {
+        if let Some(name) = &provider.server {
+            return self.servers.get(name).cloned();
+        }
+        let config = match provider.adapter {
+            AdapterType::Deepseek => &self.deepseek,
+            AdapterType::OpenaiCompat => &self.openai_compat,
+            AdapterType::Ollama => &self.ollama,
+            AdapterType::Mock => return self.mock.then(ProviderConfig::default),
+        };
+        Some(config.clone())
+    }
+
+    /// check the servers the providers name are configured and mocks are allowed.
+    /// Mocks never read fixture files of the server, those are set up by tests
+    pub fn validate(&self, providers: &[AgentProvider]) -> Result<(), String> {
+        for provider in providers {
+            if provider.adapter == AdapterType::Mock && provider.model.starts_with("fixture:") {
+                return Err("mock fixtures can't be set for agents".to_string());
+            }
+            if self.resolve(provider).is_none() {
+                return Err(unavailable(provider));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// why the provider can't be resolved, see [`ProvidersConfig::resolve`]
+pub(crate) fn unavailable(provider: &AgentProvider) -> String {
+    match &provider.server {
+        Some(name) => format!("unknown server {name}, add it to providers.servers"),
+        None => "the mock adapter is disabled, set providers.mock".to_string(),
+    }
+}
+
+impl fmt::Debug for ProviderConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProviderConfig")
+            .field("base_url", &self.base_url)
+            .field("api_key", &self.api_key.as_ref().map(|_| "***"))
+            .finish()
+    }
+}
+
+impl EmbeddingConfig {
+    pub fn embedder(&self, providers: &ProvidersConfig) -> Result<EmbeddingAdapter> {
+        let Some(dimensions) = self.dimensions.or_else(|| known_dimensions(&self.model)) else {
+            bail!("dimensions of embedding model {} must be set", self.model);
+        };
+        let provider = AgentProvider::new(self.adapter.clone(), &self.model);
+        let defaults = providers.resolve(&provider).unwrap_or_default();
+        let base_url = self.base_url.clone().or(defaults.base_url);
+        let api_key = self.api_key.clone().or(defaults.api_key);
+        let embedder = match self.adapter {
+            AdapterType::OpenaiCompat => OpenAiCompatEmbedder::new(
+                base_url.unwrap_or_else(get_openai_compat_base_url),
+                api_key.or_else(get_openai_compat_api_key),
+                &self.model,
+                dimensions,
+            )
+            .into(),
+            AdapterType::Ollama => OllamaEmbedder::new(
+                base_url.unwrap_or_else(get_ollama_host),
+                &self.model,
+                dimensions,
+            )
+            .into(),
+            AdapterType::Mock => MockEmbedder::new(dimensions).into(),
+            AdapterType::Deepseek => bail!("deepseek has no embedding models"),
+        };
+        Ok(embedder)
+    }
+}
+
 impl ChatConfig {
     pub fn load() -> Result<Self> {
         // read from ./chat.yml or /etc/config/app.yml or from env CHAT_CONFIG
@@ -39,3 +259,80 @@ impl ChatConfig {
         Ok(ret?)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn model_price_cost_should_work() {
+        let price = ModelPrice {
+            prompt: 0.27,
+            completion: 1.1,
+            cache_hit: Some(0.07),
+        };
+        let usage = Usage {
+            prompt_tokens: 1_000_000,
+            completion_tokens: 500_000,
+            cache_hit_tokens: 400_000,
+        };
+        let cost = price.cost(&usage);
+        assert!((cost - (0.6 * 0.27 + 0.4 * 0.07 + 0.5 * 1.1)).abs() < 1e-9);
+
+        let price = ModelPrice {
+            cache_hit: None,
+            ..price
+        };
+        assert!((price.cost(&usage) - (0.27 + 0.55)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn providers_config_should_resolve_provider() {
+        let config: ProvidersConfig = serde_yaml::from_str(
+            r#"
+ollama:
+  base_url: http://ollama:11434
+openai_compat:
+  api_key: sk-test
+servers:
+  vllm:
+    base_url: http://vllm:8000/v1
+    api_key: sk-vllm
+"#,
+        )
+        .unwrap();
+        let provider = AgentProvider::new(AdapterType::Ollama, "llama3.2");
+        let resolved = config.resolve(&provider).unwrap();
+        assert_eq!(resolved.base_url.as_deref(), Some("http://ollama:11434"));
+
+        // a named server comes with its own key only
+        let provider = AgentProvider::new(AdapterType::OpenaiCompat, "gpt-4o-mini");
+        let resolved = config
+            .resolve(&provider.clone().with_server("vllm"))
+            .unwrap();
+        assert_eq!(resolved.base_url.as_deref(), Some("http://vllm:8000/v1"));
+        assert_eq!(resolved.api_key.as_deref(), Some("sk-vllm"));
+        assert!(!format!("{config:?}").contains("sk-"));
+
+        let unknown = provider.with_server("elsewhere");
+        assert!(config.resolve(&unknown).is_none());
+        let err = config.validate(&[unknown]).unwrap_err();
+        assert!(err.contains("unknown server elsewhere"));
+
+        let provider = AgentProvider::new(AdapterType::Deepseek, "deepseek-chat");
+        let resolved = config.resolve(&provider).unwrap();
+        assert_eq!(resolved.base_url, None);
+        assert_eq!(resolved.api_key, None);
+        // mocks are off unless allowed, and never read fixture files
+        let mock = AgentProvider::new(AdapterType::Mock, "echo");
+        let err = config.validate(std::slice::from_ref(&mock)).unwrap_err();
+        assert!(err.contains("mock adapter is disabled"));
+        let config = ProvidersConfig {
+            mock: true,
+            ..config
+        };
+        assert!(config.validate(&[mock]).is_ok());
+        let fixture = AgentProvider::new(AdapterType::Mock, "fixture:/etc/passwd");
+        assert!(config.validate(&[fixture]).is_err());
+    }
+}
@@ -37,9 +37,24 @@ pub enum AppError {
     #[error("user {user_id} is not member of chat {chat_id}")]
     NotChatMemberError { user_id: u64, chat_id: u64 },
 
+    #[error("user {user_id} is not owner of workspace {ws_id}")]
+    NotWorkspaceOwnerError { user_id: u64, ws_id: u64 },
+
+    #[error("quota exceeded: {0}")]
+    QuotaExceeded(String),
+
     #[error("create message error: {0}")]
     CreateMessageError(String),
 
+    #[error("message rejected by agent {agent}: labelled {label}")]
+    MessageRejected { agent: String, label: String },
+
+    #[error("search error: {0}")]
+    SearchError(String),
+
+    #[error("vector store error: {0}")]
+    VectorStoreError(String),
+
     #[error("IO error: {0}")]
     IoError(#[from] std::io::Error),
 
@@ -60,6 +75,22 @@ pub enum AppError {
 
     #[error("agent error: {0}")]
     AiAgentError(#[from] AgentError),
+
+    #[error("tool call error: {0}")]
+    ToolCallError(String),
+}
+
+impl AppError {
+    /// a failure which may not happen again when the job is retried: the database
+    /// or the provider was unavailable, or the provider rate limited the call
+    pub fn is_transient(&self) -> bool {
+        match self {
+            AppError::SqlxError(_) => true,
+            AppError::AiAgentError(AgentError::Network(_)) => true,
+            AppError::AiAgentError(AgentError::AnyError(e)) => ai_sdk::is_transient(e),
+            _ => false,
+        }
+    }
 }
 
 impl ErrorOutput {
@@ -81,7 +112,12 @@ impl IntoResponse for AppError {
             AppError::UpdateChatError(_) => StatusCode::BAD_REQUEST,
             AppError::ChatFileError(_) => StatusCode::BAD_REQUEST,
             AppError::CreateMessageError(_) => StatusCode::BAD_REQUEST,
+            AppError::MessageRejected { .. } => StatusCode::UNPROCESSABLE_ENTITY,
             AppError::NotChatMemberError { .. } => StatusCode::FORBIDDEN,
+            AppError::NotWorkspaceOwnerError { .. } => StatusCode::FORBIDDEN,
+            AppError::QuotaExceeded(_) => StatusCode::TOO_MANY_REQUESTS,
+            AppError::SearchError(_) => StatusCode::BAD_REQUEST,
+            AppError::VectorStoreError(_) => StatusCode::INTERNAL_SERVER_ERROR,
             AppError::NotFound(_) => StatusCode::NOT_FOUND,
             AppError::SqlxError(_) => StatusCode::INTERNAL_SERVER_ERROR,
             AppError::PasswordHashError(_) => StatusCode::UNPROCESSABLE_ENTITY,
@@ -89,6 +125,7 @@ impl IntoResponse for AppError {
             AppError::HttpHeaderError(_) => StatusCode::UNPROCESSABLE_ENTITY,
             AppError::IoError(_) => StatusCode::INTERNAL_SERVER_ERROR,
             AppError::AiAgentError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::ToolCallError(_) => StatusCode::INTERNAL_SERVER_ERROR,
         };
 
         (status, Json(ErrorOutput::new(self.to_string()))).into_response()
@@ -1,18 +1,22 @@
 use crate::{
-    models::{CreateAgent, UpdateAgent},
+    models::{AgentMetrics, AgentRun, CreateAgent, ListRuns, RunReplay, TestAgent, UpdateAgent},
     AppError, AppState,
 };
 use axum::{
-    extract::{Path, State},
+    extract::{Path, Query, State},
     http::StatusCode,
     response::IntoResponse,
-    Json,
+    Extension, Json,
 };
+use chat_core::{AgentDecision, ChatAgent, User};
 
 /// List all agents in the chat.
 #[utoipa::path(
     get,
-    path = "/api/chats/{id}/agents",
+    path = "/api/chats/{id}/agent",
+    responses(
+        (status = 200, description = "agents of the chat in pipeline order", body = Vec<ChatAgent>)
+    ),
     params(
         ("id" = u64, Path, description = "Chat id")
     ),
@@ -31,7 +35,11 @@ pub(crate) async fn list_agent_handler(
 /// Create a new agent in the chat.
 #[utoipa::path(
     post,
-    path = "/api/chats/{id}/agents",
+    path = "/api/chats/{id}/agent",
+    request_body = CreateAgent,
+    responses(
+        (status = 201, description = "agent created", body = ChatAgent)
+    ),
     params(
         ("id" = u64, Path, description = "Chat id")
     ),
@@ -48,13 +56,16 @@ pub(crate) async fn create_agent_handler(
     Ok((StatusCode::CREATED, Json(agent)))
 }
 
-/// Update the agent by id.
+/// Update the agent by the id in the body.
 #[utoipa::path(
     patch,
-    path = "/api/chats/{id}/agents/{agent_id}",
+    path = "/api/chats/{id}/agent",
+    request_body = UpdateAgent,
+    responses(
+        (status = 200, description = "agent updated", body = ChatAgent)
+    ),
     params(
-        ("id" = u64, Path, description = "Chat id"),
-        ("agent_id" = u64, Path, description = "Agent id")
+        ("id" = u64, Path, description = "Chat id")
     ),
     security(
         ("token" = [])
@@ -68,3 +79,201 @@ pub(crate) async fn update_agent_handler(
     let agent = state.update_agent(input, id as _).await?;
     Ok((StatusCode::OK, Json(agent)))
 }
+
+/// Get the agent by id.
+#[utoipa::path(
+    get,
+    path = "/api/chats/{id}/agent/{agent_id}",
+    responses(
+        (status = 200, description = "get agent", body = ChatAgent)
+    ),
+    params(
+        ("id" = u64, Path, description = "Chat id"),
+        ("agent_id" = u64, Path, description = "Agent id")
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn get_agent_handler(
+    Path((id, agent_id)): Path<(u64, u64)>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    match state.get_agent(id, agent_id).await? {
+        Some(agent) => Ok(Json(agent)),
+        None => Err(AppError::NotFound(format!("agent {agent_id}"))),
+    }
+}
+
+/// Delete the agent by id.
+#[utoipa::path(
+    delete,
+    path = "/api/chats/{id}/agent/{agent_id}",
+    responses(
+        (status = 204, description = "agent deleted")
+    ),
+    params(
+        ("id" = u64, Path, description = "Chat id"),
+        ("agent_id" = u64, Path, description = "Agent id")
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn delete_agent_handler(
+    Path((id, agent_id)): Path<(u64, u64)>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    state.delete_agent(id, agent_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Run the agent on sample content and return its decision, only its usage is stored.
+#[utoipa::path(
+    post,
+    path = "/api/chats/{id}/agent/{agent_id}/test",
+    request_body = TestAgent,
+    responses(
+        (status = 200, description = "what the agent would do", body = AgentDecision)
+    ),
+    params(
+        ("id" = u64, Path, description = "Chat id"),
+        ("agent_id" = u64, Path, description = "Agent id")
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn test_agent_handler(
+    Extension(user): Extension<User>,
+    Path((id, agent_id)): Path<(u64, u64)>,
+    State(state): State<AppState>,
+    Json(input): Json<TestAgent>,
+) -> Result<impl IntoResponse, AppError> {
+    let decision = state.test_agent(input, id, agent_id, &user).await?;
+    Ok((StatusCode::OK, Json(decision)))
+}
+
+/// Call metrics and circuit breaker state of the agents in the chat.
+#[utoipa::path(
+    get,
+    path = "/api/chats/{id}/agent/metrics",
+    responses(
+        (status = 200, description = "metrics of the agents", body = Vec<AgentMetrics>)
+    ),
+    params(
+        ("id" = u64, Path, description = "Chat id")
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn list_agent_metrics_handler(
+    Path(id): Path<u64>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let metrics = state.list_agent_metrics(id).await?;
+    Ok((StatusCode::OK, Json(metrics)))
+}
+
+/// Model calls of the agents in the chat, newest first.
+#[utoipa::path(
+    get,
+    path = "/api/chats/{id}/agent/runs",
+    responses(
+        (status = 200, description = "runs of the agents", body = Vec<AgentRun>)
+    ),
+    params(
+        ("id" = u64, Path, description = "Chat id"),
+        ListRuns
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn list_agent_runs_handler(
+    Path(id): Path<u64>,
+    Query(input): Query<ListRuns>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let runs = state.list_agent_runs(id, input).await?;
+    Ok((StatusCode::OK, Json(runs)))
+}
+
+/// Model calls of the agent, newest first.
+#[utoipa::path(
+    get,
+    path = "/api/chats/{id}/agent/{agent_id}/runs",
+    responses(
+        (status = 200, description = "runs of the agent", body = Vec<AgentRun>)
+    ),
+    params(
+        ("id" = u64, Path, description = "Chat id"),
+        ("agent_id" = u64, Path, description = "Agent id"),
+        ("last_id" = Option<u64>, Query, description = "Runs with a smaller id, for paging")
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn list_runs_of_agent_handler(
+    Path((id, agent_id)): Path<(u64, u64)>,
+    Query(input): Query<ListRuns>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let input = ListRuns {
+        agent_id: Some(agent_id),
+        ..input
+    };
+    let runs = state.list_agent_runs(id, input).await?;
+    Ok((StatusCode::OK, Json(runs)))
+}
+
+/// Get an agent run by id.
+#[utoipa::path(
+    get,
+    path = "/api/chats/{id}/agent/runs/{run_id}",
+    responses(
+        (status = 200, description = "get agent run", body = AgentRun)
+    ),
+    params(
+        ("id" = u64, Path, description = "Chat id"),
+        ("run_id" = u64, Path, description = "Run id")
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn get_agent_run_handler(
+    Path((id, run_id)): Path<(u64, u64)>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    match state.get_agent_run(id, run_id).await? {
+        Some(run) => Ok(Json(run)),
+        None => Err(AppError::NotFound(format!("agent run {run_id}"))),
+    }
+}
+
+/// Run the input of a past run against the agent's current config, to compare both.
+#[utoipa::path(
+    post,
+    path = "/api/chats/{id}/agent/runs/{run_id}/replay",
+    responses(
+        (status = 200, description = "the original run and its replay", body = RunReplay)
+    ),
+    params(
+        ("id" = u64, Path, description = "Chat id"),
+        ("run_id" = u64, Path, description = "Run id")
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn replay_agent_run_handler(
+    Extension(user): Extension<User>,
+    Path((id, run_id)): Path<(u64, u64)>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let replay = state.replay_agent_run(id, run_id, user.ws_id as _).await?;
+    Ok((StatusCode::OK, Json(replay)))
+}
@@ -0,0 +1,80 @@
+use crate::{
+    models::{IngestFile, KnowledgeFile, ListKnowledgeFiles},
+    AppError, AppState,
+};
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+use chat_core::User;
+
+/// Queue an uploaded file for ingestion into the workspace's knowledge base.
+#[utoipa::path(
+    post,
+    path = "/api/knowledge/files",
+    request_body = IngestFile,
+    responses(
+        (status = 202, description = "file queued, or already in the collection", body = KnowledgeFile)
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn ingest_file_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<IngestFile>,
+) -> Result<impl IntoResponse, AppError> {
+    let file = state.ingest_file(input, user.ws_id as _).await?;
+    Ok((StatusCode::ACCEPTED, Json(file)))
+}
+
+/// Files of the workspace's knowledge base with their ingestion status, newest first.
+#[utoipa::path(
+    get,
+    path = "/api/knowledge/files",
+    responses(
+        (status = 200, description = "files of the knowledge base", body = Vec<KnowledgeFile>)
+    ),
+    params(
+        ListKnowledgeFiles
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn list_knowledge_files_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Query(input): Query<ListKnowledgeFiles>,
+) -> Result<impl IntoResponse, AppError> {
+    let files = state.list_knowledge_files(user.ws_id as _, input).await?;
+    Ok(Json(files))
+}
+
+/// Get a file of the knowledge base and its ingestion status.
+#[utoipa::path(
+    get,
+    path = "/api/knowledge/files/{id}",
+    responses(
+        (status = 200, description = "get knowledge file", body = KnowledgeFile)
+    ),
+    params(
+        ("id" = u64, Path, description = "Knowledge file id")
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn get_knowledge_file_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    match state.get_knowledge_file(user.ws_id as _, id).await? {
+        Some(file) => Ok(Json(file)),
+        None => Err(AppError::NotFound(format!("knowledge file {id}"))),
+    }
+}
@@ -1,5 +1,5 @@
 use crate::{
-    models::{ChatFile, CreateMessage, DeleteMessage, ListMessage},
+    models::{ChatFile, CreateMessage, DeleteMessage, ListMessage, MessageHit, SearchMessages},
     AppError, AppState,
 };
 use axum::{
@@ -83,6 +83,31 @@ pub(crate) async fn list_message_handler(
     Ok(Json(messages))
 }
 
+/// Search the messages of the chat, by text or by meaning with `mode=semantic`.
+#[utoipa::path(
+    get,
+    path = "/api/chats/{id}/search",
+    responses(
+        (status = 200, description = "matching messages, best first", body = Vec<MessageHit>),
+    ),
+    params(
+        ("id" = u64, Path, description = "chat id"),
+        SearchMessages,
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn search_message_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    Query(input): Query<SearchMessages>,
+) -> Result<impl IntoResponse, AppError> {
+    let hits = state.search_chat_messages(id, user.id as _, input).await?;
+    Ok(Json(hits))
+}
+
 #[utoipa::path(
     get,
     path = "/api/files/{ws_id}/{*path}",
@@ -1,14 +1,18 @@
 mod agent;
 mod auth;
 mod chat;
+mod knowledge;
 mod message;
+mod usage;
 mod workspace;
 
 pub(crate) use agent::*;
 pub(crate) use auth::*;
 use axum::response::IntoResponse;
 pub(crate) use chat::*;
+pub(crate) use knowledge::*;
 pub(crate) use message::*;
+pub(crate) use usage::*;
 pub(crate) use workspace::*;
 
 pub(crate) async fn index_handler() -> impl IntoResponse {
@@ -0,0 +1,155 @@
+use crate::{
+    models::{
+        AgentJob, LabelledMessage, ListJobs, ListLabels, QuotaState, UsageQuery, UsageReport,
+        WorkspaceBudget,
+    },
+    AppError, AppState, ErrorOutput,
+};
+use axum::{
+    extract::{Path, Query, State},
+    response::IntoResponse,
+    Extension, Json,
+};
+use chat_core::User;
+
+/// Token usage and cost of the workspace's agents over a time range.
+#[utoipa::path(
+    get,
+    path = "/api/usage",
+    responses(
+        (status = 200, description = "usage report", body = UsageReport),
+    ),
+    params(
+        UsageQuery,
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn get_usage_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Query(input): Query<UsageQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    let report = state.get_usage_report(user.ws_id as _, input).await?;
+    Ok(Json(report))
+}
+
+/// Monthly budget of the workspace and how much of it is used.
+#[utoipa::path(
+    get,
+    path = "/api/budget",
+    responses(
+        (status = 200, description = "quota state", body = QuotaState),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn get_budget_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let quota = state.get_quota_state(user.ws_id as _).await?;
+    Ok(Json(quota))
+}
+
+/// Set the monthly budget and agent rate limit of the workspace, owner only.
+#[utoipa::path(
+    put,
+    path = "/api/budget",
+    request_body = WorkspaceBudget,
+    responses(
+        (status = 200, description = "updated budget", body = WorkspaceBudget),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn set_budget_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<WorkspaceBudget>,
+) -> Result<impl IntoResponse, AppError> {
+    let budget = state
+        .set_workspace_budget(user.ws_id as _, user.id as _, input)
+        .await?;
+    Ok(Json(budget))
+}
+
+/// Agent jobs of the workspace, dead-lettered ones by default. Owner only.
+#[utoipa::path(
+    get,
+    path = "/api/admin/jobs",
+    responses(
+        (status = 200, description = "agent jobs", body = Vec<AgentJob>),
+    ),
+    params(
+        ListJobs,
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn list_agent_jobs_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Query(input): Query<ListJobs>,
+) -> Result<impl IntoResponse, AppError> {
+    let jobs = state
+        .list_agent_jobs(user.ws_id as _, user.id as _, input)
+        .await?;
+    Ok(Json(jobs))
+}
+
+/// Queue a dead agent job again. Owner only.
+#[utoipa::path(
+    post,
+    path = "/api/admin/jobs/{id}/retry",
+    params(
+        ("id" = u64, Path, description = "Job id")
+    ),
+    responses(
+        (status = 200, description = "queued job", body = AgentJob),
+        (status = 404, description = "Dead job not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn retry_agent_job_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    let job = state
+        .retry_agent_job(user.ws_id as _, user.id as _, id)
+        .await?;
+    Ok(Json(job))
+}
+
+/// Messages labelled by the tap agents of the workspace, flagged ones by default.
+/// Owner only.
+#[utoipa::path(
+    get,
+    path = "/api/admin/labels",
+    responses(
+        (status = 200, description = "labelled messages", body = Vec<LabelledMessage>),
+    ),
+    params(
+        ListLabels,
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn list_labelled_messages_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Query(input): Query<ListLabels>,
+) -> Result<impl IntoResponse, AppError> {
+    let messages = state
+        .list_labelled_messages(user.ws_id as _, user.id as _, input)
+        .await?;
+    Ok(Json(messages))
+}
@@ -6,6 +6,7 @@ mod middlewares;
 mod models;
 mod openapi;
 
+use ai_sdk::{EmbeddingAdapter, Resilience};
 use anyhow::Context;
 use axum::{
     http::Method,
@@ -14,14 +15,22 @@ use axum::{
     Router,
 };
 use chat_core::{set_layer, verify_token, DecodingKey, EncodingKey, TokenVerify};
-pub use config::ChatConfig;
+pub use config::{ChatConfig, ProviderConfig, ProvidersConfig};
 pub use error::{AppError, ErrorOutput};
 use handlers::*;
 use middlewares::verify_chat;
+use models::open_vector_store;
 pub use models::ParamChat;
 use openapi::OpenApiRouter;
+use regex::Regex;
 use sqlx::PgPool;
-use std::{fmt::Debug, ops::Deref, sync::Arc};
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    ops::Deref,
+    sync::{Arc, Mutex},
+};
+use swiftide_pgvector::PgVector;
 use tokio::fs;
 use tower_http::cors;
 
@@ -44,6 +53,14 @@ pub struct AppStateInner {
     pub(crate) dk: DecodingKey,
     pub(crate) ek: EncodingKey,
     pub(crate) pool: PgPool,
+    /// circuit breaker state and metrics of the agents, keyed by agent id and provider index
+    pub(crate) agent_resilience: Mutex<HashMap<(i64, usize), Arc<Resilience>>>,
+    /// compiled keyword patterns of the agents, keyed by agent id
+    pub(crate) agent_keywords: Mutex<HashMap<i64, Regex>>,
+    /// embedder of the semantic search, see `ChatConfig::embedding`
+    pub(crate) embedder: Option<EmbeddingAdapter>,
+    /// vectors of the messages and knowledge chunks, there is one with the embedder
+    pub(crate) vector_store: Option<PgVector>,
 }
 
 /// Get the router for the chat application
@@ -67,16 +84,49 @@ pub async fn get_router(state: AppState) -> Result<Router, AppError> {
                 .post(create_agent_handler)
                 .patch(update_agent_handler),
         )
+        .route("/{id}/search", get(search_message_handler))
+        .route("/{id}/agent/metrics", get(list_agent_metrics_handler))
+        .route(
+            "/{id}/agent/{agent_id}",
+            get(get_agent_handler).delete(delete_agent_handler),
+        )
+        .route("/{id}/agent/{agent_id}/test", post(test_agent_handler))
+        .route(
+            "/{id}/agent/{agent_id}/runs",
+            get(list_runs_of_agent_handler),
+        )
+        .route("/{id}/agent/runs", get(list_agent_runs_handler))
+        .route("/{id}/agent/runs/{run_id}", get(get_agent_run_handler))
+        .route(
+            "/{id}/agent/runs/{run_id}/replay",
+            post(replay_agent_run_handler),
+        )
         .layer(from_fn_with_state(state.clone(), verify_chat))
         .route("/", get(list_chat_handler).post(create_chat_handler));
 
     let cors = cors::CorsLayer::new()
-        .allow_methods([Method::GET, Method::POST, Method::PATCH, Method::DELETE])
+        .allow_methods([
+            Method::GET,
+            Method::POST,
+            Method::PUT,
+            Method::PATCH,
+            Method::DELETE,
+        ])
         .allow_origin(cors::Any)
         .allow_headers(cors::Any);
 
     let api = Router::new()
         .route("/users", get(list_chat_user_handler))
+        .route("/usage", get(get_usage_handler))
+        .route("/budget", get(get_budget_handler).put(set_budget_handler))
+        .route("/admin/jobs", get(list_agent_jobs_handler))
+        .route("/admin/jobs/{id}/retry", post(retry_agent_job_handler))
+        .route("/admin/labels", get(list_labelled_messages_handler))
+        .route(
+            "/knowledge/files",
+            get(list_knowledge_files_handler).post(ingest_file_handler),
+        )
+        .route("/knowledge/files/{id}", get(get_knowledge_file_handler))
         .nest("/chats", chat)
         .route("/upload", post(upload_handler))
         .route("/files/{ws_id}/{*path}", get(file_handler))
@@ -112,12 +162,23 @@ impl AppState {
         let pool = PgPool::connect(&config.server.db_url)
             .await
             .context("connect to db failed")?;
+        let embedder = config
+            .embedding
+            .as_ref()
+            .map(|c| c.embedder(&config.providers))
+            .transpose()
+            .context("load embedder failed")?;
+        let vector_store = open_vector_store(&pool, embedder.as_ref()).await?;
         Ok(Self {
             inner: Arc::new(AppStateInner {
                 config,
                 ek,
                 dk,
                 pool,
+                agent_resilience: Default::default(),
+                agent_keywords: Default::default(),
+                embedder,
+                vector_store,
             }),
         })
     }
@@ -145,12 +206,23 @@ mod test_util {
             let post = config.server.db_url.rfind('/').expect("invalid db_url");
             let server_url = &config.server.db_url[..post];
             let (tdb, pool) = get_test_pool(Some(server_url)).await;
+            let embedder = config
+                .embedding
+                .as_ref()
+                .map(|c| c.embedder(&config.providers))
+                .transpose()
+                .context("load embedder failed")?;
+            let vector_store = open_vector_store(&pool, embedder.as_ref()).await?;
             let state = Self {
                 inner: Arc::new(AppStateInner {
                     config,
                     ek,
                     dk,
                     pool,
+                    agent_resilience: Default::default(),
+                    agent_keywords: Default::default(),
+                    embedder,
+                    vector_store,
                 }),
             };
             Ok((tdb, state))
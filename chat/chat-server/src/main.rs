@@ -12,6 +12,9 @@ async fn main() -> Result<()> {
     let config = ChatConfig::load()?;
     let addr = format!("0.0.0.0:{}", config.server.port);
     let state = AppState::try_new(config).await?;
+    state.spawn_agent_workers();
+    state.spawn_message_indexer().await?;
+    state.spawn_file_ingester();
     let app = get_router(state).await?;
 
     let listener = TcpListener::bind(&addr).await?;
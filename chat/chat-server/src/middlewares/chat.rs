@@ -5,10 +5,17 @@ use axum::{
     response::{IntoResponse, Response},
 };
 use chat_core::User;
+use serde::Deserialize;
+
+/// the chat id of the routes nested under `/chats/{id}`
+#[derive(Deserialize)]
+struct ChatPath {
+    id: u64,
+}
 
 pub async fn verify_chat(State(state): State<AppState>, req: Request, next: Next) -> Response {
     let (mut parts, body) = req.into_parts();
-    let Path(chat_id) = Path::<u64>::from_request_parts(&mut parts, &state)
+    let Path(ChatPath { id: chat_id }) = Path::<ChatPath>::from_request_parts(&mut parts, &state)
         .await
         .unwrap();
     let user = parts.extensions.get::<User>().unwrap();
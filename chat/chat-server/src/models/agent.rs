@@ -1,6 +1,17 @@
-use crate::{AppError, AppState};
-use chat_core::{AdapterType, AgentType, ChatAgent};
-use serde::{Deserialize, Serialize};
+use crate::{
+    agent::{agent_triggered, validate_args, validate_template, validate_trigger, AgentVariant},
+    AppError, AppState,
+};
+use ai_sdk::{Resilience, ResilienceConfig, ResilienceMetrics};
+use chat_core::{
+    AdapterType, AgentArgs, AgentDecision, AgentError, AgentProvider, AgentTrigger, AgentType,
+    ChatAgent, ChatUser, LabelRule, Message, User,
+};
+use chrono::Utc;
+use regex::Regex;
+use serde::{Deserialize, Deserializer, Serialize};
+use sqlx::types::Json;
+use std::sync::Arc;
 use tracing::info;
 use utoipa::ToSchema;
 
@@ -11,13 +22,34 @@ pub struct CreateAgent {
     pub model: String,
     pub name: String,
     pub r#type: AgentType,
+    /// template of the system prompt, see `ChatAgent::prompt`
     pub prompt: String,
-    #[serde(default = "default_map")]
-    pub args: serde_json::Value,
+    /// template of the user turn, the message itself when empty
+    #[serde(default)]
+    pub user_prompt: String,
+    #[serde(default)]
+    pub args: AgentArgs,
+    /// providers tried in order when the primary adapter/model fails
+    #[serde(default)]
+    pub fallbacks: Vec<AgentProvider>,
+    /// position in the chat's agent pipeline, lower runs first
+    #[serde(default)]
+    pub priority: i32,
+    /// which messages the agent is evaluated on, every message by default
+    #[serde(default)]
+    pub trigger: AgentTrigger,
+    /// actions taken on the labels of a tap agent
+    #[serde(default)]
+    pub policy: Vec<LabelRule>,
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// max calls per minute, failed ones included, unlimited by default
+    #[serde(default)]
+    pub rate_limit: Option<i32>,
 }
 
-fn default_map() -> serde_json::Value {
-    serde_json::Value::Object(serde_json::Map::new())
+fn default_true() -> bool {
+    true
 }
 
 #[derive(Debug, Clone, Default, ToSchema, Serialize, Deserialize)]
@@ -26,8 +58,61 @@ pub struct UpdateAgent {
     pub id: u64,
     #[serde(default)]
     pub prompt: String,
+    /// replaces the user prompt when set, empty to send the message as is
+    #[serde(default)]
+    pub user_prompt: Option<String>,
+    /// replaces the args when set
+    #[serde(default)]
+    pub args: Option<AgentArgs>,
+    /// replaces the fallback providers when set
     #[serde(default)]
-    pub args: serde_json::Value,
+    pub fallbacks: Option<Vec<AgentProvider>>,
+    #[serde(default)]
+    pub priority: Option<i32>,
+    #[serde(default)]
+    pub trigger: Option<AgentTrigger>,
+    /// replaces the label policy when set
+    #[serde(default)]
+    pub policy: Option<Vec<LabelRule>>,
+    /// pauses or resumes the agent when set
+    #[serde(default)]
+    pub enabled: Option<bool>,
+    /// replaces the rate limit when set, `null` removes it
+    #[serde(default, deserialize_with = "double_option")]
+    #[schema(value_type = Option<i32>)]
+    pub rate_limit: Option<Option<i32>>,
+}
+
+/// sample input for a dry run of an agent
+#[derive(Debug, Clone, Default, ToSchema, Serialize, Deserialize)]
+pub struct TestAgent {
+    pub content: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct AgentMetrics {
+    pub agent_id: i64,
+    pub name: String,
+    /// in the order they are tried
+    #[schema(value_type = Vec<Object>)]
+    pub providers: Vec<ProviderMetrics>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProviderMetrics {
+    #[serde(flatten)]
+    pub provider: AgentProvider,
+    #[serde(flatten)]
+    pub metrics: ResilienceMetrics,
+}
+
+/// tells a `null` field (`Some(None)`) from a missing one (`None`)
+fn double_option<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::deserialize(deserializer).map(Some)
 }
 
 impl CreateAgent {
@@ -37,7 +122,7 @@ impl CreateAgent {
         name: impl Into<String>,
         r#type: AgentType,
         prompt: impl Into<String>,
-        args: impl Serialize,
+        args: AgentArgs,
     ) -> Self {
         Self {
             adapter,
@@ -45,17 +130,31 @@ impl CreateAgent {
             name: name.into(),
             r#type,
             prompt: prompt.into(),
-            args: serde_json::to_value(args).unwrap(),
+            user_prompt: String::new(),
+            args,
+            fallbacks: vec![],
+            priority: 0,
+            trigger: AgentTrigger::Always,
+            policy: vec![],
+            enabled: true,
+            rate_limit: None,
         }
     }
 }
 
 impl UpdateAgent {
-    pub fn new(id: u64, prompt: impl Into<String>, args: impl Serialize) -> Self {
+    pub fn new(id: u64, prompt: impl Into<String>, args: AgentArgs) -> Self {
         Self {
             id,
             prompt: prompt.into(),
-            args: serde_json::to_value(args).unwrap(),
+            user_prompt: None,
+            args: Some(args),
+            fallbacks: None,
+            priority: None,
+            trigger: None,
+            policy: None,
+            enabled: None,
+            rate_limit: None,
         }
     }
 }
@@ -77,11 +176,28 @@ impl AppState {
             )));
         }
 
-        // TODO: check if model is supported by adapter
-        let agent = sqlx::query_as(
+        if input.r#type == AgentType::Knowledge && self.embedder.is_none() {
+            return Err(AppError::CreateAgentError(
+                "knowledge agents need an embedding model".to_string(),
+            ));
+        }
+        validate_trigger(&input.trigger, &input.name).map_err(AppError::CreateAgentError)?;
+        validate_template(&input.prompt).map_err(AppError::CreateAgentError)?;
+        validate_template(&input.user_prompt).map_err(AppError::CreateAgentError)?;
+        let providers = std::iter::once(AgentProvider::new(input.adapter.clone(), &input.model))
+            .chain(input.fallbacks.iter().cloned())
+            .collect::<Vec<_>>();
+        validate_args(&providers, &input.args).map_err(AppError::CreateAgentError)?;
+        self.config
+            .providers
+            .validate(&providers)
+            .map_err(AppError::CreateAgentError)?;
+
+        let mut tx = self.pool.begin().await?;
+        let agent: ChatAgent = sqlx::query_as(
             r#"
-            INSERT INTO chat_agents (chat_id, name, type, adapter, model, prompt, args)
-            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            INSERT INTO chat_agents (chat_id, name, type, adapter, model, prompt, args, fallbacks, priority, trigger, policy, enabled, user_prompt, rate_limit)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14)
             RETURNING *
             "#,
         )
@@ -91,10 +207,37 @@ impl AppState {
         .bind(input.adapter)
         .bind(input.model)
         .bind(input.prompt)
-        .bind(input.args)
-        .fetch_one(&self.pool)
+        .bind(Json(input.args))
+        .bind(Json(input.fallbacks))
+        .bind(input.priority)
+        .bind(Json(input.trigger))
+        .bind(Json(input.policy))
+        .bind(input.enabled)
+        .bind(input.user_prompt)
+        .bind(input.rate_limit)
+        .fetch_one(&mut *tx)
         .await?;
 
+        // the bot user replies on behalf of the agent, in the workspace of the chat
+        let fullname: String = agent.name.chars().take(64).collect();
+        let agent = sqlx::query_as(
+            r#"
+            WITH bot AS (
+                INSERT INTO users (ws_id, fullname, email, password_hash, is_bot)
+                SELECT ws_id, $1, $2, '', true FROM chats WHERE id = $3
+                RETURNING id
+            )
+            UPDATE chat_agents SET bot_id = (SELECT id FROM bot) WHERE id = $4 RETURNING *
+            "#,
+        )
+        .bind(fullname)
+        .bind(format!("agent-{}@bot.chatapp", agent.id))
+        .bind(chat_id as i64)
+        .bind(agent.id)
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
         Ok(agent)
     }
 
@@ -128,11 +271,11 @@ impl AppState {
         Ok(exists)
     }
 
-    /// List all agents in a chat
+    /// List all agents in a chat, in pipeline order
     pub async fn list_agents(&self, chat_id: u64) -> Result<Vec<ChatAgent>, AppError> {
         let agents = sqlx::query_as(
             r#"
-            SELECT * FROM chat_agents WHERE chat_id = $1 ORDER BY id ASC
+            SELECT * FROM chat_agents WHERE chat_id = $1 ORDER BY priority ASC, id ASC
             "#,
         )
         .bind(chat_id as i64)
@@ -142,6 +285,131 @@ impl AppState {
         Ok(agents)
     }
 
+    /// get an agent of a chat by id
+    pub async fn get_agent(
+        &self,
+        chat_id: u64,
+        agent_id: u64,
+    ) -> Result<Option<ChatAgent>, AppError> {
+        let agent = sqlx::query_as(
+            r#"
+            SELECT * FROM chat_agents WHERE chat_id = $1 AND id = $2
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(agent_id as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(agent)
+    }
+
+    /// delete an agent of a chat. Its bot user stays as the sender of its replies
+    pub async fn delete_agent(&self, chat_id: u64, agent_id: u64) -> Result<(), AppError> {
+        let ret = sqlx::query(
+            r#"
+            DELETE FROM chat_agents WHERE chat_id = $1 AND id = $2
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(agent_id as i64)
+        .execute(&self.pool)
+        .await?;
+
+        if ret.rows_affected() == 0 {
+            return Err(AppError::DeleteAgentError(format!(
+                "Agent {agent_id} does not exist"
+            )));
+        }
+        Ok(())
+    }
+
+    /// run an agent on sample content sent by the user, with the chat as context.
+    /// The call counts against the quota and its usage is recorded, nothing else
+    /// is stored. Disabled agents run as well
+    pub async fn test_agent(
+        &self,
+        input: TestAgent,
+        chat_id: u64,
+        agent_id: u64,
+        user: &User,
+    ) -> Result<AgentDecision, AppError> {
+        let agent = self
+            .get_agent(chat_id, agent_id)
+            .await?
+            .ok_or_else(|| AppError::NotFound(format!("agent {agent_id}")))?;
+        // a draft after the latest message of the chat
+        let message = Message {
+            id: i64::MAX,
+            chat_id: chat_id as _,
+            sender_id: user.id,
+            content: input.content,
+            modified_content: None,
+            files: vec![],
+            reply_to: None,
+            hidden: false,
+            created_at: Utc::now(),
+        };
+        let ws_id = user.ws_id as u64;
+        self.check_agent_quota(&agent, ws_id).await?;
+        let ctx = self.agent_context(&message, ws_id).await?;
+        let ctx = self
+            .knowledge_context(&agent, ws_id, &message.content, &ctx)
+            .await?;
+        let call = self
+            .agent_variant(agent.clone())?
+            .call(&message.content, &ctx)
+            .await?;
+        self.record_usage(&agent, ws_id, &call.usage).await;
+
+        Ok(call.decision)
+    }
+
+    /// agents of the chat whose trigger matches the message, in pipeline order
+    pub(crate) async fn triggered_agents(
+        &self,
+        message: &Message,
+    ) -> Result<Vec<ChatAgent>, AppError> {
+        let agents = self.list_agents(message.chat_id as _).await?;
+        let replied_sender = match message.reply_to {
+            Some(id) => {
+                sqlx::query_scalar("SELECT sender_id FROM messages WHERE id = $1")
+                    .bind(id)
+                    .fetch_optional(&self.pool)
+                    .await?
+            }
+            None => None,
+        };
+        let agents = agents
+            .into_iter()
+            .filter(|agent| {
+                let keyword = match &agent.trigger {
+                    AgentTrigger::Keyword { pattern } => self.agent_keyword(agent.id, pattern),
+                    _ => None,
+                };
+                agent.enabled && agent_triggered(agent, message, replied_sender, keyword.as_ref())
+            })
+            .collect();
+
+        Ok(agents)
+    }
+
+    /// bot users of the agents in a chat
+    pub async fn list_agent_bots(&self, chat_id: u64) -> Result<Vec<ChatUser>, AppError> {
+        let bots = sqlx::query_as(
+            r#"
+            SELECT u.id, u.fullname, u.email, u.is_bot
+            FROM chat_agents a JOIN users u ON u.id = a.bot_id
+            WHERE a.chat_id = $1
+            "#,
+        )
+        .bind(chat_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(bots)
+    }
+
     /// update an agent in a chat
     pub async fn update_agent(
         &self,
@@ -151,39 +419,90 @@ impl AppState {
         let agent_id = input.id;
         let prompt = input.prompt;
         let args = input.args;
+        let fallbacks = input.fallbacks;
+        let priority = input.priority;
+        let policy = input.policy.map(Json);
+        validate_template(&prompt).map_err(AppError::UpdateAgentError)?;
+        if let Some(user_prompt) = &input.user_prompt {
+            validate_template(user_prompt).map_err(AppError::UpdateAgentError)?;
+        }
 
         // check if agent exists
-        if !self.agent_id_exists(chat_id, agent_id).await? {
+        let Some(agent) = self.get_agent(chat_id, agent_id).await? else {
             info!("Agent {agent_id} does not exist in chat {chat_id}");
             return Err(AppError::UpdateAgentError(format!(
                 "Agent {} does not exist",
                 agent_id
             )));
+        };
+        if let Some(trigger) = &input.trigger {
+            validate_trigger(trigger, &agent.name).map_err(AppError::UpdateAgentError)?;
         }
+        let trigger = input.trigger.map(Json);
+        // the new args have to fit the providers, the new providers the args
+        let providers = match &fallbacks {
+            Some(fallbacks) => std::iter::once(AgentProvider::new(agent.adapter, agent.model))
+                .chain(fallbacks.iter().cloned())
+                .collect(),
+            None => agent.providers(),
+        };
+        validate_args(&providers, args.as_ref().unwrap_or(&agent.args))
+            .map_err(AppError::UpdateAgentError)?;
+        self.config
+            .providers
+            .validate(&providers)
+            .map_err(AppError::UpdateAgentError)?;
+        let args = args.map(Json);
 
-        let agent = match (prompt.as_str(), &args) {
-            ("", _) => {
+        let agent = match prompt.as_str() {
+            "" => {
                 sqlx::query_as(
                     r#"
-                    UPDATE chat_agents SET args = $1 WHERE chat_id = $2 AND id = $3 RETURNING *
+                    UPDATE chat_agents SET args = COALESCE($1, args), fallbacks = COALESCE($4, fallbacks),
+                        priority = COALESCE($5, priority), trigger = COALESCE($6, trigger),
+                        policy = COALESCE($7, policy), enabled = COALESCE($8, enabled),
+                        user_prompt = COALESCE($9, user_prompt),
+                        rate_limit = CASE WHEN $10 THEN $11 ELSE rate_limit END
+                    WHERE chat_id = $2 AND id = $3 RETURNING *
                     "#,
                 )
                 .bind(args)
                 .bind(chat_id as i64)
                 .bind(agent_id as i64)
+                .bind(fallbacks.map(Json))
+                .bind(priority)
+                .bind(trigger)
+                .bind(policy)
+                .bind(input.enabled)
+                .bind(&input.user_prompt)
+                .bind(input.rate_limit.is_some())
+                .bind(input.rate_limit.flatten())
                 .fetch_one(&self.pool)
                 .await?
             }
-            (_, _) => {
+            _ => {
                 sqlx::query_as(
                     r#"
-                    UPDATE chat_agents SET prompt = $1, args = $2 WHERE chat_id = $3 AND id = $4 RETURNING *
+                    UPDATE chat_agents SET prompt = $1, args = COALESCE($2, args), fallbacks = COALESCE($5, fallbacks),
+                        priority = COALESCE($6, priority), trigger = COALESCE($7, trigger),
+                        policy = COALESCE($8, policy), enabled = COALESCE($9, enabled),
+                        user_prompt = COALESCE($10, user_prompt),
+                        rate_limit = CASE WHEN $11 THEN $12 ELSE rate_limit END
+                    WHERE chat_id = $3 AND id = $4 RETURNING *
                     "#,
                 )
                 .bind(prompt)
                 .bind(args)
                 .bind(chat_id as i64)
                 .bind(agent_id as i64)
+                .bind(fallbacks.map(Json))
+                .bind(priority)
+                .bind(trigger)
+                .bind(policy)
+                .bind(input.enabled)
+                .bind(&input.user_prompt)
+                .bind(input.rate_limit.is_some())
+                .bind(input.rate_limit.flatten())
                 .fetch_one(&self.pool)
                 .await?
             }
@@ -191,13 +510,69 @@ impl AppState {
 
         Ok(agent)
     }
+
+    /// circuit breaker state of an agent's provider, shared by all its calls
+    pub(crate) fn agent_resilience(&self, agent_id: i64, provider: usize) -> Arc<Resilience> {
+        let mut resilience = self.agent_resilience.lock().unwrap();
+        resilience
+            .entry((agent_id, provider))
+            .or_insert_with(|| Arc::new(Resilience::new(ResilienceConfig::default())))
+            .clone()
+    }
+
+    /// compiled keyword pattern of an agent, compiled again only when the pattern changed.
+    /// `None` if it doesn't compile, patterns are validated when the agent is saved
+    pub(crate) fn agent_keyword(&self, agent_id: i64, pattern: &str) -> Option<Regex> {
+        let mut keywords = self.agent_keywords.lock().unwrap();
+        match keywords.get(&agent_id) {
+            Some(re) if re.as_str() == pattern => Some(re.clone()),
+            _ => {
+                let re = Regex::new(pattern).ok()?;
+                keywords.insert(agent_id, re.clone());
+                Some(re)
+            }
+        }
+    }
+
+    pub(crate) fn agent_variant(&self, agent: ChatAgent) -> Result<AgentVariant, AgentError> {
+        let agent_id = agent.id;
+        let agent = AgentVariant::new(agent, &self.config.providers)?;
+        Ok(agent.with_resilience(|i| self.agent_resilience(agent_id, i)))
+    }
+
+    /// call metrics of the agents in a chat, agents not called yet report zeros
+    pub async fn list_agent_metrics(&self, chat_id: u64) -> Result<Vec<AgentMetrics>, AppError> {
+        let agents = self.list_agents(chat_id).await?;
+        let resilience = self.agent_resilience.lock().unwrap();
+        let metrics = agents
+            .into_iter()
+            .map(|agent| AgentMetrics {
+                providers: agent
+                    .providers()
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, provider)| ProviderMetrics {
+                        provider,
+                        metrics: resilience
+                            .get(&(agent.id, i))
+                            .map(|r| r.metrics())
+                            .unwrap_or_default(),
+                    })
+                    .collect(),
+                agent_id: agent.id,
+                name: agent.name,
+            })
+            .collect();
+
+        Ok(metrics)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::models::{ListMessage, SigninUser};
     use anyhow::Result;
-    use std::collections::HashMap;
 
     #[tokio::test]
     async fn create_agent_should_work() -> Result<()> {
@@ -208,7 +583,7 @@ mod tests {
             "test",
             AgentType::Proxy,
             "You are a helpful assistant",
-            HashMap::<String, String>::new(),
+            AgentArgs::default(),
         );
         let agent = state
             .create_agent(input, 1)
@@ -220,7 +595,43 @@ mod tests {
         assert_eq!(agent.model, "deepseek-chat");
         assert_eq!(agent.r#type, AgentType::Proxy);
         assert_eq!(agent.prompt, "You are a helpful assistant");
-        assert_eq!(agent.args, serde_json::json!({}));
+        assert_eq!(agent.args, AgentArgs::default());
+
+        let bots = state.list_agent_bots(1).await?;
+        assert_eq!(bots.len(), 1);
+        assert_eq!(Some(bots[0].id), agent.bot_id);
+        assert_eq!(bots[0].fullname, "test");
+        assert!(bots[0].is_bot);
+
+        // bots can't sign in
+        let input = SigninUser::new(&bots[0].email, "");
+        assert!(state.verify_user(&input).await?.is_none());
+
+        // fallbacks only pick servers of the config
+        let mut input = CreateAgent::new(
+            AdapterType::Deepseek,
+            "deepseek-chat",
+            "fallback",
+            AgentType::Proxy,
+            "",
+            AgentArgs::default(),
+        );
+        input.fallbacks =
+            vec![AgentProvider::new(AdapterType::OpenaiCompat, "gpt-4o-mini").with_server("evil")];
+        let err = state.create_agent(input, 1).await.unwrap_err();
+        assert!(err.to_string().contains("unknown server evil"));
+
+        // mocks don't read files of the server
+        let input = CreateAgent::new(
+            AdapterType::Mock,
+            "fixture:/etc/passwd",
+            "fixture",
+            AgentType::Reply,
+            "",
+            AgentArgs::default(),
+        );
+        let err = state.create_agent(input, 1).await.unwrap_err();
+        assert!(err.to_string().contains("mock fixtures"));
         Ok(())
     }
 
@@ -233,7 +644,7 @@ mod tests {
             "test",
             AgentType::Proxy,
             "You are a helpful assistant",
-            HashMap::<String, String>::new(),
+            AgentArgs::default(),
         );
         let _ = state
             .create_agent(input, 1)
@@ -244,7 +655,7 @@ mod tests {
         assert_eq!(agents[0].name, "test");
         assert_eq!(agents[0].r#type, AgentType::Proxy);
         assert_eq!(agents[0].prompt, "You are a helpful assistant");
-        assert_eq!(agents[0].args, serde_json::json!({}));
+        assert_eq!(agents[0].args, AgentArgs::default());
         Ok(())
     }
 
@@ -258,7 +669,7 @@ mod tests {
             "test",
             AgentType::Proxy,
             "You are a helpful assistant",
-            HashMap::<String, String>::new(),
+            AgentArgs::default(),
         );
         let agent = state
             .create_agent(input, 1)
@@ -268,14 +679,152 @@ mod tests {
         let input = UpdateAgent::new(
             agent.id as u64,
             "Can you tell me the weather in Tokyo?",
-            HashMap::<String, String>::new(),
+            AgentArgs::default(),
         );
         let agent = state
             .update_agent(input, 1)
             .await
             .expect("update agent failed");
         assert_eq!(agent.prompt, "Can you tell me the weather in Tokyo?");
-        assert_eq!(agent.args, serde_json::json!({}));
+        assert_eq!(agent.args, AgentArgs::default());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn agent_keyword_should_be_compiled_again_when_changed() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let re = state.agent_keyword(1, "invoice").unwrap();
+        assert_eq!(re.as_str(), "invoice");
+        assert!(state
+            .agent_keyword(1, "invoice")
+            .unwrap()
+            .is_match("my invoice"));
+        // the pattern was updated
+        let re = state.agent_keyword(1, "refund").unwrap();
+        assert!(re.is_match("a refund") && !re.is_match("my invoice"));
+        assert!(state.agent_keyword(1, "(").is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_delete_and_test_agent_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = CreateAgent::new(
+            AdapterType::Mock,
+            "echo",
+            "echo",
+            AgentType::Reply,
+            "",
+            AgentArgs::default(),
+        );
+        let agent = state.create_agent(input, 1).await?;
+        assert!(agent.enabled);
+        let ret = state.get_agent(1, agent.id as _).await?;
+        assert_eq!(ret, Some(agent.clone()));
+        // only agents of the chat
+        assert!(state.get_agent(2, agent.id as _).await?.is_none());
+
+        // a paused agent isn't triggered but can still be tried out
+        let input = UpdateAgent {
+            enabled: Some(false),
+            ..UpdateAgent::new(agent.id as _, "", AgentArgs::default())
+        };
+        let agent = state.update_agent(input, 1).await?;
+        assert!(!agent.enabled);
+        let message = state
+            .get_message_by_id(1)
+            .await?
+            .expect("message should exist");
+        assert!(state.triggered_agents(&message).await?.is_empty());
+
+        let messages = state.list_message(ListMessage::new(None, 0), 1).await?;
+        let user = state.find_user_by_id(1).await?.expect("user should exist");
+        let input = TestAgent {
+            content: "hello".to_string(),
+        };
+        let decision = state.test_agent(input, 1, agent.id as _, &user).await?;
+        assert!(matches!(decision, AgentDecision::Reply(content) if content.ends_with("hello")));
+        let ret = state.list_message(ListMessage::new(None, 0), 1).await?;
+        assert_eq!(ret.len(), messages.len());
+
+        state.delete_agent(1, agent.id as _).await?;
+        assert!(state.get_agent(1, agent.id as _).await?.is_none());
+        let ret = state.delete_agent(1, agent.id as _).await;
+        assert!(matches!(ret, Err(AppError::DeleteAgentError(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_agent_should_count_against_quota() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let mut input = CreateAgent::new(
+            AdapterType::Mock,
+            "echo",
+            "echo",
+            AgentType::Reply,
+            "",
+            AgentArgs::default(),
+        );
+        input.rate_limit = Some(1);
+        let agent = state.create_agent(input, 1).await?;
+        let user = state.find_user_by_id(1).await?.expect("user should exist");
+        let input = || TestAgent {
+            content: "hello".to_string(),
+        };
+        state.test_agent(input(), 1, agent.id as _, &user).await?;
+        let quota = state.get_quota_state(1).await?;
+        assert!(quota.used_tokens > 0);
+
+        let ret = state.test_agent(input(), 1, agent.id as _, &user).await;
+        assert!(matches!(ret, Err(AppError::QuotaExceeded(_))));
+        assert_eq!(
+            state.get_quota_state(1).await?.used_tokens,
+            quota.used_tokens
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn agent_prompt_templates_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = CreateAgent {
+            user_prompt: "{{ sender.fullname }} in #{{ chat.name }}: {{ message }}".to_string(),
+            ..CreateAgent::new(
+                AdapterType::Mock,
+                "echo",
+                "echo",
+                AgentType::Proxy,
+                "Translate to French",
+                AgentArgs::default(),
+            )
+        };
+        let agent = state.create_agent(input, 1).await?;
+        let user = state.find_user_by_id(2).await?.expect("user should exist");
+        let input = TestAgent {
+            content: "hello".to_string(),
+        };
+        let decision = state.test_agent(input, 1, agent.id as _, &user).await?;
+        assert!(
+            matches!(decision, AgentDecision::Modify(content) if content == "alice123 in #general: hello")
+        );
+
+        // templates are checked when the agent is saved
+        let input = UpdateAgent {
+            user_prompt: Some("{{ message }".to_string()),
+            ..UpdateAgent::new(agent.id as _, "", AgentArgs::default())
+        };
+        let ret = state.update_agent(input, 1).await;
+        assert!(matches!(ret, Err(AppError::UpdateAgentError(_))));
+        let input = CreateAgent::new(
+            AdapterType::Mock,
+            "echo",
+            "greeter",
+            AgentType::Reply,
+            "Greet {{ user }}",
+            AgentArgs::default(),
+        );
+        let ret = state.create_agent(input, 1).await;
+        assert!(matches!(ret, Err(AppError::CreateAgentError(e)) if e.contains("user")));
         Ok(())
     }
 }
@@ -0,0 +1,265 @@
+use crate::{AppError, AppState};
+use chat_core::ChatAgent;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use utoipa::ToSchema;
+
+/// monthly budget of a workspace, `None` means unlimited
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, ToSchema, FromRow)]
+pub struct WorkspaceBudget {
+    #[serde(default)]
+    pub monthly_tokens: Option<i64>,
+    #[serde(default)]
+    pub monthly_cost: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct QuotaState {
+    pub ws_id: u64,
+    pub budget: WorkspaceBudget,
+    pub month_start: DateTime<Utc>,
+    pub used_tokens: i64,
+    pub used_cost: f64,
+    /// agents are skipped until the next month
+    pub exhausted: bool,
+}
+
+#[derive(Debug, FromRow)]
+struct MonthlyUsage {
+    month_start: DateTime<Utc>,
+    tokens: i64,
+    cost: f64,
+}
+
+impl AppState {
+    /// set the budget of the workspace, only the owner may do so
+    pub async fn set_workspace_budget(
+        &self,
+        ws_id: u64,
+        user_id: u64,
+        input: WorkspaceBudget,
+    ) -> Result<WorkspaceBudget, AppError> {
+        self.check_workspace_owner(ws_id, user_id).await?;
+
+        let budget = sqlx::query_as(
+            r#"
+            INSERT INTO workspace_budgets (ws_id, monthly_tokens, monthly_cost)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (ws_id) DO UPDATE SET
+                monthly_tokens = EXCLUDED.monthly_tokens,
+                monthly_cost = EXCLUDED.monthly_cost,
+                updated_at = CURRENT_TIMESTAMP
+            RETURNING monthly_tokens, monthly_cost
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(input.monthly_tokens)
+        .bind(input.monthly_cost)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(budget)
+    }
+
+    pub async fn get_workspace_budget(&self, ws_id: u64) -> Result<WorkspaceBudget, AppError> {
+        let budget = sqlx::query_as(
+            "SELECT monthly_tokens, monthly_cost FROM workspace_budgets WHERE ws_id = $1",
+        )
+        .bind(ws_id as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(budget.unwrap_or_default())
+    }
+
+    /// budget of the workspace and its usage in the current month
+    pub async fn get_quota_state(&self, ws_id: u64) -> Result<QuotaState, AppError> {
+        let budget = self.get_workspace_budget(ws_id).await?;
+        let usage: MonthlyUsage = sqlx::query_as(
+            r#"
+            SELECT date_trunc('month', CURRENT_TIMESTAMP) AS month_start,
+                COALESCE(SUM(prompt_tokens + completion_tokens), 0)::BIGINT AS tokens,
+                COALESCE(SUM(cost), 0) AS cost
+            FROM agent_usage
+            WHERE ws_id = $1 AND created_at >= date_trunc('month', CURRENT_TIMESTAMP)
+            "#,
+        )
+        .bind(ws_id as i64)
+        .fetch_one(&self.pool)
+        .await?;
+
+        let exhausted = budget.monthly_tokens.is_some_and(|b| usage.tokens >= b)
+            || budget.monthly_cost.is_some_and(|b| usage.cost >= b);
+        Ok(QuotaState {
+            ws_id,
+            budget,
+            month_start: usage.month_start,
+            used_tokens: usage.tokens,
+            used_cost: usage.cost,
+            exhausted,
+        })
+    }
+
+    /// check the workspace budget and the agent rate limit before calling the agent
+    pub async fn check_agent_quota(&self, agent: &ChatAgent, ws_id: u64) -> Result<(), AppError> {
+        let state = self.get_quota_state(ws_id).await?;
+        if state.exhausted {
+            return Err(AppError::QuotaExceeded(format!(
+                "workspace {ws_id} used {} tokens and {:.4} of its monthly budget",
+                state.used_tokens, state.used_cost
+            )));
+        }
+
+        if let Some(limit) = agent.rate_limit {
+            self.count_agent_call(agent, limit).await?;
+        }
+
+        Ok(())
+    }
+
+    /// count the call against the agent's rate limit, whether it succeeds or not
+    async fn count_agent_call(&self, agent: &ChatAgent, limit: i32) -> Result<(), AppError> {
+        let mut tx = self.pool.begin().await?;
+        // concurrent jobs of the agent wait for each other's count
+        sqlx::query("SELECT pg_advisory_xact_lock($1)")
+            .bind(agent.id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query(
+            "DELETE FROM agent_calls WHERE agent_id = $1 AND created_at <= CURRENT_TIMESTAMP - INTERVAL '1 minute'",
+        )
+        .bind(agent.id)
+        .execute(&mut *tx)
+        .await?;
+        let calls: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM agent_calls WHERE agent_id = $1")
+            .bind(agent.id)
+            .fetch_one(&mut *tx)
+            .await?;
+        if calls >= limit as i64 {
+            return Err(AppError::QuotaExceeded(format!(
+                "agent {} reached its rate limit of {limit} calls per minute",
+                agent.name
+            )));
+        }
+        sqlx::query("INSERT INTO agent_calls (agent_id) VALUES ($1)")
+            .bind(agent.id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{CreateAgent, CreateMessage, UpdateAgent};
+    use ai_sdk::Usage;
+    use anyhow::Result;
+    use chat_core::AgentArgs;
+    use chat_core::{AdapterType, AgentType};
+
+    async fn create_proxy_agent(state: &AppState) -> Result<ChatAgent> {
+        let input = CreateAgent::new(
+            AdapterType::OpenaiCompat,
+            "gpt-4o-mini",
+            "rewriter",
+            AgentType::Proxy,
+            "Rewrite the message",
+            AgentArgs::default(),
+        );
+        Ok(state.create_agent(input, 1).await?)
+    }
+
+    #[tokio::test]
+    async fn workspace_budget_should_only_be_set_by_owner() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        state.update_workspace_owner(1, 1).await?;
+        let input = WorkspaceBudget {
+            monthly_tokens: Some(1000),
+            ..Default::default()
+        };
+        let err = state
+            .set_workspace_budget(1, 2, input.clone())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AppError::NotWorkspaceOwnerError { .. }));
+
+        let budget = state.set_workspace_budget(1, 1, input).await?;
+        assert_eq!(budget.monthly_tokens, Some(1000));
+        assert_eq!(state.get_workspace_budget(1).await?, budget);
+        assert_eq!(state.get_workspace_budget(2).await?, Default::default());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn exhausted_budget_should_skip_agent() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        state.update_workspace_owner(1, 1).await?;
+        let agent = create_proxy_agent(&state).await?;
+        state.check_agent_quota(&agent, 1).await?;
+
+        let input = WorkspaceBudget {
+            monthly_tokens: Some(1000),
+            ..Default::default()
+        };
+        state.set_workspace_budget(1, 1, input).await?;
+        state
+            .record_agent_usage(&agent, 1, &Usage::new(800, 200).into())
+            .await?;
+        let quota = state.get_quota_state(1).await?;
+        assert!(quota.exhausted);
+        assert_eq!(quota.used_tokens, 1000);
+        let err = state.check_agent_quota(&agent, 1).await.unwrap_err();
+        assert!(matches!(err, AppError::QuotaExceeded(_)));
+
+        // the message is stored as is, without calling the agent
+        let message = state
+            .create_message(CreateMessage::new("hello", vec![]), 1, 1, 1)
+            .await?;
+        state.run_agent_jobs().await?;
+        let message = state.get_message_by_id(message.id).await?.unwrap();
+        assert_eq!(message.content, "hello");
+        assert_eq!(message.modified_content, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn agent_rate_limit_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let mut input = CreateAgent::new(
+            AdapterType::OpenaiCompat,
+            "gpt-4o-mini",
+            "limited",
+            AgentType::Proxy,
+            "Rewrite the message",
+            AgentArgs::default(),
+        );
+        input.rate_limit = Some(2);
+        let agent = state.create_agent(input, 1).await?;
+        let other = create_proxy_agent(&state).await?;
+
+        // calls count even when they failed and recorded no usage
+        state.check_agent_quota(&agent, 1).await?;
+        state.check_agent_quota(&agent, 1).await?;
+        let err = state.check_agent_quota(&agent, 1).await.unwrap_err();
+        assert!(err.to_string().contains("rate limit"));
+        // other agents of the workspace aren't limited by it
+        for _ in 0..3 {
+            state.check_agent_quota(&other, 1).await?;
+        }
+
+        // the limit is kept unless set, null removes it
+        let input: UpdateAgent = serde_json::from_str(&format!(r#"{{"id": {}}}"#, agent.id))?;
+        let agent = state.update_agent(input, 1).await?;
+        assert_eq!(agent.rate_limit, Some(2));
+        let input: UpdateAgent =
+            serde_json::from_str(&format!(r#"{{"id": {}, "rate_limit": null}}"#, agent.id))?;
+        let agent = state.update_agent(input, 1).await?;
+        assert_eq!(agent.rate_limit, None);
+        state.check_agent_quota(&agent, 1).await?;
+        Ok(())
+    }
+}
@@ -70,6 +70,7 @@ impl AppState {
             .bind(id as i64)
             .fetch_one(&self.pool)
             .await?;
+        self.forget_chat(chat.id).await?;
 
         Ok(chat)
     }
@@ -0,0 +1,539 @@
+use super::{
+    knowledge::collection_name,
+    search::{store_nodes, vector_node, vector_store_error},
+    ChatFile,
+};
+use crate::{AppError, AppState};
+use ai_sdk::EmbeddingService;
+use chat_core::{AgentError, KnowledgeCollection};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{query_as, FromRow};
+use std::{str::FromStr, time::Duration};
+use swiftide_pgvector::MetadataFilter;
+use tokio::{
+    fs,
+    task::{self, JoinHandle},
+};
+use tracing::{info, warn};
+use utoipa::{IntoParams, ToSchema};
+
+/// chunks are cut at paragraph or row boundaries below this many characters
+const CHUNK_CHARS: usize = 1000;
+/// chunks embedded and stored at once, so a large file isn't held in memory as vectors
+const INGEST_BATCH: usize = 32;
+/// a running ingestion whose worker didn't report back within the lease is claimed again
+const INGEST_LEASE_SECS: i32 = 600;
+/// larger files are not ingested, extracting their text would hold a blocking thread too long
+const MAX_INGEST_BYTES: u64 = 10 * 1024 * 1024;
+/// extensions of the files which can be ingested
+const INGESTIBLE: [&str; 5] = ["txt", "md", "markdown", "csv", "pdf"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, sqlx::Type, ToSchema)]
+#[sqlx(type_name = "ingest_status", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum IngestStatus {
+    Pending,
+    Running,
+    Done,
+    Failed,
+}
+
+/// an uploaded file of the knowledge base and how far its ingestion got
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, FromRow, ToSchema)]
+pub struct KnowledgeFile {
+    pub id: i64,
+    pub ws_id: i64,
+    pub collection: KnowledgeCollection,
+    /// sha1 of the content, the ids of its chunks are derived from it
+    pub hash: String,
+    pub url: String,
+    pub name: String,
+    pub status: IngestStatus,
+    pub chunks: i32,
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct IngestFile {
+    /// url of an uploaded file, e.g. `/files/1/1e2/078/862bd199a443a09348c11e463c80527905.md`
+    pub url: String,
+    /// shown instead of the url, e.g. the name of the uploaded file
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub collection: KnowledgeCollection,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, IntoParams, ToSchema)]
+pub struct ListKnowledgeFiles {
+    #[serde(default)]
+    pub collection: Option<KnowledgeCollection>,
+    #[serde(default)]
+    pub status: Option<IngestStatus>,
+    /// files with a smaller id, for paging
+    #[serde(default)]
+    pub last_id: Option<u64>,
+}
+
+impl AppState {
+    /// queue an uploaded file for ingestion into a collection of the workspace. Uploads
+    /// are only ingested this way, so files of private chats stay out of the knowledge
+    /// base unless added on purpose. A file already in the collection is returned as
+    /// is, a failed one is queued again
+    pub async fn ingest_file(
+        &self,
+        input: IngestFile,
+        ws_id: u64,
+    ) -> Result<KnowledgeFile, AppError> {
+        self.vector_index()?;
+        if input.collection == KnowledgeCollection::ChatHistory {
+            return Err(AppError::ChatFileError(
+                "files can't be ingested into the chat history".to_string(),
+            ));
+        }
+        let file = ChatFile::from_str(&input.url)?;
+        let path = file.path(&self.config.server.base_dir);
+        if file.ws_id != ws_id || !path.exists() {
+            return Err(AppError::NotFound(format!("file {}", input.url)));
+        }
+        check_size(fs::metadata(path).await?.len())?;
+        if !is_ingestible(&file.ext) {
+            return Err(AppError::ChatFileError(format!(
+                "unsupported file type {}, expected one of {}",
+                file.ext,
+                INGESTIBLE.join(", ")
+            )));
+        }
+        let name = input.name.unwrap_or_else(|| input.url.clone());
+        let file = query_as(
+            r#"
+            INSERT INTO knowledge_files (ws_id, collection, hash, url, name)
+            VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (ws_id, collection, hash) DO UPDATE SET
+                status = CASE WHEN knowledge_files.status = 'failed' THEN 'pending'
+                    ELSE knowledge_files.status END,
+                updated_at = CURRENT_TIMESTAMP
+            RETURNING *
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(input.collection)
+        .bind(&file.hash)
+        .bind(file.url())
+        .bind(name)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(file)
+    }
+
+    /// files of the workspace's knowledge base, newest first
+    pub async fn list_knowledge_files(
+        &self,
+        ws_id: u64,
+        input: ListKnowledgeFiles,
+    ) -> Result<Vec<KnowledgeFile>, AppError> {
+        let last_id = input.last_id.unwrap_or(i64::MAX as _);
+        let files = query_as(
+            r#"
+            SELECT * FROM knowledge_files
+            WHERE ws_id = $1 AND id < $2
+                AND ($3::knowledge_collection IS NULL OR collection = $3)
+                AND ($4::ingest_status IS NULL OR status = $4)
+            ORDER BY id DESC
+            LIMIT 100
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(last_id as i64)
+        .bind(input.collection)
+        .bind(input.status)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(files)
+    }
+
+    pub async fn get_knowledge_file(
+        &self,
+        ws_id: u64,
+        id: u64,
+    ) -> Result<Option<KnowledgeFile>, AppError> {
+        let file = query_as("SELECT * FROM knowledge_files WHERE id = $1 AND ws_id = $2")
+            .bind(id as i64)
+            .bind(ws_id as i64)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(file)
+    }
+
+    /// claim the next pending file, skipping the ones locked by other workers
+    async fn claim_knowledge_file(&self) -> Result<Option<KnowledgeFile>, AppError> {
+        let file = query_as(
+            r#"
+            UPDATE knowledge_files SET status = 'running', locked_at = CURRENT_TIMESTAMP,
+                updated_at = CURRENT_TIMESTAMP
+            WHERE id = (
+                SELECT id FROM knowledge_files
+                WHERE status = 'pending'
+                    OR (status = 'running' AND locked_at < CURRENT_TIMESTAMP - make_interval(secs => $1))
+                ORDER BY id ASC
+                FOR UPDATE SKIP LOCKED
+                LIMIT 1
+            )
+            RETURNING *
+            "#,
+        )
+        .bind(INGEST_LEASE_SECS)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(file)
+    }
+
+    /// claim and ingest the next file, returns false if there is none
+    pub(crate) async fn run_next_ingestion(&self) -> Result<bool, AppError> {
+        let Some(file) = self.claim_knowledge_file().await? else {
+            return Ok(false);
+        };
+        let (status, chunks, error) = match self.ingest(&file).await {
+            Ok(chunks) => (IngestStatus::Done, chunks as i32, None),
+            Err(e) => {
+                warn!("ingest file {} failed: {e}", file.id);
+                (IngestStatus::Failed, 0, Some(e.to_string()))
+            }
+        };
+        sqlx::query(
+            r#"
+            UPDATE knowledge_files SET status = $1, chunks = $2, error = $3, locked_at = NULL,
+                updated_at = CURRENT_TIMESTAMP
+            WHERE id = $4
+            "#,
+        )
+        .bind(status)
+        .bind(chunks)
+        .bind(error)
+        .bind(file.id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(true)
+    }
+
+    /// chunk the file and store the embedding of each chunk in place of the previous
+    /// ones, a batch at a time. Returns the number of chunks
+    async fn ingest(&self, file: &KnowledgeFile) -> Result<usize, AppError> {
+        let (embedder, store) = self.vector_index()?;
+        let chat_file = ChatFile::from_str(&file.url)?;
+        let path = chat_file.path(&self.config.server.base_dir);
+        check_size(fs::metadata(&path).await?.len())?;
+        let data = fs::read(path).await?;
+        // pdf extraction is cpu bound and may panic on malformed files, the panic
+        // fails the file instead of the worker
+        let chunks = task::spawn_blocking(move || file_chunks(&chat_file.ext, &data))
+            .await
+            .map_err(|e| AppError::ChatFileError(format!("failed to extract the text: {e}")))?
+            .map_err(AppError::ChatFileError)?;
+        store
+            .delete(&[MetadataFilter::eq("file_id", file.id)])
+            .await
+            .map_err(vector_store_error)?;
+
+        let collection = collection_name(file.collection);
+        for (n, batch) in chunks.chunks(INGEST_BATCH).enumerate() {
+            let vectors = embedder.embed(batch).await.map_err(AgentError::from)?;
+            let nodes = batch
+                .iter()
+                .zip(vectors)
+                .enumerate()
+                .map(|(i, (chunk, vector))| {
+                    let i = n * INGEST_BATCH + i;
+                    let metadata = json!({
+                        "ws_id": file.ws_id,
+                        "model": embedder.model(),
+                        "collection": collection,
+                        "file_id": file.id,
+                        "hash": file.hash,
+                        "chunk": i,
+                        "name": file.name,
+                        "source": file.url,
+                    });
+                    // the node id is derived from the path, so from the hash of the content.
+                    // Other workspaces ingesting the same content get nodes of their own
+                    let path = format!(
+                        "{}/{}/{}/{i}",
+                        file.ws_id,
+                        collection.as_str().unwrap_or_default(),
+                        file.hash
+                    );
+                    vector_node(path, chunk, metadata, vector)
+                })
+                .collect();
+            store_nodes(store, nodes).await?;
+        }
+
+        Ok(chunks.len())
+    }
+
+    /// start the worker ingesting the files queued for the knowledge base
+    pub fn spawn_file_ingester(&self) -> Option<JoinHandle<()>> {
+        self.embedder.as_ref()?;
+        let poll_interval = Duration::from_millis(self.config.agent.poll_interval);
+        info!("starting the knowledge base file ingester");
+        let state = self.clone();
+        Some(tokio::spawn(async move {
+            loop {
+                match state.run_next_ingestion().await {
+                    Ok(true) => continue,
+                    Ok(false) => {}
+                    Err(e) => warn!("file ingester failed: {e}"),
+                }
+                tokio::time::sleep(poll_interval).await;
+            }
+        }))
+    }
+
+    /// ingest the queued files until none is left
+    #[cfg(test)]
+    pub(crate) async fn ingest_files(&self) -> Result<(), AppError> {
+        while self.run_next_ingestion().await? {}
+        Ok(())
+    }
+}
+
+fn check_size(len: u64) -> Result<(), AppError> {
+    if len > MAX_INGEST_BYTES {
+        return Err(AppError::ChatFileError(format!(
+            "file of {len} bytes is too large, at most {MAX_INGEST_BYTES} bytes are ingested"
+        )));
+    }
+    Ok(())
+}
+
+fn is_ingestible(ext: &str) -> bool {
+    INGESTIBLE.contains(&ext.to_lowercase().as_str())
+}
+
+/// the text of the file cut into chunks: paragraphs for text, markdown and pdf,
+/// rows labelled with the header for csv
+pub(crate) fn file_chunks(ext: &str, data: &[u8]) -> Result<Vec<String>, String> {
+    match ext.to_lowercase().as_str() {
+        "txt" | "md" | "markdown" => Ok(text_chunks(&String::from_utf8_lossy(data))),
+        "csv" => csv_chunks(data),
+        "pdf" => pdf_extract::extract_text_from_mem(data)
+            .map(|text| text_chunks(&text))
+            .map_err(|e| format!("invalid pdf: {e}")),
+        ext => Err(format!("unsupported file type {ext}")),
+    }
+}
+
+/// paragraphs joined up to `CHUNK_CHARS`, a markdown heading starts a new chunk
+fn text_chunks(text: &str) -> Vec<String> {
+    let text = text.replace("\r\n", "\n");
+    let mut chunks = Chunks::default();
+    for paragraph in text.split("\n\n").map(str::trim).filter(|p| !p.is_empty()) {
+        if paragraph.starts_with('#') {
+            chunks.flush();
+        }
+        let chars: Vec<char> = paragraph.chars().collect();
+        for piece in chars.chunks(CHUNK_CHARS) {
+            chunks.push(&piece.iter().collect::<String>(), "\n\n");
+        }
+    }
+    chunks.finish()
+}
+
+/// one `header: value, ...` line per row, joined up to `CHUNK_CHARS`
+fn csv_chunks(data: &[u8]) -> Result<Vec<String>, String> {
+    let mut reader = csv::ReaderBuilder::new().flexible(true).from_reader(data);
+    let headers = reader
+        .headers()
+        .map_err(|e| format!("invalid csv: {e}"))?
+        .clone();
+    let mut chunks = Chunks::default();
+    for record in reader.records() {
+        let record = record.map_err(|e| format!("invalid csv: {e}"))?;
+        let row = headers
+            .iter()
+            .zip(record.iter())
+            .map(|(header, value)| format!("{header}: {value}"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        chunks.push(&row, "\n");
+    }
+    Ok(chunks.finish())
+}
+
+#[derive(Default)]
+struct Chunks {
+    chunks: Vec<String>,
+    current: String,
+}
+
+impl Chunks {
+    /// add the piece to the current chunk, a new one is started when it's full
+    fn push(&mut self, piece: &str, separator: &str) {
+        let len = self.current.chars().count() + separator.len() + piece.chars().count();
+        if !self.current.is_empty() && len > CHUNK_CHARS {
+            self.flush();
+        }
+        if !self.current.is_empty() {
+            self.current.push_str(separator);
+        }
+        self.current.push_str(piece);
+    }
+
+    fn flush(&mut self) {
+        if !self.current.is_empty() {
+            self.chunks.push(std::mem::take(&mut self.current));
+        }
+    }
+
+    fn finish(mut self) -> Vec<String> {
+        self.flush();
+        self.chunks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use chat_core::KnowledgeArgs;
+
+    const JUVENTUS: &[u8] = include_bytes!("../../../assets/juventus.csv");
+
+    #[test]
+    fn file_chunks_should_work() {
+        let chunks = file_chunks("csv", JUVENTUS).unwrap();
+        assert!(chunks.len() > 1);
+        assert!(chunks[0].starts_with(
+            "Name: Wojciech Szczesny, Position: Goalkeeper, DOB: Apr 18, 1990 (29), Nationality: Poland, Kit Number: 1\n"
+        ));
+        assert!(chunks.iter().all(|c| c.chars().count() <= CHUNK_CHARS));
+
+        let md =
+            "# Deploy\n\nRun make deploy.\n\nCheck the logs.\n\n# Rollback\n\nRun make rollback.";
+        let chunks = file_chunks("md", md.as_bytes()).unwrap();
+        assert_eq!(
+            chunks,
+            [
+                "# Deploy\n\nRun make deploy.\n\nCheck the logs.",
+                "# Rollback\n\nRun make rollback."
+            ]
+        );
+        let long = "a".repeat(CHUNK_CHARS + 1);
+        assert_eq!(file_chunks("txt", long.as_bytes()).unwrap().len(), 2);
+        assert!(file_chunks("pdf", b"not a pdf").is_err());
+        assert!(file_chunks("png", b"").is_err());
+    }
+
+    #[tokio::test]
+    async fn ingest_file_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let file = ChatFile::new(1, "juventus.csv", JUVENTUS);
+        let path = file.path(&state.config.server.base_dir);
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::fs::write(path, JUVENTUS)?;
+
+        let input = IngestFile {
+            url: file.url(),
+            name: Some("juventus.csv".to_string()),
+            collection: KnowledgeCollection::Files,
+        };
+        let files = state.list_knowledge_files(1, Default::default()).await?;
+        assert!(files.is_empty());
+        state.ingest_file(input.clone(), 1).await?;
+        let files = state.list_knowledge_files(1, Default::default()).await?;
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].status, IngestStatus::Pending);
+        assert_eq!(files[0].collection, KnowledgeCollection::Files);
+
+        state.ingest_files().await?;
+        let ingested = state
+            .get_knowledge_file(1, files[0].id as _)
+            .await?
+            .unwrap();
+        assert_eq!(ingested.status, IngestStatus::Done);
+        assert!(ingested.chunks > 1);
+        let (_, store) = state.vector_index()?;
+        let sql = format!(
+            "SELECT count(*) FROM {} WHERE metadata @> jsonb_build_object('file_id', $1::bigint)",
+            store.table_name()
+        );
+        let nodes: i64 = sqlx::query_scalar(&sql)
+            .bind(ingested.id)
+            .fetch_one(&state.pool)
+            .await?;
+        assert_eq!(nodes, ingested.chunks as i64);
+
+        // the same content is ingested once
+        let again = state.ingest_file(input.clone(), 1).await?;
+        assert_eq!(again.id, ingested.id);
+        assert_eq!(again.status, IngestStatus::Done);
+        assert!(!state.run_next_ingestion().await?);
+
+        let args = KnowledgeArgs {
+            collection: KnowledgeCollection::Files,
+            top_k: Some(1),
+        };
+        let chunks = state
+            .retrieve_knowledge(
+                1,
+                1,
+                &args,
+                "Wojciech Szczesny, Goalkeeper, Poland, kit number 1",
+            )
+            .await?;
+        assert_eq!(chunks[0].source, file.url());
+        assert!(chunks[0].content.contains("Nationality: Poland"));
+
+        // other workspaces don't see the file
+        assert!(state.ingest_file(input.clone(), 2).await.is_err());
+        // but may upload and ingest the same content, the nodes of both are kept
+        let other = ChatFile::new(2, "juventus.csv", JUVENTUS);
+        let path = other.path(&state.config.server.base_dir);
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::fs::write(path, JUVENTUS)?;
+        let other_input = IngestFile {
+            url: other.url(),
+            ..input.clone()
+        };
+        let other = state.ingest_file(other_input, 2).await?;
+        state.ingest_files().await?;
+        for file in [&ingested, &other] {
+            let nodes: i64 = sqlx::query_scalar(&sql)
+                .bind(file.id)
+                .fetch_one(&state.pool)
+                .await?;
+            assert_eq!(nodes, ingested.chunks as i64);
+        }
+        let chunks = state
+            .retrieve_knowledge(1, 1, &args, "Wojciech Szczesny, Goalkeeper")
+            .await?;
+        assert_eq!(chunks[0].source, file.url());
+        let input = IngestFile {
+            url: "/files/1/abc/def/missing.csv".to_string(),
+            ..input
+        };
+        assert!(state.ingest_file(input.clone(), 1).await.is_err());
+
+        let large = vec![b'a'; MAX_INGEST_BYTES as usize + 1];
+        let file = ChatFile::new(1, "large.txt", &large);
+        let path = file.path(&state.config.server.base_dir);
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::fs::write(path, &large)?;
+        let input = IngestFile {
+            url: file.url(),
+            ..input
+        };
+        let err = state.ingest_file(input, 1).await.unwrap_err();
+        assert!(err.to_string().contains("too large"));
+        Ok(())
+    }
+}
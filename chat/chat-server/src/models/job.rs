@@ -0,0 +1,544 @@
+use crate::{AppError, AppState};
+use chat_core::Message;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{query_as, FromRow, PgExecutor};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+use utoipa::{IntoParams, ToSchema};
+
+/// a running job whose worker didn't report back within the lease is claimed again
+const JOB_LEASE_SECS: i32 = 300;
+/// the lease of a running job is renewed this often, agents may run longer than the lease
+const JOB_HEARTBEAT: Duration = Duration::from_secs(60);
+/// the delay before the n-th retry is `JOB_RETRY_BASE_SECS * 2^(n-1)`
+const JOB_RETRY_BASE_SECS: i32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, sqlx::Type, ToSchema)]
+#[sqlx(type_name = "job_status", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Pending,
+    Running,
+    Done,
+    /// failed max_attempts times, dead-lettered until retried by hand
+    Dead,
+}
+
+/// agent processing of a message
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, FromRow, ToSchema)]
+pub struct AgentJob {
+    pub id: i64,
+    pub message_id: i64,
+    pub chat_id: i64,
+    pub ws_id: i64,
+    pub status: JobStatus,
+    pub attempts: i32,
+    pub max_attempts: i32,
+    pub last_error: Option<String>,
+    /// not claimed before
+    pub run_at: DateTime<Utc>,
+    pub locked_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, IntoParams, ToSchema)]
+pub struct ListJobs {
+    /// defaults to dead jobs
+    #[serde(default)]
+    pub status: Option<JobStatus>,
+    /// jobs with a smaller id, for paging
+    #[serde(default)]
+    pub last_id: Option<u64>,
+}
+
+impl AppState {
+    /// queue the message for the agents of its chat
+    pub(crate) async fn enqueue_agent_job<'e>(
+        &self,
+        executor: impl PgExecutor<'e>,
+        message: &Message,
+        ws_id: u64,
+    ) -> Result<AgentJob, AppError> {
+        let job = query_as(
+            r#"
+            INSERT INTO agent_jobs (message_id, chat_id, ws_id, max_attempts)
+            VALUES ($1, $2, $3, $4)
+            RETURNING *
+            "#,
+        )
+        .bind(message.id)
+        .bind(message.chat_id)
+        .bind(ws_id as i64)
+        .bind(self.config.agent.max_attempts)
+        .fetch_one(executor)
+        .await?;
+
+        Ok(job)
+    }
+
+    /// claim the next due job, skipping the ones locked by other workers. Jobs whose
+    /// worker died are claimed again once their lease is over
+    pub(crate) async fn claim_agent_job(&self) -> Result<Option<AgentJob>, AppError> {
+        let job = query_as(
+            r#"
+            UPDATE agent_jobs SET status = 'running', attempts = attempts + 1,
+                locked_at = CURRENT_TIMESTAMP, updated_at = CURRENT_TIMESTAMP
+            WHERE id = (
+                SELECT id FROM agent_jobs
+                WHERE (status = 'pending' AND run_at <= CURRENT_TIMESTAMP)
+                    OR (status = 'running' AND locked_at < CURRENT_TIMESTAMP - make_interval(secs => $1))
+                ORDER BY run_at ASC, id ASC
+                FOR UPDATE SKIP LOCKED
+                LIMIT 1
+            )
+            RETURNING *
+            "#,
+        )
+        .bind(JOB_LEASE_SECS)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(job)
+    }
+
+    /// claim and process the next job, returns false if there is none
+    pub(crate) async fn run_next_agent_job(&self) -> Result<bool, AppError> {
+        let Some(job) = self.claim_agent_job().await? else {
+            return Ok(false);
+        };
+        let heartbeat = self.spawn_job_heartbeat(job.id);
+        let ret = self.process_agent_job(&job).await;
+        drop(heartbeat);
+        match ret {
+            Ok(()) => {
+                sqlx::query(
+                    "UPDATE agent_jobs SET status = 'done', updated_at = CURRENT_TIMESTAMP WHERE id = $1",
+                )
+                .bind(job.id)
+                .execute(&self.pool)
+                .await?;
+            }
+            Err(e) => self.fail_agent_job(&job, &e).await?,
+        }
+        Ok(true)
+    }
+
+    /// keep the job's lease while it's processed, until the heartbeat is dropped
+    fn spawn_job_heartbeat(&self, job_id: i64) -> JobHeartbeat {
+        let state = self.clone();
+        JobHeartbeat(tokio::spawn(async move {
+            let mut interval = tokio::time::interval(JOB_HEARTBEAT);
+            // the first tick is right away, the job was just claimed
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                if let Err(e) = state.renew_agent_job_lease(job_id).await {
+                    warn!("renew lease of agent job {job_id} failed: {e}");
+                }
+            }
+        }))
+    }
+
+    async fn renew_agent_job_lease(&self, job_id: i64) -> Result<(), AppError> {
+        sqlx::query(
+            r#"
+            UPDATE agent_jobs SET locked_at = CURRENT_TIMESTAMP, updated_at = CURRENT_TIMESTAMP
+            WHERE id = $1 AND status = 'running'
+            "#,
+        )
+        .bind(job_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// run the agents on the message of the job. A message deleted meanwhile has
+    /// nothing left to process, retrying wouldn't bring it back
+    async fn process_agent_job(&self, job: &AgentJob) -> Result<(), AppError> {
+        let message: Option<Message> = query_as("SELECT * FROM messages WHERE id = $1")
+            .bind(job.message_id)
+            .fetch_optional(&self.pool)
+            .await?;
+        let Some(message) = message else {
+            info!("message {} of agent job {} is gone", job.message_id, job.id);
+            return Ok(());
+        };
+        self.process_message_agents(&message, job.ws_id as _).await
+    }
+
+    /// retry the job later with exponential backoff, or dead-letter it
+    async fn fail_agent_job(&self, job: &AgentJob, error: &AppError) -> Result<(), AppError> {
+        let dead = job.attempts >= job.max_attempts;
+        warn!(
+            "agent job {} failed, attempt {}/{}: {error}",
+            job.id, job.attempts, job.max_attempts
+        );
+        let delay = JOB_RETRY_BASE_SECS * 2i32.pow(job.attempts.max(1) as u32 - 1);
+        sqlx::query(
+            r#"
+            UPDATE agent_jobs SET status = $2, last_error = $3, locked_at = NULL,
+                run_at = CURRENT_TIMESTAMP + make_interval(secs => $4),
+                updated_at = CURRENT_TIMESTAMP
+            WHERE id = $1
+            "#,
+        )
+        .bind(job.id)
+        .bind(if dead {
+            JobStatus::Dead
+        } else {
+            JobStatus::Pending
+        })
+        .bind(error.to_string())
+        .bind(delay)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// jobs of the workspace with the status, newest first. Owner only
+    pub async fn list_agent_jobs(
+        &self,
+        ws_id: u64,
+        user_id: u64,
+        input: ListJobs,
+    ) -> Result<Vec<AgentJob>, AppError> {
+        self.check_workspace_owner(ws_id, user_id).await?;
+        let jobs = query_as(
+            r#"
+            SELECT * FROM agent_jobs WHERE ws_id = $1 AND status = $2 AND id < $3
+            ORDER BY id DESC LIMIT 100
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(input.status.unwrap_or(JobStatus::Dead))
+        .bind(input.last_id.unwrap_or(i64::MAX as _) as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(jobs)
+    }
+
+    /// queue a dead job again with fresh attempts. Owner only
+    pub async fn retry_agent_job(
+        &self,
+        ws_id: u64,
+        user_id: u64,
+        job_id: u64,
+    ) -> Result<AgentJob, AppError> {
+        self.check_workspace_owner(ws_id, user_id).await?;
+        let job = query_as(
+            r#"
+            UPDATE agent_jobs SET status = 'pending', attempts = 0, run_at = CURRENT_TIMESTAMP,
+                updated_at = CURRENT_TIMESTAMP
+            WHERE id = $1 AND ws_id = $2 AND status = 'dead'
+            RETURNING *
+            "#,
+        )
+        .bind(job_id as i64)
+        .bind(ws_id as i64)
+        .fetch_optional(&self.pool)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("dead agent job {job_id}")))?;
+
+        Ok(job)
+    }
+
+    /// start the workers processing the agent jobs
+    pub fn spawn_agent_workers(&self) -> Vec<JoinHandle<()>> {
+        let config = &self.config.agent;
+        let poll_interval = Duration::from_millis(config.poll_interval);
+        info!("starting {} agent workers", config.workers);
+        (0..config.workers)
+            .map(|_| {
+                let state = self.clone();
+                tokio::spawn(async move {
+                    loop {
+                        match state.run_next_agent_job().await {
+                            Ok(true) => continue,
+                            Ok(false) => {}
+                            Err(e) => warn!("agent worker failed: {e}"),
+                        }
+                        tokio::time::sleep(poll_interval).await;
+                    }
+                })
+            })
+            .collect()
+    }
+
+    /// process the queued jobs until none is due
+    #[cfg(test)]
+    pub(crate) async fn run_agent_jobs(&self) -> Result<(), AppError> {
+        while self.run_next_agent_job().await? {}
+        Ok(())
+    }
+}
+
+/// renews the lease of a running job until dropped, also when the worker panics
+struct JobHeartbeat(JoinHandle<()>);
+
+impl Drop for JobHeartbeat {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{CreateAgent, CreateMessage};
+    use anyhow::Result;
+    use chat_core::AgentArgs;
+    use chat_core::{AdapterType, AgentType};
+
+    #[tokio::test]
+    async fn agent_job_should_process_message() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = CreateAgent::new(
+            AdapterType::Mock,
+            "HELLO",
+            "shout",
+            AgentType::Proxy,
+            "Shout",
+            AgentArgs::default(),
+        );
+        state.create_agent(input, 3).await?;
+
+        // stored before the agents run
+        let message = state
+            .create_message(CreateMessage::new("hello", vec![]), 3, 1, 1)
+            .await?;
+        assert_eq!(message.modified_content, None);
+
+        let job = state
+            .claim_agent_job()
+            .await?
+            .expect("job should be queued");
+        assert_eq!(job.message_id, message.id);
+        assert_eq!(job.attempts, 1);
+        // a claimed job is not handed out twice
+        assert!(state.claim_agent_job().await?.is_none());
+        // nor once its lease is renewed
+        let expire = format!(
+            "UPDATE agent_jobs SET locked_at = CURRENT_TIMESTAMP - interval '{} seconds'",
+            JOB_LEASE_SECS + 1
+        );
+        sqlx::query(&expire).execute(&state.pool).await?;
+        state.renew_agent_job_lease(job.id).await?;
+        assert!(state.claim_agent_job().await?.is_none());
+        // only when the worker stopped renewing it
+        sqlx::query(&expire).execute(&state.pool).await?;
+        let job = state.claim_agent_job().await?.expect("lease is over");
+        assert_eq!(job.attempts, 2);
+
+        state.process_agent_job(&job).await?;
+        let message = state.get_message_by_id(message.id).await?.unwrap();
+        assert_eq!(message.modified_content.as_deref(), Some("HELLO"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn agent_job_of_deleted_message_should_be_done() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = CreateAgent::new(
+            AdapterType::Mock,
+            "echo",
+            "echo",
+            AgentType::Proxy,
+            "Echo",
+            AgentArgs::default(),
+        );
+        state.create_agent(input, 3).await?;
+        let message = state
+            .create_message(CreateMessage::new("hello", vec![]), 3, 1, 1)
+            .await?;
+        // the message is gone before the job runs
+        sqlx::query("DELETE FROM messages WHERE id = $1")
+            .bind(message.id)
+            .execute(&state.pool)
+            .await?;
+
+        assert!(state.run_next_agent_job().await?);
+        assert!(!state.run_next_agent_job().await?);
+        let status: JobStatus =
+            sqlx::query_scalar("SELECT status FROM agent_jobs WHERE message_id = $1")
+                .bind(message.id)
+                .fetch_one(&state.pool)
+                .await?;
+        assert_eq!(status, JobStatus::Done);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn failed_agent_job_should_be_dead_lettered() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = CreateAgent::new(
+            AdapterType::Mock,
+            "Hi",
+            "greet",
+            AgentType::Reply,
+            "Greet",
+            AgentArgs::default(),
+        );
+        let agent = state.create_agent(input, 3).await?;
+        // the reply can't be posted without a bot user
+        sqlx::query("UPDATE chat_agents SET bot_id = NULL WHERE id = $1")
+            .bind(agent.id)
+            .execute(&state.pool)
+            .await?;
+        state
+            .create_message(CreateMessage::new("hello", vec![]), 3, 1, 1)
+            .await?;
+
+        for _ in 0..state.config.agent.max_attempts {
+            assert!(state.run_next_agent_job().await?);
+            // make the retry due right away
+            sqlx::query("UPDATE agent_jobs SET run_at = CURRENT_TIMESTAMP")
+                .execute(&state.pool)
+                .await?;
+        }
+        assert!(!state.run_next_agent_job().await?);
+
+        // owner only
+        let ret = state.list_agent_jobs(1, 1, ListJobs::default()).await;
+        assert!(matches!(ret, Err(AppError::NotWorkspaceOwnerError { .. })));
+        state.update_workspace_owner(1, 1).await?;
+        let jobs = state.list_agent_jobs(1, 1, ListJobs::default()).await?;
+        assert_eq!(jobs.len(), 1);
+        assert_eq!(jobs[0].status, JobStatus::Dead);
+        assert_eq!(jobs[0].attempts, 3);
+        assert!(jobs[0].last_error.as_deref().unwrap().contains("bot user"));
+
+        let job = state.retry_agent_job(1, 1, jobs[0].id as _).await?;
+        assert_eq!(job.status, JobStatus::Pending);
+        assert_eq!(job.attempts, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unavailable_agent_should_retry_job() -> Result<()> {
+        use crate::models::StepDecision;
+        use ai_sdk::{AiService, OpenAiCompatAdapter, Resilience, ResilienceConfig};
+        use ai_sdk::{Message as AiMessage, ResilientAdapter};
+        use std::sync::Arc;
+
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = CreateAgent::new(
+            AdapterType::Mock,
+            "HELLO",
+            "shout",
+            AgentType::Proxy,
+            "Shout",
+            AgentArgs::default(),
+        );
+        let agent = state.create_agent(input, 3).await?;
+        // open the circuit of the agent's provider, nothing listens on port 1
+        let config = ResilienceConfig {
+            max_retries: 0,
+            failure_threshold: 1,
+            ..Default::default()
+        };
+        let resilience = Arc::new(Resilience::new(config));
+        let adapter = OpenAiCompatAdapter::new("http://127.0.0.1:1/v1", None, "llama3");
+        let adapter = ResilientAdapter::new(adapter, resilience.clone());
+        assert!(adapter.complete(&[AiMessage::user("hi")]).await.is_err());
+        state
+            .agent_resilience
+            .lock()
+            .unwrap()
+            .insert((agent.id, 0), resilience);
+
+        let message = state
+            .create_message(CreateMessage::new("hello", vec![]), 3, 1, 1)
+            .await?;
+        assert!(state.run_next_agent_job().await?);
+        let steps = state.list_agent_steps(3, message.id as _).await?;
+        assert_eq!(steps[0].decision, StepDecision::Failed);
+        let job: AgentJob = query_as("SELECT * FROM agent_jobs WHERE message_id = $1")
+            .bind(message.id)
+            .fetch_one(&state.pool)
+            .await?;
+        assert_eq!(job.status, JobStatus::Pending);
+        assert!(job.run_at > Utc::now());
+
+        // the provider is back, the failed agent runs again in place of its step
+        state.agent_resilience.lock().unwrap().clear();
+        sqlx::query("UPDATE agent_jobs SET run_at = CURRENT_TIMESTAMP")
+            .execute(&state.pool)
+            .await?;
+        state.run_agent_jobs().await?;
+        let message = state.get_message_by_id(message.id).await?.unwrap();
+        assert_eq!(message.modified_content.as_deref(), Some("HELLO"));
+        let steps = state.list_agent_steps(3, message.id as _).await?;
+        assert_eq!(steps.len(), 1);
+        assert_eq!(steps[0].decision, StepDecision::Modify);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn retried_agent_job_should_not_run_agents_again() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let agents = [
+            ("shout", AgentType::Proxy, "HELLO"),
+            ("greet", AgentType::Reply, "Hi"),
+        ];
+        let mut ids = vec![];
+        for (name, r#type, reply) in agents {
+            let input = CreateAgent::new(
+                AdapterType::Mock,
+                reply,
+                name,
+                r#type,
+                name,
+                AgentArgs::default(),
+            );
+            ids.push(state.create_agent(input, 3).await?.id);
+        }
+        let input = CreateAgent::new(
+            AdapterType::Mock,
+            "Hi",
+            "broken",
+            AgentType::Reply,
+            "broken",
+            AgentArgs::default(),
+        );
+        let broken = state.create_agent(input, 3).await?;
+        sqlx::query("UPDATE chat_agents SET bot_id = NULL WHERE id = $1")
+            .bind(broken.id)
+            .execute(&state.pool)
+            .await?;
+        let message = state
+            .create_message(CreateMessage::new("hello", vec![]), 3, 1, 1)
+            .await?;
+
+        for _ in 0..2 {
+            assert!(state.run_next_agent_job().await?);
+            sqlx::query("UPDATE agent_jobs SET run_at = CURRENT_TIMESTAMP")
+                .execute(&state.pool)
+                .await?;
+        }
+        let steps = state.list_agent_steps(3, message.id as _).await?;
+        for id in ids {
+            assert_eq!(steps.iter().filter(|s| s.agent_id == id).count(), 1);
+            let usage: i64 =
+                sqlx::query_scalar("SELECT count(*) FROM agent_usage WHERE agent_id = $1")
+                    .bind(id)
+                    .fetch_one(&state.pool)
+                    .await?;
+            assert_eq!(usage, 1);
+        }
+        let replies: i64 = sqlx::query_scalar(
+            "SELECT count(*) FROM messages WHERE chat_id = 3 AND content = 'Hi'",
+        )
+        .fetch_one(&state.pool)
+        .await?;
+        assert_eq!(replies, 1);
+        let message = state.get_message_by_id(message.id).await?.unwrap();
+        assert_eq!(message.modified_content.as_deref(), Some("HELLO"));
+        Ok(())
+    }
+}
@@ -0,0 +1,173 @@
+use super::search::{metadata_i64, score};
+use crate::{AppError, AppState};
+use chat_core::{
+    AgentContext, AgentType, ChatAgent, KnowledgeArgs, KnowledgeChunk, KnowledgeCollection,
+};
+use serde_json::Value;
+use sqlx::query_scalar;
+use std::{borrow::Cow, collections::HashSet};
+use swiftide_pgvector::{FilteredSimilarity, MetadataFilter};
+
+/// chunks a knowledge agent gets unless its args say otherwise
+const DEFAULT_TOP_K: usize = 5;
+const MAX_TOP_K: usize = 20;
+
+impl AppState {
+    /// the context with the chunks a knowledge agent retrieved for the content,
+    /// other agents get the context as is
+    pub(crate) async fn knowledge_context<'a>(
+        &self,
+        chat_agent: &ChatAgent,
+        ws_id: u64,
+        content: &str,
+        ctx: &'a AgentContext,
+    ) -> Result<Cow<'a, AgentContext>, AppError> {
+        if chat_agent.r#type != AgentType::Knowledge {
+            return Ok(Cow::Borrowed(ctx));
+        }
+        let args = chat_agent.args.knowledge.clone().unwrap_or_default();
+        let knowledge = self
+            .retrieve_knowledge(ws_id, chat_agent.chat_id as _, &args, content)
+            .await?;
+        Ok(Cow::Owned(AgentContext {
+            knowledge,
+            ..ctx.clone()
+        }))
+    }
+
+    /// the chunks of the collection closest to the question, best first. The chat
+    /// history is the one of the agent's chat, hidden messages and the question
+    /// itself are left out
+    pub(crate) async fn retrieve_knowledge(
+        &self,
+        ws_id: u64,
+        chat_id: u64,
+        args: &KnowledgeArgs,
+        question: &str,
+    ) -> Result<Vec<KnowledgeChunk>, AppError> {
+        let top_k = args.top_k.unwrap_or(DEFAULT_TOP_K).clamp(1, MAX_TOP_K);
+        // one more, in case the question itself is retrieved
+        let mut strategy = FilteredSimilarity::new(top_k as u64 + 1)
+            .with_filter(MetadataFilter::eq("ws_id", ws_id));
+        for filter in collection_filters(args.collection, chat_id) {
+            strategy = strategy.with_filter(filter);
+        }
+        let mut docs = self.retrieve_similar(question, strategy).await?;
+        docs.retain(|doc| doc.content() != question);
+        // messages deleted or hidden since they were indexed
+        let ids: Vec<i64> = docs
+            .iter()
+            .filter_map(|doc| metadata_i64(doc, "message_id"))
+            .collect();
+        let visible: HashSet<i64> =
+            query_scalar("SELECT id FROM messages WHERE id = ANY($1) AND NOT hidden")
+                .bind(&ids)
+                .fetch_all(&self.pool)
+                .await?
+                .into_iter()
+                .collect();
+        docs.retain(|doc| metadata_i64(doc, "message_id").is_none_or(|id| visible.contains(&id)));
+        // older messages and earlier chunks first among equally close ones
+        docs.sort_by(|a, b| {
+            let position = |doc| metadata_i64(doc, "message_id").or(metadata_i64(doc, "chunk"));
+            score(b)
+                .total_cmp(&score(a))
+                .then(position(a).cmp(&position(b)))
+        });
+
+        Ok(docs
+            .iter()
+            .take(top_k)
+            .map(|doc| KnowledgeChunk {
+                source: doc
+                    .metadata()
+                    .get("source")
+                    .and_then(Value::as_str)
+                    .unwrap_or_default()
+                    .to_string(),
+                content: doc.content().to_string(),
+                score: score(doc),
+            })
+            .collect())
+    }
+}
+
+/// the `collection` of a node's metadata, e.g. `chat_history`
+pub(crate) fn collection_name(collection: KnowledgeCollection) -> Value {
+    serde_json::to_value(collection).unwrap_or_default()
+}
+
+/// the nodes of the collection in the vector store, the chat history is the one of the chat
+fn collection_filters(collection: KnowledgeCollection, chat_id: u64) -> Vec<MetadataFilter> {
+    let mut filters = vec![MetadataFilter::eq(
+        "collection",
+        collection_name(collection),
+    )];
+    if collection == KnowledgeCollection::ChatHistory {
+        filters.push(MetadataFilter::eq("chat_id", chat_id));
+    }
+    filters
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{CreateAgent, CreateMessage};
+    use anyhow::Result;
+    use chat_core::{AdapterType, AgentArgs};
+
+    #[tokio::test]
+    async fn knowledge_agent_should_answer_with_sources() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        state.index_messages().await?;
+        let args = AgentArgs {
+            knowledge: Some(KnowledgeArgs {
+                collection: KnowledgeCollection::ChatHistory,
+                top_k: Some(2),
+            }),
+            ..Default::default()
+        };
+        let input = CreateAgent::new(
+            AdapterType::Mock,
+            "Ask them how they are [1]",
+            "support",
+            AgentType::Knowledge,
+            "You answer questions about the chat.",
+            args,
+        );
+        let agent = state.create_agent(input, 1).await?;
+
+        let message = state
+            .create_message(CreateMessage::new("how are you doing?", vec![]), 1, 1, 1)
+            .await?;
+        state.run_agent_jobs().await?;
+        let steps = state.list_agent_steps(1, message.id as _).await?;
+        let reply = steps[0].output.as_deref().unwrap();
+        let run = state
+            .list_agent_runs(1, Default::default())
+            .await?
+            .into_iter()
+            .find(|r| r.agent_id == agent.id)
+            .unwrap();
+        let system = &run.prompt[0].content;
+        assert!(system.contains("[1] message 3\nHow are you?"));
+        assert!(system.contains("[2] message 8\nHow are you?"));
+        assert!(!system.contains("how are you doing?"));
+        assert!(reply.starts_with("Ask them how they are [1]\n\nSources:\n[1] message 3"));
+        assert!(!reply.contains("[2]"));
+
+        // nothing ingested in the docs yet
+        let chunks = state
+            .retrieve_knowledge(1, 1, &KnowledgeArgs::default(), "how are you")
+            .await?;
+        assert!(chunks.is_empty());
+        // nor the history of another chat
+        let args = KnowledgeArgs {
+            collection: KnowledgeCollection::ChatHistory,
+            top_k: None,
+        };
+        let chunks = state.retrieve_knowledge(1, 2, &args, "how are you").await?;
+        assert!(chunks.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,244 @@
+use crate::{AppError, AppState};
+use chat_core::{Label, LabelAction, Message};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgConnection};
+use utoipa::{IntoParams, ToSchema};
+
+/// a label a tap agent put on a message and the action its policy took
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, FromRow, ToSchema)]
+pub struct MessageLabel {
+    pub id: i64,
+    pub message_id: i64,
+    pub chat_id: i64,
+    pub agent_id: i64,
+    pub label: String,
+    pub score: Option<f32>,
+    pub action: LabelAction,
+    pub created_at: DateTime<Utc>,
+}
+
+/// a labelled message to review
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, FromRow, ToSchema)]
+pub struct LabelledMessage {
+    #[sqlx(flatten)]
+    #[serde(flatten)]
+    pub label: MessageLabel,
+    pub sender_id: i64,
+    pub content: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, IntoParams, ToSchema)]
+pub struct ListLabels {
+    /// defaults to flagged labels
+    #[serde(default)]
+    pub action: Option<LabelAction>,
+    /// labels with a smaller id, for paging
+    #[serde(default)]
+    pub last_id: Option<u64>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct CreateLabel {
+    pub agent_id: i64,
+    pub label: Label,
+    pub action: LabelAction,
+}
+
+impl AppState {
+    /// store the labels of the taps with the message
+    pub(crate) async fn create_message_labels(
+        &self,
+        conn: &mut PgConnection,
+        message: &Message,
+        labels: &[CreateLabel],
+    ) -> Result<(), AppError> {
+        for label in labels {
+            sqlx::query(
+                r#"
+                INSERT INTO message_labels (message_id, chat_id, agent_id, label, score, action)
+                VALUES ($1, $2, $3, $4, $5, $6)
+                "#,
+            )
+            .bind(message.id)
+            .bind(message.chat_id)
+            .bind(label.agent_id)
+            .bind(&label.label.name)
+            .bind(label.label.score)
+            .bind(label.action)
+            .execute(&mut *conn)
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// labels of a message, in the order they were put on it
+    pub async fn list_message_labels(
+        &self,
+        message_id: u64,
+    ) -> Result<Vec<MessageLabel>, AppError> {
+        let labels =
+            sqlx::query_as("SELECT * FROM message_labels WHERE message_id = $1 ORDER BY id ASC")
+                .bind(message_id as i64)
+                .fetch_all(&self.pool)
+                .await?;
+
+        Ok(labels)
+    }
+
+    /// labelled messages of the workspace with the action, newest first. Owner only
+    pub async fn list_labelled_messages(
+        &self,
+        ws_id: u64,
+        user_id: u64,
+        input: ListLabels,
+    ) -> Result<Vec<LabelledMessage>, AppError> {
+        self.check_workspace_owner(ws_id, user_id).await?;
+        let messages = sqlx::query_as(
+            r#"
+            SELECT l.*, m.sender_id, m.content
+            FROM message_labels l
+            JOIN messages m ON m.id = l.message_id
+            JOIN chats c ON c.id = l.chat_id
+            WHERE c.ws_id = $1 AND l.action = $2 AND l.id < $3
+            ORDER BY l.id DESC LIMIT 100
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(input.action.unwrap_or(LabelAction::Flag))
+        .bind(input.last_id.unwrap_or(i64::MAX as _) as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(messages)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{CreateAgent, CreateMessage, ListMessage};
+    use anyhow::Result;
+    use chat_core::AgentArgs;
+    use chat_core::{AdapterType, AgentType, LabelRule};
+
+    fn rule(label: &str, action: LabelAction, min_score: Option<f32>) -> LabelRule {
+        LabelRule {
+            label: label.to_string(),
+            action,
+            min_score,
+        }
+    }
+
+    async fn create_moderator(state: &AppState, fixture: &str) -> Result<()> {
+        let path = std::env::temp_dir().join(format!("moderator-{}.json", std::process::id()));
+        std::fs::write(&path, fixture)?;
+        let input = CreateAgent {
+            policy: vec![
+                rule("spam", LabelAction::Delete, Some(0.8)),
+                rule("pii", LabelAction::Hide, None),
+                rule("toxicity", LabelAction::Flag, None),
+            ],
+            ..CreateAgent::new(
+                AdapterType::Mock,
+                "",
+                "moderator",
+                AgentType::Tap,
+                "Label spam, pii and toxicity",
+                AgentArgs::default(),
+            )
+        };
+        let agent = state.create_agent(input, 1).await?;
+        // fixtures can't be set through the API
+        sqlx::query("UPDATE chat_agents SET model = $1 WHERE id = $2")
+            .bind(format!("fixture:{}", path.display()))
+            .bind(agent.id)
+            .execute(&state.pool)
+            .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tap_policy_should_reject_hide_and_flag() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let fixture = r#"[
+            {"prompt": "buy now", "response": "{\"labels\": [{\"name\": \"spam\", \"score\": 0.95}]}"},
+            {"prompt": "maybe buy", "response": "{\"labels\": [{\"name\": \"spam\", \"score\": 0.5}]}"},
+            {"prompt": "cheap pills", "response": "spam"},
+            {"prompt": "my phone", "response": "{\"labels\": [\"pii\"]}"},
+            {"prompt": "idiot", "response": "toxicity"},
+            {"prompt": "", "response": "none"}
+        ]"#;
+        create_moderator(&state, fixture).await?;
+
+        let ret = state
+            .create_message(CreateMessage::new("buy now!", vec![]), 1, 1, 1)
+            .await;
+        assert!(
+            matches!(ret, Err(AppError::MessageRejected { agent, label }) if agent == "moderator" && label == "spam")
+        );
+
+        // below the min score, only stored
+        let message = state
+            .create_message(CreateMessage::new("maybe buy?", vec![]), 1, 1, 1)
+            .await?;
+        let labels = state.list_message_labels(message.id as _).await?;
+        assert_eq!(labels.len(), 1);
+        assert_eq!(labels[0].action, LabelAction::None);
+        // so is a label without a score
+        let message = state
+            .create_message(CreateMessage::new("cheap pills", vec![]), 1, 1, 1)
+            .await?;
+        let labels = state.list_message_labels(message.id as _).await?;
+        assert_eq!(labels[0].action, LabelAction::None);
+
+        let hidden = state
+            .create_message(CreateMessage::new("my phone is 123", vec![]), 1, 1, 1)
+            .await?;
+        assert!(hidden.hidden);
+        let messages = state.list_message(ListMessage::new(None, 0), 1).await?;
+        assert!(messages.iter().all(|m| m.id != hidden.id));
+
+        let flagged = state
+            .create_message(CreateMessage::new("you idiot", vec![]), 1, 1, 1)
+            .await?;
+        assert!(!flagged.hidden);
+        let message = state
+            .create_message(CreateMessage::new("hello", vec![]), 1, 1, 1)
+            .await?;
+        assert!(state.list_message_labels(message.id as _).await?.is_empty());
+
+        state.update_workspace_owner(1, 1).await?;
+        let review = state
+            .list_labelled_messages(1, 1, ListLabels::default())
+            .await?;
+        assert_eq!(review.len(), 1);
+        assert_eq!(review[0].label.message_id, flagged.id);
+        assert_eq!(review[0].content, "you idiot");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tap_should_see_the_chat() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = CreateAgent::new(
+            AdapterType::Mock,
+            "none",
+            "observer",
+            AgentType::Tap,
+            "Label the messages of #{{ chat.name }} after:\n{{ history }}",
+            AgentArgs::default(),
+        );
+        let agent = state.create_agent(input, 1).await?;
+        state
+            .create_message(CreateMessage::new("hello", vec![]), 1, 1, 1)
+            .await?;
+
+        let runs = state.list_agent_runs(1, Default::default()).await?;
+        let run = runs.iter().find(|r| r.agent_id == agent.id).unwrap();
+        let system = &run.prompt[0].content;
+        assert!(system.starts_with("Label the messages of #general after:\n"));
+        assert!(system.contains("Hello, world!"));
+        Ok(())
+    }
+}
@@ -1,9 +1,19 @@
-use super::ChatFile;
-use crate::{agent::AgentVariant, AppError, AppState};
-use chat_core::{Agent, AgentContext, AgentDecision, ChatType, Message};
+use super::{
+    pipeline::{CreateAgentStep, PendingReply, StepDecision},
+    run::{AgentOutcome, CreateAgentRun},
+    ChatFile,
+};
+use crate::{
+    agent::{estimate_tokens, AgentVariant, CallUsage, ChatTools, ReplyAgent},
+    AppError, AppState,
+};
+use ai_sdk::{CompletionChunk, Usage};
+use chat_core::{AgentContext, AgentError, AgentType, ChatAgent, LabelAction, Message};
+use chrono::Utc;
+use futures::StreamExt;
 use serde::{Deserialize, Serialize};
 use sqlx::query_as;
-use std::str::FromStr;
+use std::{str::FromStr, time::Instant};
 use tracing::warn;
 use utoipa::{IntoParams, ToSchema};
 
@@ -12,6 +22,9 @@ pub struct CreateMessage {
     pub content: String,
     #[serde(default)]
     pub files: Vec<String>,
+    /// id of the message in the same chat this one replies to
+    #[serde(default)]
+    pub reply_to: Option<u64>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, ToSchema, IntoParams)]
@@ -55,57 +68,403 @@ impl AppState {
             }
         }
 
-        // if we have gent, apply it and get the result
-        let mut agents = self.list_agents(chat_id).await?;
-        let decision = if let Some(agent) = agents.pop() {
-            let agent: AgentVariant = agent.into();
-            agent.process(&input.content, &AgentContext::new()).await?
-        } else {
-            AgentDecision::None
-        };
+        // verify the replied message is in the chat
+        if let Some(id) = input.reply_to {
+            let exists: bool = sqlx::query_scalar(
+                "SELECT EXISTS (SELECT 1 FROM messages WHERE id = $1 AND chat_id = $2)",
+            )
+            .bind(id as i64)
+            .bind(chat_id as i64)
+            .fetch_one(&self.pool)
+            .await?;
+            if !exists {
+                return Err(AppError::CreateMessageError(format!(
+                    "Message {id} to reply to doesn't exist"
+                )));
+            }
+        }
 
-        let modified_content = match decision {
-            AgentDecision::Modify(ref s) => Some(s),
-            _ => None,
+        // the taps classify the message first, their policies may reject or hide it.
+        // A draft after the latest message of the chat
+        let draft = Message {
+            id: i64::MAX,
+            chat_id: chat_id as _,
+            sender_id: user_id as _,
+            content: input.content,
+            modified_content: None,
+            files: input.files,
+            reply_to: input.reply_to.map(|id| id as _),
+            hidden: false,
+            created_at: Utc::now(),
         };
+        let agents = self.triggered_agents(&draft).await?;
+        let taps = self.run_tap_agents(&draft, &agents, ws_id).await?;
+        if let Some(agent) = taps.deleted_by.clone() {
+            self.record_agent_steps(chat_id, None, &taps.steps).await;
+            let label = taps.rejected_label().unwrap_or_default().to_string();
+            return Err(AppError::MessageRejected { agent, label });
+        }
+        let hidden = taps.label_action() == LabelAction::Hide;
 
-        // crate message
+        // store the message right away, the other triggered agents of the chat
+        // process it in the background and the results arrive through the pg_notify
+        // triggers
+        let mut tx = self.pool.begin().await?;
         let message: Message = query_as(
-            "INSERT INTO messages (chat_id, sender_id, content, modified_content, files) VALUES ($1, $2, $3, $4, $5) RETURNING *",
+            r#"
+            INSERT INTO messages (chat_id, sender_id, content, files, reply_to, hidden)
+            VALUES ($1, $2, $3, $4, $5, $6) RETURNING *
+            "#,
         )
-        .bind(chat_id as i64)
-        .bind(user_id as i64)
-        .bind(&input.content)
-        .bind(&modified_content)
-        .bind(&input.files)
-        .fetch_one(&self.pool)
+        .bind(draft.chat_id)
+        .bind(draft.sender_id)
+        .bind(&draft.content)
+        .bind(&draft.files)
+        .bind(draft.reply_to)
+        .bind(hidden)
+        .fetch_one(&mut *tx)
         .await?;
+        self.create_message_labels(&mut tx, &message, &taps.labels)
+            .await?;
+        if !hidden && agents.iter().any(|a| a.r#type != AgentType::Tap) {
+            self.enqueue_agent_job(&mut *tx, &message, ws_id).await?;
+        }
+        tx.commit().await?;
+        self.record_agent_steps(chat_id, Some(message.id), &taps.steps)
+            .await;
+
+        Ok(message)
+    }
+
+    /// run the agents of the chat on a stored message. A delete by any of them
+    /// removes the message, otherwise the modified content is saved and the replies
+    /// are streamed one after another
+    pub(crate) async fn process_message_agents(
+        &self,
+        message: &Message,
+        ws_id: u64,
+    ) -> Result<(), AppError> {
+        let chat_id = message.chat_id as u64;
+        let mut pipeline = self.run_agent_pipeline(message, ws_id).await?;
+        // the step of a reply is recorded once the reply is posted, so a retry of the
+        // job doesn't post it twice
+        let (reply_steps, steps): (Vec<_>, Vec<_>) = std::mem::take(&mut pipeline.steps)
+            .into_iter()
+            .partition(|step| step.decision == StepDecision::Reply);
+        if pipeline.deleted_by.is_some() {
+            self.record_agent_steps(chat_id, Some(message.id), &steps)
+                .await;
+            sqlx::query("DELETE FROM messages WHERE id = $1")
+                .bind(message.id)
+                .execute(&self.pool)
+                .await?;
+            self.forget_message(message.id).await?;
+            return Ok(());
+        }
+
+        if let Some(content) = pipeline.modified_content() {
+            sqlx::query("UPDATE messages SET modified_content = $1 WHERE id = $2")
+                .bind(content)
+                .bind(message.id)
+                .execute(&self.pool)
+                .await?;
+        }
+
+        self.record_agent_steps(chat_id, Some(message.id), &steps)
+            .await;
 
         // if decision is reply, create a new message
-        if let AgentDecision::Reply(reply) = decision {
-            let chat = self
-                .get_chat_by_id(chat_id, ws_id)
-                .await?
-                .expect("chat should exist");
-            if chat.r#type != ChatType::Single {
-                warn!("reply decision found in non single chat {chat_id}. reply: {reply}")
+        for (chat_agent, reply) in &pipeline.replies {
+            self.create_reply_message(chat_agent, reply).await?;
+            let steps: Vec<_> = reply_steps
+                .iter()
+                .filter(|step| step.agent_id == chat_agent.id)
+                .cloned()
+                .collect();
+            self.record_agent_steps(chat_id, Some(message.id), &steps)
+                .await;
+        }
+
+        // create the reply messages up front and fill them in as deltas arrive
+        let mut failure = None;
+        for pending in pipeline.pending_replies {
+            let PendingReply {
+                chat_agent,
+                agent,
+                position,
+            } = pending;
+            let reply = self.create_reply_message(&chat_agent, "").await?;
+            let tools = ChatTools::new(self.clone(), chat_id, ws_id);
+            let messages = agent.conversation(&pipeline.content, &pipeline.ctx);
+            let mut run = CreateAgentRun::new(&pipeline.content, &messages);
+            let mut usage = CallUsage::default();
+            let start = Instant::now();
+            let ret = self
+                .stream_reply(
+                    &agent,
+                    &pipeline.content,
+                    &pipeline.ctx,
+                    &tools,
+                    &reply,
+                    &mut usage,
+                )
+                .await;
+            run.latency_ms = start.elapsed().as_millis() as _;
+            // tokens streamed before a failure are used all the same
+            if usage.usage != Usage::default() {
+                self.record_usage(&chat_agent, ws_id, &usage).await;
+            }
+            let (decision, output, error) = match ret {
+                Ok(output) => {
+                    run.response = Some(output.clone());
+                    (StepDecision::Reply, Some(output), None)
+                }
+                Err(e) => {
+                    warn!("stream reply {} failed: {e}", reply.id);
+                    self.discard_reply(reply.id).await?;
+                    let error = e.to_string();
+                    if e.is_transient() {
+                        failure.get_or_insert(e);
+                    }
+                    (StepDecision::Failed, None, Some(error))
+                }
+            };
+            let failed = decision == StepDecision::Failed;
+            let step = CreateAgentStep {
+                agent_id: chat_agent.id,
+                position,
+                decision,
+                output,
+                error,
+                run: Some(run),
+            };
+            self.record_agent_steps(chat_id, Some(message.id), &[step])
+                .await;
+            if !failed {
+                self.append_message_delta(reply.id as _, "", true).await?;
             }
-            let other_user_id = chat
-                .members
-                .into_iter()
-                .find(|m| m != &(user_id as i64))
-                .expect("other user");
-            sqlx::query(
-                "INSERT INTO messages (chat_id, sender_id, content, files) VALUES ($1, $2, $3, $4)",
-            )
-            .bind(chat_id as i64)
-            .bind(other_user_id as i64)
-            .bind(reply)
-            .bind(&input.files)
-            .execute(&self.pool)
-            .await?;
         }
 
+        // the job is retried, failed replies are streamed again in place of their steps
+        match failure {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// create the agent's reply, sent by its bot user
+    async fn create_reply_message(
+        &self,
+        chat_agent: &ChatAgent,
+        reply: &str,
+    ) -> Result<Message, AppError> {
+        let bot_id = chat_agent
+            .bot_id
+            .ok_or_else(|| AppError::NotFound(format!("bot user of agent {}", chat_agent.id)))?;
+        let message = query_as(
+            "INSERT INTO messages (chat_id, sender_id, content) VALUES ($1, $2, $3) RETURNING *",
+        )
+        .bind(chat_agent.chat_id)
+        .bind(bot_id)
+        .bind(reply)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(message)
+    }
+
+    /// stream the reply into the message and return the whole reply. The usage
+    /// reported along the way is added up even if the stream fails
+    async fn stream_reply(
+        &self,
+        agent: &ReplyAgent,
+        content: &str,
+        ctx: &AgentContext,
+        tools: &ChatTools,
+        reply: &Message,
+        usage: &mut CallUsage,
+    ) -> Result<String, AppError> {
+        let mut stream = agent.process_stream(content, ctx, tools).await?;
+        let mut output = String::new();
+        while let Some(chunk) = stream.next().await {
+            match chunk.map_err(AgentError::from)? {
+                CompletionChunk::Delta(delta) => {
+                    self.append_message_delta(reply.id as _, &delta, false)
+                        .await?;
+                    output.push_str(&delta);
+                }
+                CompletionChunk::Usage(u) => usage.usage += u,
+                CompletionChunk::Provider(provider) => usage.provider = provider,
+            }
+        }
+        Ok(output)
+    }
+
+    /// run the agent, record its usage and keep the model call for the run log.
+    /// Knowledge agents retrieve their sources first
+    pub(crate) async fn apply_agent(
+        &self,
+        agent: &AgentVariant,
+        chat_agent: &ChatAgent,
+        ws_id: u64,
+        content: &str,
+        ctx: &AgentContext,
+    ) -> AgentOutcome {
+        let ctx = match self
+            .knowledge_context(chat_agent, ws_id, content, ctx)
+            .await
+        {
+            Ok(ctx) => ctx,
+            Err(e) => {
+                let run = CreateAgentRun::new(content, &agent.messages(content, ctx));
+                return AgentOutcome {
+                    result: Err(e),
+                    run,
+                };
+            }
+        };
+        let ctx = ctx.as_ref();
+        let mut run = CreateAgentRun::new(content, &agent.messages(content, ctx));
+        let start = Instant::now();
+        let ret = agent.call(content, ctx).await;
+        run.latency_ms = start.elapsed().as_millis() as _;
+        let result = match ret {
+            Ok(call) => {
+                self.record_usage(chat_agent, ws_id, &call.usage).await;
+                run.response = Some(call.response);
+                Ok(call.decision)
+            }
+            Err(e) => Err(e.into()),
+        };
+        AgentOutcome { result, run }
+    }
+
+    /// the chat, the sender and its members, and the latest messages before the
+    /// message within the configured history limits
+    pub(crate) async fn agent_context(
+        &self,
+        message: &Message,
+        ws_id: u64,
+    ) -> Result<AgentContext, AppError> {
+        let chat_id = message.chat_id;
+        let chat = self
+            .get_chat_by_id(chat_id as _, ws_id)
+            .await?
+            .ok_or_else(|| AppError::NotFound(format!("chat {chat_id}")))?;
+        let members = self.fetch_chat_user_by_ids(&chat.members).await?;
+        let sender = members.iter().find(|u| u.id == message.sender_id).cloned();
+
+        // replies still being streamed have no content yet
+        let config = &self.config.agent;
+        let latest: Vec<Message> = query_as(
+            r#"
+            SELECT * FROM messages WHERE chat_id = $1 AND id < $2 AND content <> '' AND NOT hidden
+            ORDER BY id DESC LIMIT $3
+            "#,
+        )
+        .bind(chat_id)
+        .bind(message.id)
+        .bind(config.history_messages as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let bots = self.list_agent_bots(chat_id as _).await?;
+        Ok(AgentContext {
+            chat_id,
+            chat_name: chat.name,
+            sender,
+            members,
+            bots,
+            history: fit_history(latest, config.history_tokens),
+            knowledge: vec![],
+        })
+    }
+
+    /// whether the workspace budget and its rate limit allow calling the agent.
+    /// Otherwise the message is stored without running the agent
+    pub(crate) async fn agent_within_quota(
+        &self,
+        agent: &ChatAgent,
+        ws_id: u64,
+    ) -> Result<bool, AppError> {
+        match self.check_agent_quota(agent, ws_id).await {
+            Ok(()) => Ok(true),
+            Err(e @ AppError::QuotaExceeded(_)) => {
+                warn!("agent {} skipped: {e}", agent.id);
+                Ok(false)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// usage is only recorded for reporting, failing to do so doesn't fail the message
+    pub(crate) async fn record_usage(&self, agent: &ChatAgent, ws_id: u64, usage: &CallUsage) {
+        if let Err(e) = self.record_agent_usage(agent, ws_id, usage).await {
+            warn!("record usage of agent {} failed: {e}", agent.id);
+        }
+    }
+
+    /// append the delta to the message content and notify the chat members
+    pub(crate) async fn append_message_delta(
+        &self,
+        message_id: u64,
+        delta: &str,
+        done: bool,
+    ) -> Result<(), AppError> {
+        sqlx::query(
+            r#"
+            WITH updated AS (
+                UPDATE messages SET content = content || $1 WHERE id = $2 RETURNING id, chat_id
+            )
+            SELECT pg_notify(
+                'chat_message_delta',
+                json_build_object(
+                    'delta', json_build_object('id', u.id, 'chat_id', u.chat_id, 'delta', $1, 'done', $3),
+                    'members', c.members
+                )::text
+            )
+            FROM updated u JOIN chats c ON c.id = u.chat_id
+            "#,
+        )
+        .bind(delta)
+        .bind(message_id as i64)
+        .bind(done)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// remove a reply whose stream failed, the chat members drop what they got of it
+    async fn discard_reply(&self, message_id: i64) -> Result<(), AppError> {
+        sqlx::query(
+            r#"
+            WITH deleted AS (
+                DELETE FROM messages WHERE id = $1 RETURNING id, chat_id
+            )
+            SELECT pg_notify(
+                'chat_message_delta',
+                json_build_object(
+                    'delta', json_build_object('id', d.id, 'chat_id', d.chat_id, 'delta', '', 'done', true, 'failed', true),
+                    'members', c.members
+                )::text
+            )
+            FROM deleted d JOIN chats c ON c.id = d.chat_id
+            "#,
+        )
+        .bind(message_id)
+        .execute(&self.pool)
+        .await?;
+        self.forget_message(message_id).await?;
+
+        Ok(())
+    }
+
+    pub(crate) async fn get_message_by_id(&self, id: i64) -> Result<Option<Message>, AppError> {
+        let message = query_as("SELECT * FROM messages WHERE id = $1")
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?;
         Ok(message)
     }
 
@@ -119,6 +478,7 @@ impl AppState {
             .bind(chat_id as i64)
             .fetch_one(&self.pool)
             .await?;
+        self.forget_message(message.id).await?;
         Ok(message)
     }
 
@@ -136,7 +496,7 @@ impl AppState {
         };
 
         let messages: Vec<Message> = query_as(
-            "SELECT * FROM messages WHERE chat_id = $1 AND id < $2 ORDER BY id ASC LIMIT $3",
+            "SELECT * FROM messages WHERE chat_id = $1 AND id < $2 AND NOT hidden ORDER BY id ASC LIMIT $3",
         )
         .bind(chat_id as i64)
         .bind(last_id as i64)
@@ -146,6 +506,26 @@ impl AppState {
 
         Ok(messages)
     }
+
+    /// search messages in a chat whose content contains the query, newest first
+    pub async fn search_messages(
+        &self,
+        chat_id: u64,
+        query: &str,
+        limit: u64,
+    ) -> Result<Vec<Message>, AppError> {
+        let limit = limit.clamp(1, 100);
+        let messages: Vec<Message> = query_as(
+            "SELECT * FROM messages WHERE chat_id = $1 AND NOT hidden AND strpos(lower(content), lower($2)) > 0 ORDER BY id DESC LIMIT $3",
+        )
+        .bind(chat_id as i64)
+        .bind(query)
+        .bind(limit as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(messages)
+    }
 }
 
 #[cfg(test)]
@@ -154,6 +534,7 @@ impl CreateMessage {
         Self {
             content: content.into(),
             files: files.into_iter().map(|s| s.into()).collect(),
+            reply_to: None,
         }
     }
 }
@@ -165,6 +546,21 @@ impl ListMessage {
     }
 }
 
+/// keep the newest messages whose estimated tokens fit in the budget, oldest first.
+/// `latest` is ordered newest first
+fn fit_history(latest: Vec<Message>, max_tokens: usize) -> Vec<Message> {
+    let mut tokens = 0;
+    let mut history: Vec<Message> = latest
+        .into_iter()
+        .take_while(|m| {
+            tokens += estimate_tokens(&m.content);
+            tokens <= max_tokens
+        })
+        .collect();
+    history.reverse();
+    history
+}
+
 #[cfg(test)]
 impl DeleteMessage {
     pub fn new(message_id: u64) -> Self {
@@ -221,6 +617,259 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn agent_context_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let message = state
+            .create_message(CreateMessage::new("hi", vec![]), 1, 3, 1)
+            .await?;
+        let ctx = state.agent_context(&message, 1).await?;
+        assert_eq!(ctx.chat_id, 1);
+        assert_eq!(ctx.members.len(), 5);
+        assert_eq!(ctx.sender.map(|u| u.id), Some(3));
+        assert_eq!(ctx.history.len(), 10);
+        assert_eq!(ctx.history[0].content, "Hello, world!");
+        assert_eq!(ctx.history[1].content, "Hi, there!");
+
+        // "Hello, world!" is 4 tokens, "Hi, there!" 3
+        let history = fit_history(ctx.history.into_iter().rev().collect(), 8);
+        let contents: Vec<_> = history.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents, ["Hello, world!", "Hello, world!"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn proxy_agent_should_modify_message() -> Result<()> {
+        use crate::models::CreateAgent;
+        use chat_core::AgentArgs;
+        use chat_core::{AdapterType, AgentType};
+
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = CreateAgent::new(
+            AdapterType::Mock,
+            "Hello, hard work today",
+            "translator",
+            AgentType::Proxy,
+            "Translate to English",
+            AgentArgs::default(),
+        );
+        state.create_agent(input, 3).await?;
+
+        let message = state
+            .create_message(CreateMessage::new("您好, 今天工作辛苦了", vec![]), 3, 1, 1)
+            .await?;
+        // stored as is, the agents run in the background
+        assert_eq!(message.modified_content, None);
+        state.run_agent_jobs().await?;
+        let message = state.get_message_by_id(message.id).await?.unwrap();
+        assert_eq!(message.content, "您好, 今天工作辛苦了");
+        assert_eq!(
+            message.modified_content.as_deref(),
+            Some("Hello, hard work today")
+        );
+
+        let report = state.get_usage_report(1, Default::default()).await?.total;
+        assert_eq!(report.calls, 1);
+        assert_eq!(report.completion_tokens, 4);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reply_agent_should_stream_into_reply_message() -> Result<()> {
+        use crate::models::CreateAgent;
+        use chat_core::AgentArgs;
+        use chat_core::{AdapterType, AgentType};
+
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = CreateAgent::new(
+            AdapterType::Mock,
+            "Hi there",
+            "greeter",
+            AgentType::Reply,
+            "",
+            AgentArgs::default(),
+        );
+        // a group chat, the reply is sent by the agent's bot
+        let agent = state.create_agent(input, 4).await?;
+
+        let message = state
+            .create_message(CreateMessage::new("hello", vec![]), 4, 1, 1)
+            .await?;
+        assert_eq!(message.content, "hello");
+
+        state.run_agent_jobs().await?;
+        let messages = state.list_message(ListMessage::new(None, 0), 4).await?;
+        let reply = messages.into_iter().find(|m| m.id > message.id);
+        let reply = reply.expect("reply should be created");
+        assert_eq!(Some(reply.sender_id), agent.bot_id);
+        assert_eq!(reply.content, "Hi there");
+
+        // "nyh@gmail.com: hello"
+        let report = state.get_usage_report(1, Default::default()).await?.total;
+        assert_eq!(report.calls, 1);
+        assert_eq!(report.prompt_tokens, 2);
+        assert_eq!(report.completion_tokens, 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn failed_reply_should_be_discarded_and_retried() -> Result<()> {
+        use crate::models::{AgentJob, CreateAgent, JobStatus};
+        use ai_sdk::{AiService, OpenAiCompatAdapter, Resilience, ResilienceConfig};
+        use ai_sdk::{Message as AiMessage, ResilientAdapter};
+        use chat_core::{AdapterType, AgentArgs, AgentType};
+        use std::sync::Arc;
+
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = CreateAgent::new(
+            AdapterType::Mock,
+            "Hi there",
+            "greeter",
+            AgentType::Reply,
+            "Greet the sender",
+            AgentArgs::default(),
+        );
+        let agent = state.create_agent(input, 3).await?;
+        // open the circuit of the agent's provider, nothing listens on port 1
+        let config = ResilienceConfig {
+            max_retries: 0,
+            failure_threshold: 1,
+            ..Default::default()
+        };
+        let resilience = Arc::new(Resilience::new(config));
+        let adapter = OpenAiCompatAdapter::new("http://127.0.0.1:1/v1", None, "llama3");
+        let adapter = ResilientAdapter::new(adapter, resilience.clone());
+        assert!(adapter.complete(&[AiMessage::user("hi")]).await.is_err());
+        state
+            .agent_resilience
+            .lock()
+            .unwrap()
+            .insert((agent.id, 0), resilience);
+
+        let message = state
+            .create_message(CreateMessage::new("hello", vec![]), 3, 1, 1)
+            .await?;
+        assert!(state.run_next_agent_job().await?);
+        // the placeholder of the reply is removed
+        let messages = state.list_message(ListMessage::new(None, 0), 3).await?;
+        assert_eq!(messages.last().map(|m| m.id), Some(message.id));
+        let steps = state.list_agent_steps(3, message.id as _).await?;
+        assert_eq!(steps.len(), 1);
+        assert_eq!(steps[0].decision, StepDecision::Failed);
+        let job: AgentJob = query_as("SELECT * FROM agent_jobs WHERE message_id = $1")
+            .bind(message.id)
+            .fetch_one(&state.pool)
+            .await?;
+        assert_eq!(job.status, JobStatus::Pending);
+
+        // the retry streams the reply once
+        state.agent_resilience.lock().unwrap().clear();
+        sqlx::query("UPDATE agent_jobs SET run_at = CURRENT_TIMESTAMP")
+            .execute(&state.pool)
+            .await?;
+        state.run_agent_jobs().await?;
+        let messages = state.list_message(ListMessage::new(None, 0), 3).await?;
+        let replies: Vec<_> = messages.iter().filter(|m| m.id > message.id).collect();
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].content, "Hi there");
+        let steps = state.list_agent_steps(3, message.id as _).await?;
+        assert_eq!(steps.len(), 1);
+        assert_eq!(steps[0].decision, StepDecision::Reply);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn failing_agent_should_be_skipped() -> Result<()> {
+        use crate::{
+            agent::ProxyAgent,
+            models::{AgentJob, CreateAgent, JobStatus},
+        };
+        use ai_sdk::{
+            AiAdapter, CircuitState, OpenAiCompatAdapter, Resilience, ResilienceConfig,
+            ResilientAdapter,
+        };
+        use chat_core::{AdapterType, AgentArgs, AgentType};
+        use std::sync::Arc;
+
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = CreateAgent::new(
+            AdapterType::OpenaiCompat,
+            "llama3",
+            "broken",
+            AgentType::Proxy,
+            "Rewrite the message",
+            AgentArgs::default(),
+        );
+        let chat_agent = state.create_agent(input, 1).await?;
+        let config = ResilienceConfig {
+            max_retries: 0,
+            failure_threshold: 1,
+            ..Default::default()
+        };
+        state
+            .agent_resilience
+            .lock()
+            .unwrap()
+            .insert((chat_agent.id, 0), Arc::new(Resilience::new(config)));
+
+        // nothing listens on port 1
+        let adapter = OpenAiCompatAdapter::new("http://127.0.0.1:1/v1", None, "llama3");
+        let agent = AgentVariant::Proxy(ProxyAgent {
+            name: chat_agent.name.clone(),
+            adapter: ResilientAdapter::from(AiAdapter::from(adapter)).into(),
+            prompt: chat_agent.prompt.clone(),
+            user_prompt: chat_agent.user_prompt.clone(),
+            args: chat_agent.args.clone(),
+        })
+        .with_resilience(|i| state.agent_resilience(chat_agent.id, i));
+
+        let ret = state
+            .apply_agent(&agent, &chat_agent, 1, "hello", &AgentContext::new())
+            .await;
+        assert!(ret.result.is_err());
+
+        let metrics = state.list_agent_metrics(1).await?;
+        assert_eq!(metrics.len(), 1);
+        let provider = &metrics[0].providers[0];
+        assert_eq!(provider.metrics.failures, 1);
+        assert_eq!(provider.metrics.state, CircuitState::Open);
+
+        // the message is still sent, the failure is recorded as a step and the job
+        // is retried later
+        let message = state
+            .create_message(CreateMessage::new("hello", vec![]), 1, 1, 1)
+            .await?;
+        state.run_agent_jobs().await?;
+        let message = state.get_message_by_id(message.id).await?.unwrap();
+        assert_eq!(message.modified_content, None);
+        let steps = state.list_agent_steps(1, message.id as _).await?;
+        assert_eq!(steps.len(), 1);
+        assert_eq!(steps[0].decision, StepDecision::Failed);
+        let job: AgentJob = query_as("SELECT * FROM agent_jobs WHERE message_id = $1")
+            .bind(message.id)
+            .fetch_one(&state.pool)
+            .await?;
+        assert_eq!(job.status, JobStatus::Pending);
+        assert!(job.last_error.unwrap().contains("circuit open"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn search_messages_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let messages = state.search_messages(1, "hello", 10).await?;
+        assert_eq!(messages.len(), 4);
+        assert!(messages[0].id > messages[1].id);
+
+        let messages = state.search_messages(1, "HOW ARE", 1).await?;
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].content, "How are you?");
+
+        let messages = state.search_messages(2, "hello", 10).await?;
+        assert!(messages.is_empty());
+        Ok(())
+    }
+
     fn upload_dummy_file(state: &AppState) -> Result<String> {
         let file = ChatFile::new(1, "test.txt", b"hello world");
         let path = file.path(&state.config.server.base_dir);
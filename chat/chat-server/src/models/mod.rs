@@ -1,14 +1,32 @@
 mod agent;
+mod budget;
 mod chat;
 mod file;
+mod ingest;
+mod job;
+mod knowledge;
+mod label;
 mod message;
+mod pipeline;
+mod run;
+mod search;
+mod usage;
 mod user;
 mod workspace;
 
-pub use agent::{CreateAgent, UpdateAgent};
+pub use agent::{AgentMetrics, CreateAgent, TestAgent, UpdateAgent};
+pub use budget::{QuotaState, WorkspaceBudget};
 pub use chat::ParamChat;
+pub use ingest::{IngestFile, IngestStatus, KnowledgeFile, ListKnowledgeFiles};
+pub use job::{AgentJob, JobStatus, ListJobs};
+pub use label::{LabelledMessage, ListLabels, MessageLabel};
 pub use message::{CreateMessage, DeleteMessage, ListMessage};
+pub use pipeline::StepDecision;
+pub use run::{AgentRun, ListRuns, PromptMessage, RunReplay};
+pub(crate) use search::open_vector_store;
+pub use search::{MessageHit, SearchMessages, SearchMode};
 use serde::{Deserialize, Serialize};
+pub use usage::{AgentUsage, UsageQuery, UsageReport, UsageSummary};
 pub use user::{CreateUser, SigninUser};
 use utoipa::ToSchema;
 
@@ -0,0 +1,626 @@
+use super::{
+    label::CreateLabel,
+    run::{AgentOutcome, CreateAgentRun},
+};
+use crate::{
+    agent::{AgentVariant, ReplyAgent},
+    AppError, AppState,
+};
+use chat_core::{
+    AgentContext, AgentDecision, AgentError, AgentType, ChatAgent, LabelAction, Message,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use std::{collections::HashMap, time::Duration};
+use tokio::time::Instant;
+use tracing::warn;
+use utoipa::ToSchema;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, sqlx::Type, ToSchema)]
+#[sqlx(type_name = "agent_step_decision", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum StepDecision {
+    Modify,
+    Reply,
+    Delete,
+    /// labels of a tap
+    Label,
+    None,
+    /// not run, e.g. over quota or after a delete
+    Skipped,
+    Failed,
+}
+
+impl StepDecision {
+    /// the step of the decision and what it produced: the modified content, the
+    /// reply or the labels as JSON
+    pub fn with_output(decision: &AgentDecision) -> (Self, Option<String>) {
+        match decision {
+            AgentDecision::Modify(content) => (Self::Modify, Some(content.clone())),
+            AgentDecision::Reply(reply) => (Self::Reply, Some(reply.clone())),
+            AgentDecision::Delete => (Self::Delete, None),
+            AgentDecision::Labels(labels) => (Self::Label, serde_json::to_string(labels).ok()),
+            AgentDecision::None => (Self::None, None),
+        }
+    }
+}
+
+/// what an agent of the pipeline did with a message
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, FromRow, ToSchema)]
+pub struct AgentStep {
+    pub id: i64,
+    pub chat_id: i64,
+    pub message_id: Option<i64>,
+    pub agent_id: i64,
+    pub position: i32,
+    pub decision: StepDecision,
+    /// modified content or reply
+    pub output: Option<String>,
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct CreateAgentStep {
+    pub agent_id: i64,
+    pub position: i32,
+    pub decision: StepDecision,
+    pub output: Option<String>,
+    pub error: Option<String>,
+    /// the model call behind the step, if the agent ran
+    pub run: Option<CreateAgentRun>,
+}
+
+/// a reply agent to stream once the message is stored
+#[derive(Debug)]
+pub(crate) struct PendingReply {
+    pub chat_agent: ChatAgent,
+    pub agent: ReplyAgent,
+    pub position: i32,
+}
+
+/// outcome of running the agents of a chat on a message.
+///
+/// the taps classify the message before it's stored, their policies may reject or
+/// hide it. Precedence of the decisions: a delete wins over everything, the
+/// pipeline stops, the message is removed and nobody replies. Otherwise the proxies
+/// modify the content in order, each seeing the output of the previous one, and
+/// every reply agent answers the final content.
+#[derive(Debug, Default)]
+pub(crate) struct AgentPipeline {
+    /// content after the proxies
+    pub content: String,
+    pub modified: bool,
+    /// name of the agent which deleted the message
+    pub deleted_by: Option<String>,
+    /// replies decided by non streaming agents, with the agent replying
+    pub replies: Vec<(ChatAgent, String)>,
+    pub pending_replies: Vec<PendingReply>,
+    /// labels of the taps and the actions of their policies
+    pub labels: Vec<CreateLabel>,
+    pub ctx: AgentContext,
+    pub steps: Vec<CreateAgentStep>,
+}
+
+#[allow(dead_code)]
+impl AppState {
+    /// run the agents of the chat triggered by the message and within the workspace
+    /// quota on it, in pipeline order. Agents with a step for the message ran in an
+    /// earlier attempt of the job and aren't run again
+    pub(crate) async fn run_agent_pipeline(
+        &self,
+        message: &Message,
+        ws_id: u64,
+    ) -> Result<AgentPipeline, AppError> {
+        let mut pipeline = AgentPipeline::new(&message.content);
+        let agents = self.triggered_agents(message).await?;
+        if agents.is_empty() {
+            return Ok(pipeline);
+        }
+        pipeline.ctx = self.agent_context(message, ws_id).await?;
+        let done: HashMap<i64, AgentStep> = self
+            .list_agent_steps(message.chat_id as _, message.id as _)
+            .await?
+            .into_iter()
+            .map(|step| (step.agent_id, step))
+            .collect();
+
+        for (position, chat_agent) in agents.into_iter().enumerate() {
+            let position = position as i32;
+            // the taps ran before the message was stored
+            if chat_agent.r#type == AgentType::Tap {
+                continue;
+            }
+            if let Some(step) = done.get(&chat_agent.id) {
+                if step.decision != StepDecision::Failed {
+                    pipeline.replay(step);
+                    continue;
+                }
+                // failed in an earlier attempt, run again in its place
+                self.delete_agent_step(step.id).await?;
+            }
+            if pipeline.deleted_by.is_some() || !self.agent_within_quota(&chat_agent, ws_id).await?
+            {
+                pipeline.skip(&chat_agent, position);
+                continue;
+            }
+            let agent = match self.agent_variant(chat_agent.clone()) {
+                Ok(agent) => agent,
+                Err(e) => {
+                    pipeline.apply(&chat_agent, position, Err(e.into()));
+                    continue;
+                }
+            };
+            match agent {
+                AgentVariant::Replay(agent) => pipeline.pending_replies.push(PendingReply {
+                    chat_agent,
+                    agent,
+                    position,
+                }),
+                agent => {
+                    let outcome = self
+                        .apply_agent(&agent, &chat_agent, ws_id, &pipeline.content, &pipeline.ctx)
+                        .await;
+                    if let Some(e) = pipeline.apply_outcome(&chat_agent, position, outcome) {
+                        // the job is retried from this agent on. Replies are left out,
+                        // their messages aren't created yet
+                        let steps: Vec<_> = pipeline
+                            .steps
+                            .iter()
+                            .filter(|step| step.decision != StepDecision::Reply)
+                            .cloned()
+                            .collect();
+                        self.record_agent_steps(message.chat_id as _, Some(message.id), &steps)
+                            .await;
+                        return Err(e);
+                    }
+                }
+            }
+        }
+        Ok(pipeline)
+    }
+
+    /// run the taps among the agents on a message before it's stored, in pipeline
+    /// order. Failing taps don't hold the message back, nor do taps still running
+    /// once `tap_timeout` is over
+    pub(crate) async fn run_tap_agents(
+        &self,
+        message: &Message,
+        agents: &[ChatAgent],
+        ws_id: u64,
+    ) -> Result<AgentPipeline, AppError> {
+        let mut pipeline = AgentPipeline::new(&message.content);
+        if !agents.iter().any(|a| a.r#type == AgentType::Tap) {
+            return Ok(pipeline);
+        }
+        pipeline.ctx = self.agent_context(message, ws_id).await?;
+        let deadline = Instant::now() + Duration::from_millis(self.config.agent.tap_timeout);
+        for (position, chat_agent) in agents.iter().enumerate() {
+            let position = position as i32;
+            if chat_agent.r#type != AgentType::Tap {
+                continue;
+            }
+            if pipeline.deleted_by.is_some() || !self.agent_within_quota(chat_agent, ws_id).await? {
+                pipeline.skip(chat_agent, position);
+                continue;
+            }
+            let agent = match self.agent_variant(chat_agent.clone()) {
+                Ok(agent) => agent,
+                Err(e) => {
+                    pipeline.apply(chat_agent, position, Err(e.into()));
+                    continue;
+                }
+            };
+            let outcome = self
+                .apply_tap(
+                    &agent,
+                    chat_agent,
+                    ws_id,
+                    &message.content,
+                    &pipeline.ctx,
+                    deadline,
+                )
+                .await;
+            pipeline.apply_outcome(chat_agent, position, outcome);
+        }
+        Ok(pipeline)
+    }
+
+    /// apply the tap unless the deadline is over first
+    async fn apply_tap(
+        &self,
+        agent: &AgentVariant,
+        chat_agent: &ChatAgent,
+        ws_id: u64,
+        content: &str,
+        ctx: &AgentContext,
+        deadline: Instant,
+    ) -> AgentOutcome {
+        let start = Instant::now();
+        let apply = self.apply_agent(agent, chat_agent, ws_id, content, ctx);
+        match tokio::time::timeout_at(deadline, apply).await {
+            Ok(outcome) => outcome,
+            Err(_) => {
+                let mut run = CreateAgentRun::new(content, &agent.messages(content, ctx));
+                run.latency_ms = start.elapsed().as_millis() as _;
+                let e = AgentError::Network("tap timed out before the message was stored".into());
+                AgentOutcome {
+                    result: Err(e.into()),
+                    run,
+                }
+            }
+        }
+    }
+
+    /// steps are only recorded for inspection, failing to do so doesn't fail the message
+    pub(crate) async fn record_agent_steps(
+        &self,
+        chat_id: u64,
+        message_id: Option<i64>,
+        steps: &[CreateAgentStep],
+    ) {
+        for step in steps {
+            let ret = sqlx::query(
+                r#"
+                INSERT INTO agent_steps (chat_id, message_id, agent_id, position, decision, output, error)
+                VALUES ($1, $2, $3, $4, $5, $6, $7)
+                "#,
+            )
+            .bind(chat_id as i64)
+            .bind(message_id)
+            .bind(step.agent_id)
+            .bind(step.position)
+            .bind(step.decision)
+            .bind(&step.output)
+            .bind(&step.error)
+            .execute(&self.pool)
+            .await;
+            if let Err(e) = ret {
+                warn!("record step of agent {} failed: {e}", step.agent_id);
+            }
+            if let Some(run) = &step.run {
+                if let Err(e) = self.create_agent_run(chat_id, message_id, step, run).await {
+                    warn!("record run of agent {} failed: {e}", step.agent_id);
+                }
+            }
+        }
+    }
+
+    /// steps of the pipeline run for a message, in order
+    async fn delete_agent_step(&self, id: i64) -> Result<(), AppError> {
+        sqlx::query("DELETE FROM agent_steps WHERE id = $1")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn list_agent_steps(
+        &self,
+        chat_id: u64,
+        message_id: u64,
+    ) -> Result<Vec<AgentStep>, AppError> {
+        let steps = sqlx::query_as(
+            r#"
+            SELECT * FROM agent_steps WHERE chat_id = $1 AND message_id = $2
+            ORDER BY position ASC, id ASC
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(message_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(steps)
+    }
+}
+
+impl AgentPipeline {
+    fn new(content: &str) -> Self {
+        Self {
+            content: content.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// the modified content, if any proxy changed it
+    pub fn modified_content(&self) -> Option<&str> {
+        self.modified.then_some(self.content.as_str())
+    }
+
+    /// the most severe action the policies took on the labels
+    pub fn label_action(&self) -> LabelAction {
+        self.labels
+            .iter()
+            .map(|l| l.action)
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// the label which got the message deleted, if any
+    pub fn rejected_label(&self) -> Option<&str> {
+        self.labels
+            .iter()
+            .find(|l| l.action == LabelAction::Delete)
+            .map(|l| l.label.name.as_str())
+    }
+
+    fn delete(&mut self, chat_agent: &ChatAgent) {
+        self.deleted_by
+            .get_or_insert_with(|| chat_agent.name.clone());
+        self.replies.clear();
+        self.pending_replies.clear();
+    }
+
+    /// apply the decision and keep the model call with the step
+    /// returns the error of a transient failure, worth retrying the job for
+    fn apply_outcome(
+        &mut self,
+        chat_agent: &ChatAgent,
+        position: i32,
+        outcome: AgentOutcome,
+    ) -> Option<AppError> {
+        let transient = self.apply(chat_agent, position, outcome.result);
+        if let Some(step) = self.steps.last_mut() {
+            step.run = Some(outcome.run);
+        }
+        transient
+    }
+
+    /// returns the error of a transient failure, worth retrying the job for
+    fn apply(
+        &mut self,
+        chat_agent: &ChatAgent,
+        position: i32,
+        result: Result<AgentDecision, AppError>,
+    ) -> Option<AppError> {
+        let (decision, output, error, transient) = match result {
+            Ok(decision) => {
+                let (step, output) = StepDecision::with_output(&decision);
+                self.take(chat_agent, decision);
+                (step, output, None, None)
+            }
+            Err(e) => {
+                warn!("agent {} failed, skipped: {e}", chat_agent.id);
+                let error = Some(e.to_string());
+                (
+                    StepDecision::Failed,
+                    None,
+                    error,
+                    e.is_transient().then_some(e),
+                )
+            }
+        };
+        self.steps.push(CreateAgentStep {
+            agent_id: chat_agent.id,
+            position,
+            decision,
+            output,
+            error,
+            run: None,
+        });
+        transient
+    }
+
+    /// act on the decision of the agent
+    fn take(&mut self, chat_agent: &ChatAgent, decision: AgentDecision) {
+        match decision {
+            AgentDecision::Modify(content) => {
+                self.content = content;
+                self.modified = true;
+            }
+            AgentDecision::Reply(reply) => self.replies.push((chat_agent.clone(), reply)),
+            AgentDecision::Delete => self.delete(chat_agent),
+            AgentDecision::Labels(labels) => {
+                for label in labels {
+                    let action = chat_agent.label_action(&label);
+                    if action == LabelAction::Delete {
+                        self.delete(chat_agent);
+                    }
+                    self.labels.push(CreateLabel {
+                        agent_id: chat_agent.id,
+                        label,
+                        action,
+                    });
+                }
+            }
+            AgentDecision::None => {}
+        }
+    }
+
+    /// the content as modified by a proxy in an earlier attempt
+    fn replay(&mut self, step: &AgentStep) {
+        if let (StepDecision::Modify, Some(content)) = (step.decision, &step.output) {
+            self.content = content.clone();
+            self.modified = true;
+        }
+    }
+
+    fn skip(&mut self, chat_agent: &ChatAgent, position: i32) {
+        self.steps.push(CreateAgentStep {
+            agent_id: chat_agent.id,
+            position,
+            decision: StepDecision::Skipped,
+            output: None,
+            error: None,
+            run: None,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{CreateAgent, CreateMessage, ListMessage};
+    use anyhow::Result;
+    use chat_core::AgentArgs;
+    use chat_core::{AdapterType, AgentTrigger};
+
+    fn mock_agent(name: &str, r#type: AgentType, model: &str, priority: i32) -> CreateAgent {
+        CreateAgent {
+            priority,
+            ..CreateAgent::new(
+                AdapterType::Mock,
+                model,
+                name,
+                r#type,
+                name,
+                AgentArgs::default(),
+            )
+        }
+    }
+
+    #[tokio::test]
+    async fn agent_pipeline_should_run_in_order() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        // created out of order, run by priority: shout, sign, observe, greet
+        let agents = [
+            mock_agent("greet", AgentType::Reply, "Hi there", 3),
+            mock_agent("sign", AgentType::Proxy, "echo", 2),
+            mock_agent("shout", AgentType::Proxy, "HELLO", 1),
+            mock_agent("observe", AgentType::Tap, "none", 2),
+        ];
+        for input in agents {
+            state.create_agent(input, 3).await?;
+        }
+
+        let message = state
+            .create_message(CreateMessage::new("hello", vec![]), 3, 1, 1)
+            .await?;
+        state.run_agent_jobs().await?;
+        let message = state.get_message_by_id(message.id).await?.unwrap();
+        // the second proxy sees the output of the first one
+        assert_eq!(message.modified_content.as_deref(), Some("sign: HELLO"));
+
+        let steps = state.list_agent_steps(3, message.id as _).await?;
+        let decisions: Vec<_> = steps.iter().map(|s| s.decision).collect();
+        assert_eq!(
+            decisions,
+            [
+                StepDecision::Modify,
+                StepDecision::Modify,
+                StepDecision::Label,
+                StepDecision::Reply
+            ]
+        );
+        assert_eq!(steps[3].position, 3);
+        assert_eq!(steps[3].output.as_deref(), Some("Hi there"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn mention_agent_should_only_run_when_addressed() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = CreateAgent {
+            trigger: AgentTrigger::Mention,
+            ..mock_agent("greet", AgentType::Reply, "Hi there", 0)
+        };
+        let bot_id = state.create_agent(input, 4).await?.bot_id;
+        async fn replies(state: &AppState, bot_id: Option<i64>) -> Result<Vec<Message>> {
+            let messages = state.list_message(ListMessage::new(None, 0), 4).await?;
+            Ok(messages
+                .into_iter()
+                .filter(|m| Some(m.sender_id) == bot_id)
+                .collect())
+        }
+
+        // not addressed, nothing to do
+        state
+            .create_message(CreateMessage::new("hello all", vec![]), 4, 1, 1)
+            .await?;
+        assert!(state.claim_agent_job().await?.is_none());
+
+        state
+            .create_message(CreateMessage::new("hello @greet", vec![]), 4, 1, 1)
+            .await?;
+        state.run_agent_jobs().await?;
+        let reply = replies(&state, bot_id).await?;
+        assert_eq!(reply.len(), 1);
+
+        // replying to the agent's message addresses it as well
+        let input = CreateMessage {
+            reply_to: Some(reply[0].id as _),
+            ..CreateMessage::new("thanks", vec![])
+        };
+        state.create_message(input, 4, 3, 1).await?;
+        state.run_agent_jobs().await?;
+        assert_eq!(replies(&state, bot_id).await?.len(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn delete_should_take_precedence() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let proxy = state
+            .create_agent(mock_agent("shout", AgentType::Proxy, "SPAM", 0), 3)
+            .await?;
+        let reply = state
+            .create_agent(mock_agent("greet", AgentType::Reply, "Hi", 1), 3)
+            .await?;
+        let moderator = state
+            .create_agent(mock_agent("moderator", AgentType::Tap, "echo", 2), 3)
+            .await?;
+
+        let mut pipeline = AgentPipeline::new("spam");
+        pipeline.apply(&proxy, 0, Ok(AgentDecision::Modify("SPAM".to_string())));
+        pipeline.apply(&reply, 1, Ok(AgentDecision::Reply("Hi".to_string())));
+        pipeline.apply(&moderator, 2, Ok(AgentDecision::Delete));
+        assert_eq!(pipeline.deleted_by.as_deref(), Some("moderator"));
+        assert!(pipeline.replies.is_empty());
+
+        let decisions: Vec<_> = pipeline.steps.iter().map(|s| s.decision).collect();
+        assert_eq!(
+            decisions,
+            [
+                StepDecision::Modify,
+                StepDecision::Reply,
+                StepDecision::Delete
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tap_should_time_out() -> Result<()> {
+        use crate::ProvidersConfig;
+        use axum::{routing::post, Router};
+
+        async fn chat() -> String {
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            String::new()
+        }
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let app = Router::new().route("/api/chat", post(chat));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let mut providers = ProvidersConfig::default();
+        providers.ollama.base_url = Some(format!("http://{addr}"));
+
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = CreateAgent {
+            adapter: AdapterType::Ollama,
+            ..mock_agent("moderator", AgentType::Tap, "llama3.2", 0)
+        };
+        let chat_agent = state.create_agent(input, 1).await?;
+        let agent = AgentVariant::new(chat_agent.clone(), &providers)?;
+
+        let deadline = Instant::now() + Duration::from_millis(100);
+        let outcome = state
+            .apply_tap(
+                &agent,
+                &chat_agent,
+                1,
+                "hello",
+                &AgentContext::new(),
+                deadline,
+            )
+            .await;
+        let err = outcome.result.unwrap_err();
+        assert!(err.to_string().contains("tap timed out"));
+        assert!(outcome.run.latency_ms < 5000);
+        Ok(())
+    }
+}
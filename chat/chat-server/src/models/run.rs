@@ -0,0 +1,275 @@
+use super::pipeline::{CreateAgentStep, StepDecision};
+use crate::{AppError, AppState};
+use chat_core::{AgentContext, AgentDecision};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{types::Json, FromRow};
+use utoipa::{IntoParams, ToSchema};
+
+/// a message sent to the model
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct PromptMessage {
+    pub role: String,
+    pub content: String,
+}
+
+/// a model call of an agent: what was sent, what came back and how long it took
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, FromRow, ToSchema)]
+pub struct AgentRun {
+    pub id: i64,
+    pub chat_id: i64,
+    pub agent_id: i64,
+    /// the message the agent ran on, null if it was never stored or got deleted
+    pub message_id: Option<i64>,
+    /// content given to the agent, the output of the previous proxies
+    pub input: String,
+    #[sqlx(json)]
+    pub prompt: Vec<PromptMessage>,
+    /// raw answer of the model
+    pub response: Option<String>,
+    pub decision: StepDecision,
+    /// modified content, reply or labels taken from the response
+    pub output: Option<String>,
+    pub error: Option<String>,
+    pub latency_ms: i32,
+    /// the run this one replays
+    pub replay_of: Option<i64>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, IntoParams, ToSchema)]
+pub struct ListRuns {
+    /// runs of a single agent
+    #[serde(default)]
+    pub agent_id: Option<u64>,
+    /// runs with a smaller id, for paging
+    #[serde(default)]
+    pub last_id: Option<u64>,
+}
+
+/// a past run and the replay of its input against the agent's current config
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct RunReplay {
+    pub original: AgentRun,
+    pub replay: AgentRun,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct CreateAgentRun {
+    pub input: String,
+    pub prompt: Vec<PromptMessage>,
+    pub response: Option<String>,
+    pub latency_ms: i32,
+    pub replay_of: Option<i64>,
+}
+
+/// the decision of an agent call and the call itself
+#[derive(Debug)]
+pub(crate) struct AgentOutcome {
+    pub result: Result<AgentDecision, AppError>,
+    pub run: CreateAgentRun,
+}
+
+impl AppState {
+    /// log the model call of a step
+    pub(crate) async fn create_agent_run(
+        &self,
+        chat_id: u64,
+        message_id: Option<i64>,
+        step: &CreateAgentStep,
+        run: &CreateAgentRun,
+    ) -> Result<AgentRun, AppError> {
+        let run = sqlx::query_as(
+            r#"
+            INSERT INTO agent_runs (chat_id, agent_id, message_id, input, prompt, response, decision, output, error, latency_ms, replay_of)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+            RETURNING *
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(step.agent_id)
+        .bind(message_id)
+        .bind(&run.input)
+        .bind(Json(&run.prompt))
+        .bind(&run.response)
+        .bind(step.decision)
+        .bind(&step.output)
+        .bind(&step.error)
+        .bind(run.latency_ms)
+        .bind(run.replay_of)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(run)
+    }
+
+    /// runs of the chat's agents, newest first
+    pub async fn list_agent_runs(
+        &self,
+        chat_id: u64,
+        input: ListRuns,
+    ) -> Result<Vec<AgentRun>, AppError> {
+        let runs = sqlx::query_as(
+            r#"
+            SELECT * FROM agent_runs
+            WHERE chat_id = $1 AND ($2::BIGINT IS NULL OR agent_id = $2) AND id < $3
+            ORDER BY id DESC LIMIT 100
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(input.agent_id.map(|id| id as i64))
+        .bind(input.last_id.unwrap_or(i64::MAX as _) as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(runs)
+    }
+
+    pub async fn get_agent_run(
+        &self,
+        chat_id: u64,
+        run_id: u64,
+    ) -> Result<Option<AgentRun>, AppError> {
+        let run = sqlx::query_as("SELECT * FROM agent_runs WHERE chat_id = $1 AND id = $2")
+            .bind(chat_id as i64)
+            .bind(run_id as i64)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(run)
+    }
+
+    /// run the input of a past run against the agent's current config, with the
+    /// context of the original message if it's still there. The replay is logged
+    /// and its usage counted, the chat isn't changed
+    pub async fn replay_agent_run(
+        &self,
+        chat_id: u64,
+        run_id: u64,
+        ws_id: u64,
+    ) -> Result<RunReplay, AppError> {
+        let original = self
+            .get_agent_run(chat_id, run_id)
+            .await?
+            .ok_or_else(|| AppError::NotFound(format!("agent run {run_id}")))?;
+        let chat_agent = self
+            .get_agent(chat_id, original.agent_id as _)
+            .await?
+            .ok_or_else(|| AppError::NotFound(format!("agent {}", original.agent_id)))?;
+        self.check_agent_quota(&chat_agent, ws_id).await?;
+
+        let message = match original.message_id {
+            Some(id) => self.get_message_by_id(id).await?,
+            None => None,
+        };
+        let ctx = match &message {
+            Some(message) => self.agent_context(message, ws_id).await?,
+            None => AgentContext::default(),
+        };
+        let agent = self.agent_variant(chat_agent.clone())?;
+        let outcome = self
+            .apply_agent(&agent, &chat_agent, ws_id, &original.input, &ctx)
+            .await;
+        let (decision, output, error) = match outcome.result {
+            Ok(decision) => {
+                let (step, output) = StepDecision::with_output(&decision);
+                (step, output, None)
+            }
+            Err(e) => (StepDecision::Failed, None, Some(e.to_string())),
+        };
+        let step = CreateAgentStep {
+            agent_id: chat_agent.id,
+            position: 0,
+            decision,
+            output,
+            error,
+            run: None,
+        };
+        let run = CreateAgentRun {
+            replay_of: Some(original.id),
+            ..outcome.run
+        };
+        let replay = self
+            .create_agent_run(chat_id, original.message_id, &step, &run)
+            .await?;
+
+        Ok(RunReplay { original, replay })
+    }
+}
+
+impl CreateAgentRun {
+    pub fn new(input: &str, prompt: &[ai_sdk::Message]) -> Self {
+        Self {
+            input: input.to_string(),
+            prompt: prompt.iter().map(PromptMessage::from).collect(),
+            response: None,
+            latency_ms: 0,
+            replay_of: None,
+        }
+    }
+}
+
+impl From<&ai_sdk::Message> for PromptMessage {
+    fn from(value: &ai_sdk::Message) -> Self {
+        Self {
+            role: value.role.to_string(),
+            content: value.content.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{CreateAgent, CreateMessage, UpdateAgent};
+    use anyhow::Result;
+    use chat_core::{AdapterType, AgentArgs, AgentType};
+
+    #[tokio::test]
+    async fn agent_runs_should_be_logged_and_replayed() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = CreateAgent::new(
+            AdapterType::Mock,
+            "HELLO",
+            "shout",
+            AgentType::Proxy,
+            "Shout",
+            AgentArgs::default(),
+        );
+        let agent = state.create_agent(input, 3).await?;
+        let message = state
+            .create_message(CreateMessage::new("hello", vec![]), 3, 1, 1)
+            .await?;
+        state.run_agent_jobs().await?;
+
+        let runs = state.list_agent_runs(3, ListRuns::default()).await?;
+        assert_eq!(runs.len(), 1);
+        let run = &runs[0];
+        assert_eq!(run.message_id, Some(message.id));
+        assert_eq!(run.input, "hello");
+        assert_eq!(run.prompt[0].role, "user");
+        assert_eq!(run.prompt[0].content, "Shout: hello");
+        assert_eq!(run.response.as_deref(), Some("HELLO"));
+        assert_eq!(run.decision, StepDecision::Modify);
+
+        // replay against the new config
+        let input = UpdateAgent::new(agent.id as _, "Whisper", AgentArgs::default());
+        state.update_agent(input, 3).await?;
+        let replay = state.replay_agent_run(3, run.id as _, 1).await?;
+        assert_eq!(replay.original, *run);
+        assert_eq!(replay.replay.replay_of, Some(run.id));
+        assert_eq!(replay.replay.prompt[0].content, "Whisper: hello");
+
+        let input = ListRuns {
+            agent_id: Some(agent.id as _),
+            ..Default::default()
+        };
+        assert_eq!(state.list_agent_runs(3, input).await?.len(), 2);
+        let input = ListRuns {
+            agent_id: Some(agent.id as u64 + 1),
+            ..Default::default()
+        };
+        assert!(state.list_agent_runs(3, input).await?.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,488 @@
+use super::knowledge::collection_name;
+use crate::{AppError, AppState};
+use ai_sdk::{normalize, EmbeddingAdapter, EmbeddingService};
+use chat_core::{AgentError, KnowledgeCollection, Message};
+use futures::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sqlx::{postgres::PgListener, query_as, query_scalar, FromRow, PgPool};
+use std::{collections::HashMap, time::Duration};
+use swiftide_core::{
+    indexing::{EmbeddedField, Node},
+    querying::{states, Document, Query},
+    Persist, Retrieve,
+};
+use swiftide_pgvector::{FilteredSimilarity, MetadataFilter, PgVector, PgVectorBuilder};
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+use utoipa::{IntoParams, ToSchema};
+
+/// messages embedded per pass of the indexer
+const INDEX_BATCH: i64 = 64;
+/// only the start of longer messages is embedded, the whole message is still returned
+const MAX_CHUNK_CHARS: usize = 8000;
+/// wait before listening again after the notifications connection failed
+const LISTEN_RETRY: Duration = Duration::from_secs(5);
+/// the vectors of the messages and knowledge chunks of all workspaces, nodes carry
+/// their `ws_id`, `model` and `collection` in the metadata
+const VECTOR_TABLE: &str = "chat_embeddings";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum SearchMode {
+    /// messages containing the query, newest first
+    #[default]
+    Text,
+    /// messages closest in meaning to the query, see `ChatConfig::embedding`
+    Semantic,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, IntoParams, ToSchema)]
+pub struct SearchMessages {
+    pub q: String,
+    #[serde(default)]
+    pub mode: SearchMode,
+    #[serde(default = "default_search_limit")]
+    pub limit: u64,
+}
+
+/// a message matching the search
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, FromRow, ToSchema)]
+pub struct MessageHit {
+    #[sqlx(flatten)]
+    #[serde(flatten)]
+    pub message: Message,
+    /// cosine similarity to the query, semantic search only
+    pub score: Option<f32>,
+}
+
+/// a message to index with the workspace of its chat
+#[derive(Debug, FromRow)]
+struct PendingMessage {
+    #[sqlx(flatten)]
+    message: Message,
+    ws_id: i64,
+}
+
+impl AppState {
+    /// search the messages of the chat, by text or by meaning
+    pub async fn search_chat_messages(
+        &self,
+        chat_id: u64,
+        user_id: u64,
+        input: SearchMessages,
+    ) -> Result<Vec<MessageHit>, AppError> {
+        let query = input.q.trim();
+        if query.is_empty() {
+            return Err(AppError::SearchError("query is empty".to_string()));
+        }
+        match input.mode {
+            SearchMode::Text => {
+                let messages = self.search_messages(chat_id, query, input.limit).await?;
+                Ok(messages
+                    .into_iter()
+                    .map(|message| MessageHit {
+                        message,
+                        score: None,
+                    })
+                    .collect())
+            }
+            SearchMode::Semantic => {
+                self.semantic_search(chat_id, user_id, query, input.limit)
+                    .await
+            }
+        }
+    }
+
+    /// messages of the chat closest in meaning to the query, most similar first.
+    /// Nothing is returned unless the user is a member of the chat
+    pub(crate) async fn semantic_search(
+        &self,
+        chat_id: u64,
+        user_id: u64,
+        query: &str,
+        limit: u64,
+    ) -> Result<Vec<MessageHit>, AppError> {
+        self.vector_index()?;
+        let ws_id: Option<i64> =
+            query_scalar("SELECT ws_id FROM chats WHERE id = $1 AND $2 = ANY(members)")
+                .bind(chat_id as i64)
+                .bind(user_id as i64)
+                .fetch_optional(&self.pool)
+                .await?;
+        let Some(ws_id) = ws_id else {
+            return Ok(vec![]);
+        };
+        let strategy = FilteredSimilarity::new(limit.clamp(1, 100))
+            .with_filter(MetadataFilter::eq("ws_id", ws_id))
+            .with_filter(MetadataFilter::eq("chat_id", chat_id));
+        let scores: HashMap<i64, f32> = self
+            .retrieve_similar(query, strategy)
+            .await?
+            .iter()
+            .filter_map(|doc| Some((metadata_i64(doc, "message_id")?, score(doc))))
+            .collect();
+        let ids: Vec<i64> = scores.keys().copied().collect();
+        let messages: Vec<Message> =
+            query_as("SELECT * FROM messages WHERE id = ANY($1) AND NOT hidden")
+                .bind(&ids)
+                .fetch_all(&self.pool)
+                .await?;
+
+        let mut hits: Vec<MessageHit> = messages
+            .into_iter()
+            .map(|message| MessageHit {
+                score: scores.get(&message.id).copied(),
+                message,
+            })
+            .collect();
+        hits.sort_by(|a, b| {
+            let (a_score, b_score) = (a.score.unwrap_or_default(), b.score.unwrap_or_default());
+            b_score
+                .total_cmp(&a_score)
+                .then(b.message.id.cmp(&a.message.id))
+        });
+        Ok(hits)
+    }
+
+    /// the nodes closest to the query among the ones matching the filters of the
+    /// strategy and embedded with the current model, most similar first
+    pub(super) async fn retrieve_similar(
+        &self,
+        query: &str,
+        strategy: FilteredSimilarity,
+    ) -> Result<Vec<Document>, AppError> {
+        let (embedder, store) = self.vector_index()?;
+        let strategy = strategy.with_filter(MetadataFilter::eq("model", embedder.model()));
+        let mut pending = Query::<states::Pending>::new(query);
+        pending.embedding = Some(embed_query(embedder, query).await?);
+        let retrieved = store
+            .retrieve(&strategy, pending)
+            .await
+            .map_err(vector_store_error)?;
+
+        Ok(retrieved.documents().to_vec())
+    }
+
+    /// embed the messages missing from the index, or changed since, e.g. replies
+    /// indexed while being streamed. Returns how many were indexed
+    pub(crate) async fn index_pending_messages(&self) -> Result<usize, AppError> {
+        let (Some(embedder), Some(store)) = (&self.embedder, &self.vector_store) else {
+            return Ok(0);
+        };
+        let sql = format!(
+            r#"
+            SELECT m.*, c.ws_id FROM messages m
+            JOIN chats c ON c.id = m.chat_id
+            WHERE NOT m.hidden AND m.content <> ''
+                AND NOT EXISTS (
+                    SELECT 1 FROM {} e
+                    WHERE e.metadata @> jsonb_build_object('message_id', m.id, 'model', $1::text)
+                        AND e.chunk = m.content
+                )
+            ORDER BY m.id
+            LIMIT $2
+            "#,
+            store.table_name()
+        );
+        let pending: Vec<PendingMessage> = query_as(&sql)
+            .bind(embedder.model())
+            .bind(INDEX_BATCH)
+            .fetch_all(&self.pool)
+            .await?;
+        if pending.is_empty() {
+            return Ok(0);
+        }
+
+        let texts: Vec<String> = pending
+            .iter()
+            .map(|p| p.message.content.chars().take(MAX_CHUNK_CHARS).collect())
+            .collect();
+        let vectors = embedder.embed(&texts).await.map_err(AgentError::from)?;
+        let mut nodes = Vec::with_capacity(pending.len());
+        for (PendingMessage { message, ws_id }, vector) in pending.iter().zip(vectors) {
+            // the node of the previous content has another id
+            self.forget_message(message.id).await?;
+            let path = format!("chats/{}/messages/{}", message.chat_id, message.id);
+            let metadata = message_metadata(message, *ws_id, embedder.model());
+            nodes.push(vector_node(path, &message.content, metadata, vector));
+        }
+        store_nodes(store, nodes).await?;
+
+        Ok(pending.len())
+    }
+
+    /// remove the message from the index
+    pub(crate) async fn forget_message(&self, message_id: i64) -> Result<(), AppError> {
+        if let Some(store) = &self.vector_store {
+            store
+                .delete(&[MetadataFilter::eq("message_id", message_id)])
+                .await
+                .map_err(vector_store_error)?;
+        }
+        Ok(())
+    }
+
+    /// remove the messages of the chat from the index
+    pub(crate) async fn forget_chat(&self, chat_id: i64) -> Result<(), AppError> {
+        if let Some(store) = &self.vector_store {
+            store
+                .delete(&[MetadataFilter::eq("chat_id", chat_id)])
+                .await
+                .map_err(vector_store_error)?;
+        }
+        Ok(())
+    }
+
+    /// keep the semantic search index up to date: the indexer catches up on start,
+    /// then on every new message and every reply done streaming
+    pub async fn spawn_message_indexer(&self) -> Result<Option<JoinHandle<()>>, AppError> {
+        if self.embedder.is_none() {
+            info!("semantic search is disabled, no embedding model configured");
+            return Ok(None);
+        }
+        let mut listener = PgListener::connect(&self.config.server.db_url).await?;
+        listener
+            .listen_all(["chat_message_created", "chat_message_delta"])
+            .await?;
+        let state = self.clone();
+        let handle = tokio::spawn(async move {
+            loop {
+                match state.index_pending_messages().await {
+                    Ok(0) => {}
+                    Ok(_) => continue,
+                    Err(e) => warn!("index messages failed: {e}"),
+                }
+                loop {
+                    match listener.recv().await {
+                        Ok(notif) if notif.channel() == "chat_message_delta" => {
+                            if is_done_delta(notif.payload()) {
+                                break;
+                            }
+                        }
+                        Ok(_) => break,
+                        Err(e) => {
+                            warn!("message indexer listener failed: {e}");
+                            tokio::time::sleep(LISTEN_RETRY).await;
+                        }
+                    }
+                }
+            }
+        });
+        Ok(Some(handle))
+    }
+
+    /// index all pending messages
+    #[cfg(test)]
+    pub(crate) async fn index_messages(&self) -> Result<usize, AppError> {
+        let mut count = 0;
+        loop {
+            match self.index_pending_messages().await? {
+                0 => return Ok(count),
+                n => count += n,
+            }
+        }
+    }
+
+    pub(crate) fn vector_index(&self) -> Result<(&EmbeddingAdapter, &PgVector), AppError> {
+        match (&self.embedder, &self.vector_store) {
+            (Some(embedder), Some(store)) => Ok((embedder, store)),
+            _ => Err(AppError::SearchError(
+                "no embedding model is configured".to_string(),
+            )),
+        }
+    }
+}
+
+/// the vector store of the embedder, created if needed. Fails when the vectors
+/// stored have another size, e.g. after the embedding model changed
+pub(crate) async fn open_vector_store(
+    pool: &PgPool,
+    embedder: Option<&EmbeddingAdapter>,
+) -> Result<Option<PgVector>, AppError> {
+    let Some(embedder) = embedder else {
+        return Ok(None);
+    };
+    let store = PgVectorBuilder::default()
+        .pool(pool.clone())
+        .table_name(VECTOR_TABLE.to_string())
+        .vector_size(embedder.dimensions() as i32)
+        .build()
+        .map_err(|e| AppError::VectorStoreError(e.to_string()))?;
+    store.setup().await.map_err(vector_store_error)?;
+    Ok(Some(store))
+}
+
+/// a node of the vector store with the embedding of the chunk
+pub(super) fn vector_node(
+    path: String,
+    chunk: &str,
+    metadata: Value,
+    mut vector: Vec<f32>,
+) -> Node {
+    normalize(&mut vector);
+    let mut node = Node::new(chunk);
+    node.path = path.into();
+    if let Value::Object(fields) = metadata {
+        node.metadata.extend(fields);
+    }
+    node.vectors = Some(HashMap::from([(EmbeddedField::Combined, vector)]));
+    node
+}
+
+/// store the nodes in a single transaction, nodes with the same path and chunk are replaced
+pub(super) async fn store_nodes(store: &PgVector, nodes: Vec<Node>) -> Result<(), AppError> {
+    store
+        .batch_store(nodes)
+        .await
+        .try_collect::<Vec<_>>()
+        .await
+        .map_err(vector_store_error)?;
+    Ok(())
+}
+
+pub(super) fn vector_store_error(e: anyhow::Error) -> AppError {
+    AppError::VectorStoreError(e.to_string())
+}
+
+/// similarity to the query of a retrieved node
+pub(super) fn score(doc: &Document) -> f32 {
+    doc.metadata()
+        .get("score")
+        .and_then(Value::as_f64)
+        .unwrap_or_default() as f32
+}
+
+pub(super) fn metadata_i64(doc: &Document, key: &str) -> Option<i64> {
+    doc.metadata().get(key).and_then(Value::as_i64)
+}
+
+pub(super) async fn embed_query(
+    embedder: &EmbeddingAdapter,
+    query: &str,
+) -> Result<Vec<f32>, AppError> {
+    let query: String = query.chars().take(MAX_CHUNK_CHARS).collect();
+    let mut vector = embedder
+        .embed(&[query])
+        .await
+        .map_err(AgentError::from)?
+        .pop()
+        .unwrap_or_default();
+    normalize(&mut vector);
+    Ok(vector)
+}
+
+fn message_metadata(message: &Message, ws_id: i64, model: &str) -> Value {
+    json!({
+        "ws_id": ws_id,
+        "model": model,
+        "collection": collection_name(KnowledgeCollection::ChatHistory),
+        "chat_id": message.chat_id,
+        "message_id": message.id,
+        "sender_id": message.sender_id,
+        "source": format!("message {}", message.id),
+    })
+}
+
+/// streamed replies are indexed once done, not on every delta
+fn is_done_delta(payload: &str) -> bool {
+    serde_json::from_str::<Value>(payload)
+        .map(|v| v["delta"]["done"].as_bool().unwrap_or(false))
+        .unwrap_or(false)
+}
+
+fn default_search_limit() -> u64 {
+    10
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::DeleteMessage;
+    use ai_sdk::MockEmbedder;
+    use anyhow::Result;
+
+    fn semantic(q: &str) -> SearchMessages {
+        SearchMessages {
+            q: q.to_string(),
+            mode: SearchMode::Semantic,
+            limit: 3,
+        }
+    }
+
+    #[tokio::test]
+    async fn semantic_search_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let indexed = state.index_messages().await?;
+        assert!(indexed >= 10);
+        assert_eq!(state.index_pending_messages().await?, 0);
+
+        let hits = state
+            .search_chat_messages(1, 1, semantic("how are YOU"))
+            .await?;
+        assert_eq!(hits.len(), 3);
+        assert_eq!(hits[0].message.content, "How are you?");
+        assert!(hits[0].score.unwrap() > 0.99);
+        assert!(hits[0].score >= hits[1].score && hits[1].score >= hits[2].score);
+
+        // user 4 isn't a member of chat 2
+        let hits = state.search_chat_messages(2, 4, semantic("hello")).await?;
+        assert!(hits.is_empty());
+
+        let input = SearchMessages {
+            mode: SearchMode::Text,
+            ..semantic("how are")
+        };
+        let hits = state.search_chat_messages(1, 1, input).await?;
+        assert_eq!(hits.len(), 2);
+        assert!(hits[0].score.is_none());
+        assert!(state
+            .search_chat_messages(1, 1, semantic(" "))
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn deleted_messages_should_leave_the_index() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        state.index_messages().await?;
+        let hits = state
+            .search_chat_messages(1, 1, semantic("how are you"))
+            .await?;
+        let id = hits[0].message.id;
+        state
+            .delete_message(
+                DeleteMessage {
+                    message_id: id as _,
+                },
+                1,
+            )
+            .await?;
+        let hits = state
+            .search_chat_messages(1, 1, semantic("how are you"))
+            .await?;
+        assert!(hits.iter().all(|h| h.message.id != id));
+        let sql = format!("SELECT COUNT(*) FROM {VECTOR_TABLE} WHERE metadata @> $1");
+        let nodes: i64 = query_scalar(&sql)
+            .bind(json!({ "message_id": id }))
+            .fetch_one(&state.pool)
+            .await?;
+        assert_eq!(nodes, 0);
+        assert!(is_done_delta(r#"{"delta": {"id": 1, "done": true}}"#));
+        assert!(!is_done_delta(r#"{"delta": {"id": 1, "done": false}}"#));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn vector_store_should_match_embedder_dimensions() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let store = state.vector_store.as_ref().unwrap();
+        assert_eq!(store.vector_size(), 256);
+
+        let embedder = MockEmbedder::new(8).into();
+        let ret = open_vector_store(&state.pool, Some(&embedder)).await;
+        assert!(matches!(ret, Err(AppError::VectorStoreError(e)) if e.contains("256 dimensions")));
+        Ok(())
+    }
+}
@@ -0,0 +1,240 @@
+use crate::{agent::CallUsage, AppError, AppState};
+use chat_core::ChatAgent;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use utoipa::{IntoParams, ToSchema};
+
+/// time range of the usage report, defaults to the last 30 days
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema, IntoParams)]
+pub struct UsageQuery {
+    #[serde(default)]
+    pub from: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub to: Option<DateTime<Utc>>,
+    /// only report this agent
+    #[serde(default)]
+    pub agent_id: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, ToSchema, FromRow)]
+pub struct UsageSummary {
+    pub calls: i64,
+    pub prompt_tokens: i64,
+    pub completion_tokens: i64,
+    pub cache_hit_tokens: i64,
+    pub cost: f64,
+    /// calls answered by a fallback provider
+    pub failovers: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema, FromRow)]
+pub struct AgentUsage {
+    pub agent_id: i64,
+    /// `None` if the agent has been removed since
+    pub name: Option<String>,
+    pub model: String,
+    #[serde(flatten)]
+    #[sqlx(flatten)]
+    pub usage: UsageSummary,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct UsageReport {
+    pub ws_id: u64,
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    pub total: UsageSummary,
+    pub agents: Vec<AgentUsage>,
+}
+
+const DEFAULT_USAGE_DAYS: i64 = 30;
+
+impl AppState {
+    /// record the usage of an agent call, priced with the model of the provider
+    /// that answered
+    pub async fn record_agent_usage(
+        &self,
+        agent: &ChatAgent,
+        ws_id: u64,
+        call: &CallUsage,
+    ) -> Result<(), AppError> {
+        let provider = agent
+            .providers()
+            .into_iter()
+            .nth(call.provider)
+            .ok_or_else(|| AppError::NotFound(format!("provider {}", call.provider)))?;
+        let usage = &call.usage;
+        let cost = self
+            .config
+            .pricing
+            .get(&provider.model)
+            .map(|price| price.cost(usage))
+            .unwrap_or_default();
+        sqlx::query(
+            r#"
+            INSERT INTO agent_usage (agent_id, chat_id, ws_id, adapter, model, provider, prompt_tokens, completion_tokens, cache_hit_tokens, cost)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+            "#,
+        )
+        .bind(agent.id)
+        .bind(agent.chat_id)
+        .bind(ws_id as i64)
+        .bind(provider.adapter)
+        .bind(provider.model)
+        .bind(call.provider as i32)
+        .bind(usage.prompt_tokens as i32)
+        .bind(usage.completion_tokens as i32)
+        .bind(usage.cache_hit_tokens as i32)
+        .bind(cost)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// usage and cost of the workspace's agents in the time range, grouped by agent and model
+    pub async fn get_usage_report(
+        &self,
+        ws_id: u64,
+        input: UsageQuery,
+    ) -> Result<UsageReport, AppError> {
+        let to = input.to.unwrap_or_else(Utc::now);
+        let from = input
+            .from
+            .unwrap_or_else(|| to - Duration::days(DEFAULT_USAGE_DAYS));
+        let agents: Vec<AgentUsage> = sqlx::query_as(
+            r#"
+            SELECT u.agent_id, a.name, u.model,
+                COUNT(*) AS calls,
+                SUM(u.prompt_tokens)::BIGINT AS prompt_tokens,
+                SUM(u.completion_tokens)::BIGINT AS completion_tokens,
+                SUM(u.cache_hit_tokens)::BIGINT AS cache_hit_tokens,
+                SUM(u.cost) AS cost,
+                COUNT(*) FILTER (WHERE u.provider > 0) AS failovers
+            FROM agent_usage u
+            LEFT JOIN chat_agents a ON a.id = u.agent_id
+            WHERE u.ws_id = $1 AND u.created_at >= $2 AND u.created_at < $3
+                AND ($4::BIGINT IS NULL OR u.agent_id = $4)
+            GROUP BY u.agent_id, a.name, u.model
+            ORDER BY cost DESC, u.agent_id
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(from)
+        .bind(to)
+        .bind(input.agent_id.map(|id| id as i64))
+        .fetch_all(&self.pool)
+        .await?;
+
+        let total = agents
+            .iter()
+            .fold(UsageSummary::default(), |mut total, agent| {
+                total.calls += agent.usage.calls;
+                total.prompt_tokens += agent.usage.prompt_tokens;
+                total.completion_tokens += agent.usage.completion_tokens;
+                total.cache_hit_tokens += agent.usage.cache_hit_tokens;
+                total.cost += agent.usage.cost;
+                total.failovers += agent.usage.failovers;
+                total
+            });
+
+        Ok(UsageReport {
+            ws_id,
+            from,
+            to,
+            total,
+            agents,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::CreateAgent;
+    use ai_sdk::Usage;
+    use anyhow::Result;
+    use chat_core::AgentArgs;
+    use chat_core::{AdapterType, AgentProvider, AgentType};
+
+    #[tokio::test]
+    async fn agent_usage_should_be_reported() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = CreateAgent {
+            fallbacks: vec![AgentProvider::new(AdapterType::Ollama, "llama3.2")],
+            ..CreateAgent::new(
+                AdapterType::Deepseek,
+                "deepseek-chat",
+                "translator",
+                AgentType::Proxy,
+                "Translate to English",
+                AgentArgs::default(),
+            )
+        };
+        let translator = state.create_agent(input, 1).await?;
+        let input = CreateAgent::new(
+            AdapterType::Ollama,
+            "llama3.2",
+            "echo",
+            AgentType::Reply,
+            "",
+            AgentArgs::default(),
+        );
+        let echo = state.create_agent(input, 2).await?;
+
+        let usage = Usage {
+            prompt_tokens: 1000,
+            completion_tokens: 500,
+            cache_hit_tokens: 200,
+        };
+        state
+            .record_agent_usage(&translator, 1, &usage.into())
+            .await?;
+        state
+            .record_agent_usage(&translator, 1, &usage.into())
+            .await?;
+        // answered by the fallback
+        state
+            .record_agent_usage(&translator, 1, &CallUsage::new(usage, 1))
+            .await?;
+        state.record_agent_usage(&echo, 1, &usage.into()).await?;
+        // other workspaces are not reported
+        state.record_agent_usage(&echo, 2, &usage.into()).await?;
+
+        let report = state.get_usage_report(1, UsageQuery::default()).await?;
+        // usage is reported per agent and model
+        assert_eq!(report.agents.len(), 3);
+        assert_eq!(report.total.calls, 4);
+        assert_eq!(report.total.prompt_tokens, 4000);
+        assert_eq!(report.total.failovers, 1);
+
+        // deepseek-chat is priced in chat.yml, llama3.2 is free
+        let agent = &report.agents[0];
+        assert_eq!(agent.name.as_deref(), Some("translator"));
+        assert_eq!(agent.usage.calls, 2);
+        let expected = 2.0 * (800.0 * 0.27 + 200.0 * 0.07 + 500.0 * 1.1) / 1_000_000.0;
+        assert!((agent.usage.cost - expected).abs() < 1e-12);
+        // the fallback call is priced as llama3.2
+        let fallback = &report.agents[1];
+        assert_eq!(fallback.model, "llama3.2");
+        assert_eq!(fallback.usage.failovers, 1);
+        assert_eq!(fallback.usage.cost, 0.0);
+
+        let input = UsageQuery {
+            agent_id: Some(echo.id as _),
+            ..Default::default()
+        };
+        let report = state.get_usage_report(1, input).await?;
+        assert_eq!(report.agents.len(), 1);
+        assert_eq!(report.total.calls, 1);
+
+        let input = UsageQuery {
+            to: Some(Utc::now() - Duration::days(1)),
+            ..Default::default()
+        };
+        let report = state.get_usage_report(1, input).await?;
+        assert!(report.agents.is_empty());
+        Ok(())
+    }
+}
@@ -86,7 +86,7 @@ impl AppState {
     /// Verify email and password
     pub async fn verify_user(&self, input: &SigninUser) -> anyhow::Result<Option<User>, AppError> {
         let user: Option<User> = query_as(
-            "SELECT id, ws_id, fullname, email, password_hash, created_at FROM users WHERE email = $1",
+            "SELECT id, ws_id, fullname, email, password_hash, created_at FROM users WHERE email = $1 AND NOT is_bot",
         )
         .bind(&input.email)
         .fetch_optional(&self.pool)
@@ -111,7 +111,7 @@ impl AppState {
     }
 
     pub async fn fetch_chat_user_by_ids(&self, ids: &[i64]) -> Result<Vec<ChatUser>, AppError> {
-        let users = query_as("SELECT id, fullname, email FROM users WHERE id = ANY($1)")
+        let users = query_as("SELECT id, fullname, email, is_bot FROM users WHERE id = ANY($1)")
             .bind(ids)
             .fetch_all(&self.pool)
             .await?;
@@ -121,7 +121,7 @@ impl AppState {
 
     #[allow(dead_code)]
     pub async fn fetch_chat_user_all(&self, ws_id: u64) -> Result<Vec<ChatUser>, AppError> {
-        let users = query_as("SELECT id, fullname, email FROM users WHERE ws_id = $1")
+        let users = query_as("SELECT id, fullname, email, is_bot FROM users WHERE ws_id = $1")
             .bind(ws_id as i64)
             .fetch_all(&self.pool)
             .await?;
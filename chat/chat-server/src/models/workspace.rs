@@ -24,7 +24,6 @@ impl AppState {
         Ok(ws)
     }
 
-    #[allow(dead_code)]
     pub async fn find_workspace_by_id(&self, id: u64) -> Result<Option<Workspace>, AppError> {
         let ws = query_as(
             "SELECT id, name, owner_id, created_at FROM workspaces WHERE id = $1 ORDER BY id",
@@ -36,6 +35,22 @@ impl AppState {
         Ok(ws)
     }
 
+    /// fails unless the user owns the workspace
+    pub(crate) async fn check_workspace_owner(
+        &self,
+        ws_id: u64,
+        user_id: u64,
+    ) -> Result<(), AppError> {
+        let ws = self
+            .find_workspace_by_id(ws_id)
+            .await?
+            .ok_or_else(|| AppError::NotFound(format!("workspace {ws_id}")))?;
+        if ws.owner_id != user_id as i64 {
+            return Err(AppError::NotWorkspaceOwnerError { user_id, ws_id });
+        }
+        Ok(())
+    }
+
     pub async fn update_workspace_owner(
         &self,
         ws_id: u64,
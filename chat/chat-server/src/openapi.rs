@@ -1,10 +1,20 @@
 use crate::{
     handlers::*,
-    models::{CreateMessage, CreateUser, ListMessage, ParamChat, SigninUser},
+    models::{
+        AgentJob, AgentMetrics, AgentRun, AgentUsage, CreateAgent, CreateMessage, CreateUser,
+        IngestFile, IngestStatus, JobStatus, KnowledgeFile, LabelledMessage, ListJobs,
+        ListKnowledgeFiles, ListLabels, ListMessage, ListRuns, MessageHit, MessageLabel, ParamChat,
+        PromptMessage, QuotaState, RunReplay, SearchMessages, SearchMode, SigninUser, StepDecision,
+        TestAgent, UpdateAgent, UsageQuery, UsageReport, UsageSummary, WorkspaceBudget,
+    },
     AppState, AuthOutput, ErrorOutput,
 };
 use axum::Router;
-use chat_core::{Chat, ChatType, ChatUser, Message, User, Workspace};
+use chat_core::{
+    AdapterType, AgentArgs, AgentDecision, AgentProvider, AgentTrigger, AgentType, Chat, ChatAgent,
+    ChatType, ChatUser, JsonSchema, KnowledgeArgs, KnowledgeCollection, Label, LabelAction,
+    LabelRule, Message, ResponseFormat, User, Workspace,
+};
 use utoipa::{
     openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme},
     Modify, OpenApi,
@@ -32,10 +42,31 @@ pub(crate) trait OpenApiRouter {
             list_message_handler,
             delete_message_handler,
             send_message_handler,
+            search_message_handler,
             list_chat_user_handler,
+            get_usage_handler,
+            get_budget_handler,
+            set_budget_handler,
+            list_agent_jobs_handler,
+            retry_agent_job_handler,
+            list_labelled_messages_handler,
+            ingest_file_handler,
+            list_knowledge_files_handler,
+            get_knowledge_file_handler,
+            list_agent_handler,
+            create_agent_handler,
+            update_agent_handler,
+            get_agent_handler,
+            delete_agent_handler,
+            test_agent_handler,
+            list_agent_metrics_handler,
+            list_agent_runs_handler,
+            list_runs_of_agent_handler,
+            get_agent_run_handler,
+            replay_agent_run_handler,
         ),
         components(
-            schemas(User, Chat, ChatType, ChatUser, Message, Workspace, SigninUser, CreateUser, CreateMessage, ListMessage, AuthOutput, ErrorOutput, ParamChat),
+            schemas(User, Chat, ChatType, ChatUser, Message, Workspace, SigninUser, CreateUser, CreateMessage, ListMessage, SearchMessages, SearchMode, MessageHit, AuthOutput, ErrorOutput, ParamChat, UsageQuery, UsageReport, UsageSummary, AgentUsage, WorkspaceBudget, QuotaState, AgentJob, JobStatus, ListJobs, LabelledMessage, MessageLabel, ListLabels, LabelAction, ChatAgent, AgentType, AdapterType, AgentProvider, AgentTrigger, LabelRule, Label, AgentArgs, KnowledgeArgs, KnowledgeCollection, IngestFile, IngestStatus, KnowledgeFile, ListKnowledgeFiles, ResponseFormat, JsonSchema, CreateAgent, UpdateAgent, TestAgent, AgentDecision, AgentMetrics, AgentRun, PromptMessage, StepDecision, ListRuns, RunReplay),
         ),
         modifiers(&SecurityAddon),
         tags(
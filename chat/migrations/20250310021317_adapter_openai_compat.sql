@@ -0,0 +1,2 @@
+-- add openai compatible adapter (OpenAI, vLLM, llama.cpp server, LM Studio...)
+ALTER TYPE adapter_type ADD VALUE IF NOT EXISTS 'openai_compat';
@@ -0,0 +1,2 @@
+-- add ollama adapter, using the native /api/chat protocol
+ALTER TYPE adapter_type ADD VALUE IF NOT EXISTS 'ollama';
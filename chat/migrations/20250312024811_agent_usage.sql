@@ -0,0 +1,19 @@
+-- token usage and cost of every agent call. agents and chats may be removed,
+-- the usage is kept so it can still be reported
+CREATE TABLE IF NOT EXISTS agent_usage(
+  id BIGSERIAL PRIMARY KEY,
+  agent_id BIGINT NOT NULL,
+  chat_id BIGINT NOT NULL,
+  ws_id BIGINT NOT NULL REFERENCES workspaces(id),
+  adapter adapter_type NOT NULL,
+  model VARCHAR(255) NOT NULL,
+  prompt_tokens INT NOT NULL DEFAULT 0,
+  completion_tokens INT NOT NULL DEFAULT 0,
+  cache_hit_tokens INT NOT NULL DEFAULT 0,
+  cost DOUBLE PRECISION NOT NULL DEFAULT 0,
+  created_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS agent_usage_ws_id_created_at_index ON agent_usage(ws_id, created_at);
+
+CREATE INDEX IF NOT EXISTS agent_usage_agent_id_created_at_index ON agent_usage(agent_id, created_at);
@@ -0,0 +1,9 @@
+-- monthly ai budget of a workspace, NULL means unlimited
+CREATE TABLE IF NOT EXISTS workspace_budgets(
+  ws_id BIGINT PRIMARY KEY REFERENCES workspaces(id),
+  monthly_tokens BIGINT,
+  monthly_cost DOUBLE PRECISION,
+  -- max calls per minute of each agent in the workspace
+  agent_rate_limit INT,
+  updated_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP
+);
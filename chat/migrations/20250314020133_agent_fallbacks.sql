@@ -0,0 +1,6 @@
+-- providers tried in order when the primary adapter/model fails,
+-- e.g. [{"adapter": "deepseek", "model": "deepseek-chat"}]
+ALTER TABLE chat_agents ADD COLUMN fallbacks JSONB NOT NULL DEFAULT '[]';
+
+-- index of the provider that answered, 0 is the primary one
+ALTER TABLE agent_usage ADD COLUMN provider INT NOT NULL DEFAULT 0;
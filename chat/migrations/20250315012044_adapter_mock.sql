@@ -0,0 +1,2 @@
+-- add mock adapter, deterministic responses without network access
+ALTER TYPE adapter_type ADD VALUE IF NOT EXISTS 'mock';
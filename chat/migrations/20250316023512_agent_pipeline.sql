@@ -0,0 +1,20 @@
+-- agents of a chat run as a pipeline, in ascending priority then id
+ALTER TABLE chat_agents ADD COLUMN priority INT NOT NULL DEFAULT 0;
+
+CREATE TYPE agent_step_decision AS ENUM ('modify', 'reply', 'delete', 'none', 'skipped', 'failed');
+
+-- what every agent of the pipeline did with a message. message_id is null when
+-- the message was deleted by an agent and never stored
+CREATE TABLE IF NOT EXISTS agent_steps(
+  id BIGSERIAL PRIMARY KEY,
+  chat_id BIGINT NOT NULL REFERENCES chats(id),
+  message_id BIGINT REFERENCES messages(id) ON DELETE SET NULL,
+  agent_id BIGINT NOT NULL,
+  position INT NOT NULL,
+  decision agent_step_decision NOT NULL,
+  output TEXT,
+  error TEXT,
+  created_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS agent_steps_message_id_index ON agent_steps(message_id);
@@ -0,0 +1,56 @@
+CREATE TYPE job_status AS ENUM ('pending', 'running', 'done', 'dead');
+
+-- agent processing of messages, claimed by the workers with FOR UPDATE SKIP LOCKED.
+-- failed jobs are retried with backoff until max_attempts, then dead-lettered.
+-- a job whose message was deleted meanwhile is done, there is nothing left to run
+CREATE TABLE IF NOT EXISTS agent_jobs(
+  id BIGSERIAL PRIMARY KEY,
+  message_id BIGINT NOT NULL,
+  chat_id BIGINT NOT NULL,
+  ws_id BIGINT NOT NULL REFERENCES workspaces(id),
+  status job_status NOT NULL DEFAULT 'pending',
+  attempts INT NOT NULL DEFAULT 0,
+  max_attempts INT NOT NULL,
+  last_error TEXT,
+  run_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  locked_at TIMESTAMP WITH TIME ZONE,
+  created_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP,
+  updated_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS agent_jobs_status_run_at_index ON agent_jobs(status, run_at);
+
+CREATE INDEX IF NOT EXISTS agent_jobs_ws_id_status_index ON agent_jobs(ws_id, status);
+
+-- notify the chat members once the agents modified a message
+CREATE OR REPLACE FUNCTION add_to_message()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  USERS bigint[];
+BEGIN
+  SELECT
+    members INTO USERS
+  FROM
+    chats
+  WHERE
+    id = NEW.chat_id;
+  IF TG_OP = 'INSERT' THEN
+    RAISE NOTICE 'add_to_message: %', NEW;
+    PERFORM
+      pg_notify('chat_message_created', json_build_object('message', NEW, 'members', USERS)::text);
+  ELSIF TG_OP = 'UPDATE' AND NEW.modified_content IS DISTINCT FROM OLD.modified_content THEN
+    PERFORM
+      pg_notify('chat_message_updated', json_build_object('message', NEW, 'members', USERS)::text);
+  END IF;
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS add_to_message_trigger ON messages;
+
+CREATE TRIGGER add_to_message_trigger
+  AFTER INSERT OR UPDATE OF modified_content ON messages
+  FOR EACH ROW
+  EXECUTE FUNCTION add_to_message();
@@ -0,0 +1,12 @@
+-- bot users speak for the reply agents, they can't sign in
+ALTER TABLE users ADD COLUMN is_bot BOOLEAN NOT NULL DEFAULT false;
+
+ALTER TABLE chat_agents ADD COLUMN bot_id BIGINT REFERENCES users(id);
+
+-- a bot for every existing agent, in the workspace of its chat
+INSERT INTO users(ws_id, fullname, email, password_hash, is_bot)
+  SELECT c.ws_id, left(a.name, 64), 'agent-' || a.id || '@bot.chatapp', '', true
+  FROM chat_agents a JOIN chats c ON c.id = a.chat_id;
+
+UPDATE chat_agents a SET bot_id = u.id
+  FROM users u WHERE u.email = 'agent-' || a.id || '@bot.chatapp';
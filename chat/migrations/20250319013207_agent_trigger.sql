@@ -0,0 +1,5 @@
+-- which messages an agent is evaluated on, see AgentTrigger
+ALTER TABLE chat_agents ADD COLUMN trigger JSONB NOT NULL DEFAULT '{"mode": "always"}';
+
+-- replying to an agent's message triggers agents in mention mode
+ALTER TABLE messages ADD COLUMN reply_to BIGINT REFERENCES messages(id) ON DELETE SET NULL;
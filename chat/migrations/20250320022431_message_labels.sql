@@ -0,0 +1,52 @@
+-- what happens to a message labelled by a tap agent, in increasing severity
+CREATE TYPE label_action AS ENUM ('none', 'flag', 'hide', 'delete');
+
+ALTER TYPE agent_step_decision ADD VALUE 'label';
+
+-- label rules of tap agents, see LabelRule
+ALTER TABLE chat_agents ADD COLUMN policy JSONB NOT NULL DEFAULT '[]';
+
+ALTER TABLE messages ADD COLUMN hidden BOOLEAN NOT NULL DEFAULT false;
+
+-- labels the tap agents put on messages
+CREATE TABLE IF NOT EXISTS message_labels(
+  id BIGSERIAL PRIMARY KEY,
+  message_id BIGINT NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+  chat_id BIGINT NOT NULL REFERENCES chats(id),
+  agent_id BIGINT NOT NULL REFERENCES chat_agents(id) ON DELETE CASCADE,
+  label TEXT NOT NULL,
+  score REAL,
+  action label_action NOT NULL DEFAULT 'none',
+  created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS message_labels_message_id_index ON message_labels(message_id);
+
+CREATE INDEX IF NOT EXISTS message_labels_chat_id_action_index ON message_labels(chat_id, action);
+
+-- hidden messages are not sent to the chat members
+CREATE OR REPLACE FUNCTION add_to_message()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  USERS bigint[];
+BEGIN
+  SELECT
+    members INTO USERS
+  FROM
+    chats
+  WHERE
+    id = NEW.chat_id;
+  IF TG_OP = 'INSERT' AND NOT NEW.hidden THEN
+    RAISE NOTICE 'add_to_message: %', NEW;
+    PERFORM
+      pg_notify('chat_message_created', json_build_object('message', NEW, 'members', USERS)::text);
+  ELSIF TG_OP = 'UPDATE' AND NOT NEW.hidden AND NEW.modified_content IS DISTINCT FROM OLD.modified_content THEN
+    PERFORM
+      pg_notify('chat_message_updated', json_build_object('message', NEW, 'members', USERS)::text);
+  END IF;
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+
@@ -0,0 +1,2 @@
+-- paused agents are kept with their settings but not evaluated on messages
+ALTER TABLE chat_agents ADD COLUMN enabled BOOLEAN NOT NULL DEFAULT true;
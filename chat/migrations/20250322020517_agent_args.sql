@@ -0,0 +1,2 @@
+-- args are typed now, agents updated without args had them set to null
+UPDATE chat_agents SET args = '{}' WHERE jsonb_typeof(args) <> 'object';
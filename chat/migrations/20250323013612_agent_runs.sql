@@ -0,0 +1,20 @@
+-- every model call of an agent: what was sent, what came back and how long it took.
+-- replays run a past input against the agent's current config
+CREATE TABLE IF NOT EXISTS agent_runs(
+  id BIGSERIAL PRIMARY KEY,
+  chat_id BIGINT NOT NULL REFERENCES chats(id) ON DELETE CASCADE,
+  agent_id BIGINT NOT NULL REFERENCES chat_agents(id) ON DELETE CASCADE,
+  message_id BIGINT REFERENCES messages(id) ON DELETE SET NULL,
+  input TEXT NOT NULL,
+  prompt JSONB NOT NULL DEFAULT '[]',
+  response TEXT,
+  decision agent_step_decision NOT NULL,
+  output TEXT,
+  error TEXT,
+  latency_ms INT NOT NULL,
+  replay_of BIGINT REFERENCES agent_runs(id) ON DELETE SET NULL,
+  created_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS agent_runs_chat_id_index ON agent_runs(chat_id, id DESC);
+CREATE INDEX IF NOT EXISTS agent_runs_agent_id_index ON agent_runs(agent_id, id DESC);
@@ -0,0 +1,2 @@
+-- prompts are templates, the user prompt places the message in the user turn
+ALTER TABLE chat_agents ADD COLUMN user_prompt TEXT NOT NULL DEFAULT '';
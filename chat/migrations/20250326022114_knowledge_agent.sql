@@ -0,0 +1,2 @@
+-- knowledge agents answer from the chunks of a collection in the vector store
+ALTER TYPE agent_type ADD VALUE IF NOT EXISTS 'knowledge';
@@ -0,0 +1,24 @@
+CREATE TYPE ingest_status AS ENUM ('pending', 'running', 'done', 'failed');
+
+CREATE TYPE knowledge_collection AS ENUM ('docs', 'files', 'chat_history');
+
+-- uploaded files ingested into a collection of the workspace's knowledge base.
+-- the ids of its chunks are derived from the sha1 of the content, a file
+-- uploaded again is ingested once per collection
+CREATE TABLE IF NOT EXISTS knowledge_files(
+  id BIGSERIAL PRIMARY KEY,
+  ws_id BIGINT NOT NULL REFERENCES workspaces(id),
+  collection knowledge_collection NOT NULL,
+  hash CHAR(40) NOT NULL,
+  url VARCHAR NOT NULL,
+  name VARCHAR NOT NULL,
+  status ingest_status NOT NULL DEFAULT 'pending',
+  chunks INT NOT NULL DEFAULT 0,
+  error TEXT,
+  locked_at TIMESTAMP WITH TIME ZONE,
+  created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  UNIQUE (ws_id, collection, hash)
+);
+
+CREATE INDEX IF NOT EXISTS knowledge_files_status_index ON knowledge_files(status);
@@ -0,0 +1,18 @@
+-- max calls per minute of an agent, NULL means unlimited. Replaces the limit
+-- of the workspace budget, which agents already limited by it keep
+ALTER TABLE chat_agents ADD COLUMN rate_limit INT;
+
+UPDATE chat_agents a SET rate_limit = b.agent_rate_limit
+FROM chats c JOIN workspace_budgets b ON b.ws_id = c.ws_id
+WHERE c.id = a.chat_id;
+
+ALTER TABLE workspace_budgets DROP COLUMN agent_rate_limit;
+
+-- calls of rate limited agents, failed ones included. Only the last minute is kept
+CREATE TABLE IF NOT EXISTS agent_calls(
+  id BIGSERIAL PRIMARY KEY,
+  agent_id BIGINT NOT NULL REFERENCES chat_agents(id) ON DELETE CASCADE,
+  created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS agent_calls_agent_id_created_at_index ON agent_calls(agent_id, created_at);
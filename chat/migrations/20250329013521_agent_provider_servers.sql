@@ -0,0 +1,5 @@
+-- fallback providers name a server of chat.yml instead of their own url and key
+UPDATE chat_agents SET fallbacks = (
+  SELECT COALESCE(jsonb_agg(f - 'base_url' - 'api_key'), '[]')
+  FROM jsonb_array_elements(fallbacks) f
+);
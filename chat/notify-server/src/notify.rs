@@ -1,7 +1,7 @@
 use std::{collections::HashSet, sync::Arc};
 
 use crate::AppState;
-use chat_core::{Chat, Message};
+use chat_core::{Chat, Message, MessageDelta};
 use futures::StreamExt;
 use serde::{Deserialize, Serialize};
 use sqlx::postgres::PgListener;
@@ -14,6 +14,9 @@ pub enum AppEvent {
     AddToChat(Chat),
     RemoveFromChat(Chat),
     NewMessage(Message),
+    /// the message was modified by the agents of the chat
+    MessageUpdated(Message),
+    MessageDelta(MessageDelta),
 }
 
 #[derive(Debug)]
@@ -32,16 +35,26 @@ struct ChatUpdated {
 }
 
 // pg_notify('chat_message_created', row_to_json(NEW)::text);
+// pg_notify('chat_message_updated', ...) has the same payload
 #[derive(Debug, Serialize, Deserialize)]
 struct ChatMessageCreated {
     message: Message,
     members: Vec<i64>,
 }
 
+// pg_notify('chat_message_delta', json_build_object('delta', DELTA, 'members', MEMBERS)::text);
+#[derive(Debug, Serialize, Deserialize)]
+struct ChatMessageDelta {
+    delta: MessageDelta,
+    members: Vec<i64>,
+}
+
 pub async fn setup_pg_listener(state: AppState) -> anyhow::Result<()> {
     let mut listener = PgListener::connect(&state.config.server.db_url).await?;
     listener.listen("chat_updated").await?;
     listener.listen("chat_message_created").await?;
+    listener.listen("chat_message_updated").await?;
+    listener.listen("chat_message_delta").await?;
 
     let mut stream = listener.into_stream();
 
@@ -92,6 +105,22 @@ impl Notification {
                     event: Arc::new(AppEvent::NewMessage(payload.message)),
                 })
             }
+            "chat_message_updated" => {
+                let payload: ChatMessageCreated = serde_json::from_str(payload)?;
+                let user_ids = payload.members.iter().map(|v| *v as u64).collect();
+                Ok(Self {
+                    user_ids,
+                    event: Arc::new(AppEvent::MessageUpdated(payload.message)),
+                })
+            }
+            "chat_message_delta" => {
+                let payload: ChatMessageDelta = serde_json::from_str(payload)?;
+                let user_ids = payload.members.iter().map(|v| *v as u64).collect();
+                Ok(Self {
+                    user_ids,
+                    event: Arc::new(AppEvent::MessageDelta(payload.delta)),
+                })
+            }
             _ => Err(anyhow::anyhow!("Invalid notification type")),
         }
     }
@@ -114,3 +143,46 @@ fn get_affected_chat_user_ids(old: Option<&Chat>, new: Option<&Chat>) -> HashSet
         _ => HashSet::new(),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn message_delta_notification_should_load() -> anyhow::Result<()> {
+        let payload = r#"{"delta": {"id": 12, "chat_id": 3, "delta": "长江", "done": false}, "members": [1, 2]}"#;
+        let notification = Notification::load("chat_message_delta", payload)?;
+        assert_eq!(notification.user_ids, HashSet::from([1, 2]));
+        match notification.event.as_ref() {
+            AppEvent::MessageDelta(delta) => {
+                assert_eq!(delta.id, 12);
+                assert_eq!(delta.delta, "长江");
+                assert!(!delta.done);
+                assert!(!delta.failed);
+            }
+            _ => panic!("Expected MessageDelta event"),
+        }
+
+        // a failed reply is dropped by the members
+        let payload = r#"{"delta": {"id": 12, "chat_id": 3, "delta": "", "done": true, "failed": true}, "members": [1, 2]}"#;
+        let notification = Notification::load("chat_message_delta", payload)?;
+        assert!(
+            matches!(notification.event.as_ref(), AppEvent::MessageDelta(delta) if delta.failed)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn message_updated_notification_should_load() -> anyhow::Result<()> {
+        let payload = r#"{"message": {"id": 12, "chat_id": 3, "sender_id": 1, "content": "你好", "modified_content": "hello", "files": [], "created_at": "2025-03-17T01:43:26.123456+00:00"}, "members": [1, 2]}"#;
+        let notification = Notification::load("chat_message_updated", payload)?;
+        assert_eq!(notification.user_ids, HashSet::from([1, 2]));
+        match notification.event.as_ref() {
+            AppEvent::MessageUpdated(message) => {
+                assert_eq!(message.modified_content.as_deref(), Some("hello"))
+            }
+            _ => panic!("Expected MessageUpdated event"),
+        }
+        Ok(())
+    }
+}
@@ -38,6 +38,8 @@ pub(crate) async fn sse_handler(
             AppEvent::AddToChat(_) => "AddToChat",
             AppEvent::RemoveFromChat(_) => "RemoveFromChat",
             AppEvent::NewMessage(_) => "NewMessage",
+            AppEvent::MessageUpdated(_) => "MessageUpdated",
+            AppEvent::MessageDelta(_) => "MessageDelta",
         };
         let v = serde_json::to_string(&v).expect("Failed to serialize event");
         Ok(Event::default().data(v).event(name))
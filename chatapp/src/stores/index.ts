@@ -115,6 +115,28 @@ export const useMainStore = defineStore('main_store', () => {
     }
   }
 
+  function updateMessage(updated: IF.IMessage) {
+    const message = messages.value[updated.chat_id]?.find((message) => message.id === updated.id)
+    if (message) {
+      message.modified_content = updated.modified_content
+    }
+  }
+
+  function appendMessageDelta(delta: IF.IMessageDelta) {
+    const chat_messages = messages.value[delta.chat_id]
+    // the reply failed and was removed
+    if (delta.failed) {
+      if (chat_messages) {
+        messages.value[delta.chat_id] = chat_messages.filter((message) => message.id !== delta.id)
+      }
+      return
+    }
+    const message = chat_messages?.find((message) => message.id === delta.id)
+    if (message) {
+      message.content += delta.delta
+    }
+  }
+
   function getChannelMessages(channel_id: number): Array<IF.IMessage> {
     return messages.value[channel_id] || []
   }
@@ -250,6 +272,8 @@ export const useMainStore = defineStore('main_store', () => {
     // other ...
     addChannel,
     addMessage,
+    updateMessage,
+    appendMessageDelta,
     signin,
     signup,
     reset,
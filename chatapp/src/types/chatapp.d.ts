@@ -27,6 +27,7 @@ declare namespace IF {
     id: number
     fullname: string
     email: string
+    is_bot?: boolean
   }
 
   interface IChannel {
@@ -60,6 +61,14 @@ declare namespace IF {
     sender: IUserInner
   }
 
+  interface IMessageDelta {
+    id: number
+    chat_id: number
+    delta: string
+    done: boolean
+    failed?: boolean
+  }
+
   interface ISendMessage {
     content: string
     files?: Array<string>
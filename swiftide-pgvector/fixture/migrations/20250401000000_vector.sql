@@ -0,0 +1,2 @@
+-- the extension of the vector columns
+CREATE EXTENSION IF NOT EXISTS vector;
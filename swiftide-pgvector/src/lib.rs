@@ -1,5 +1,8 @@
 mod persist;
 mod retrieve;
+mod search_strategy;
+
+pub use search_strategy::{FilteredSimilarity, MetadataFilter};
 
 use anyhow::Result;
 use derive_builder::Builder;
@@ -30,6 +33,14 @@ impl PgVector {
     pub fn get_pool(&self) -> &PgPool {
         &self.pool
     }
+
+    pub fn table_name(&self) -> &str {
+        &self.table_name
+    }
+
+    pub fn vector_size(&self) -> i32 {
+        self.vector_size
+    }
 }
 
 #[cfg(test)]
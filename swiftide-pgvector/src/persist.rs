@@ -1,7 +1,8 @@
-use crate::PgVector;
-use anyhow::Result;
+use crate::{MetadataFilter, PgVector};
+use anyhow::{Result, bail};
 use async_trait::async_trait;
 use pgvector::Vector;
+use sqlx::{Postgres, QueryBuilder};
 use swiftide_core::{
     Persist,
     indexing::{EmbeddedField, IndexingStream, Node},
@@ -16,8 +17,8 @@ impl Persist for PgVector {
         let mut tx = poll.begin().await?;
 
         // create extension
-        let sql = format!("CREATE EXTENSION IF NOT EXISTS vector");
-        sqlx::query(&sql).execute(&mut *tx).await?;
+        let sql = "CREATE EXTENSION IF NOT EXISTS vector";
+        sqlx::query(sql).execute(&mut *tx).await?;
 
         // create table
         let sql = format!(
@@ -35,6 +36,20 @@ impl Persist for PgVector {
         );
         sqlx::query(&sql).execute(&mut *tx).await?;
 
+        // an existing table must hold vectors of the same size
+        let sql = "SELECT atttypmod FROM pg_attribute WHERE attrelid = $1::text::regclass AND attname = 'embedding'";
+        let size: i32 = sqlx::query_scalar(sql)
+            .bind(&self.table_name)
+            .fetch_one(&mut *tx)
+            .await?;
+        if size != self.vector_size {
+            bail!(
+                "vectors of {} have {size} dimensions, not {}",
+                self.table_name,
+                self.vector_size
+            );
+        }
+
         // create hnsw index
         let sql = format!(
             "CREATE INDEX IF NOT EXISTS {}_embedding_idx ON {} USING hnsw (embedding vector_cosine_ops)",
@@ -42,6 +57,13 @@ impl Persist for PgVector {
         );
         sqlx::query(&sql).execute(&mut *tx).await?;
 
+        // create gin index for metadata filters
+        let sql = format!(
+            "CREATE INDEX IF NOT EXISTS {}_metadata_idx ON {} USING gin (metadata jsonb_path_ops)",
+            self.table_name, self.table_name
+        );
+        sqlx::query(&sql).execute(&mut *tx).await?;
+
         tx.commit().await?;
         Ok(())
     }
@@ -67,6 +89,23 @@ impl Persist for PgVector {
 }
 
 impl PgVector {
+    /// delete the nodes matching all the filters, returns how many were deleted
+    pub async fn delete(&self, filters: &[MetadataFilter]) -> Result<u64> {
+        if filters.is_empty() {
+            bail!("at least one filter is needed to delete nodes");
+        }
+        let mut builder: QueryBuilder<Postgres> =
+            QueryBuilder::new(format!("DELETE FROM {} WHERE TRUE", self.table_name));
+        for filter in filters {
+            builder.push(" AND ");
+            filter.push_sql(&mut builder);
+        }
+        info!("Running delete with SQL: {}", builder.sql());
+        let result = builder.build().execute(self.get_pool()).await?;
+
+        Ok(result.rows_affected())
+    }
+
     async fn store_nodes(&self, nodes: &[Node]) -> Result<()> {
         let pool = self.get_pool();
         let mut tx = pool.begin().await?;
@@ -81,7 +120,7 @@ impl PgVector {
                 .vectors
                 .as_ref()
                 .and_then(|v| v.get(&EmbeddedField::Combined))
-                .map(|v| v.iter().map(|f| *f).collect::<Vec<_>>())
+                .map(|v| v.to_vec())
                 .unwrap_or_default();
 
             let sql = format!(
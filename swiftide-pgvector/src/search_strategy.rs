@@ -0,0 +1,118 @@
+use serde_json::{Map, Value};
+use sqlx::{Postgres, QueryBuilder, types::Json};
+use swiftide_core::querying::SearchStrategy;
+
+/// a predicate on the stored nodes, all filters of a search must match
+#[derive(Debug, Clone, PartialEq)]
+pub enum MetadataFilter {
+    /// the metadata key holds the value, e.g. `ws_id = 1`
+    Eq(String, Value),
+    /// the metadata contains the json document, see jsonb `@>`
+    Contains(Value),
+    /// the node path starts with the prefix
+    PathPrefix(String),
+}
+
+impl MetadataFilter {
+    pub fn eq(key: impl Into<String>, value: impl Into<Value>) -> Self {
+        Self::Eq(key.into(), value.into())
+    }
+
+    pub fn contains(value: impl Into<Value>) -> Self {
+        Self::Contains(value.into())
+    }
+
+    pub fn path_prefix(prefix: impl Into<String>) -> Self {
+        Self::PathPrefix(prefix.into())
+    }
+
+    /// push the predicate, with its values bound as parameters
+    pub(crate) fn push_sql(&self, builder: &mut QueryBuilder<'_, Postgres>) {
+        match self {
+            // containment of a single key document, so the gin index on metadata is used
+            Self::Eq(key, value) => {
+                let doc = Map::from_iter([(key.clone(), value.clone())]);
+                builder
+                    .push("metadata @> ")
+                    .push_bind(Json(Value::Object(doc)));
+            }
+            Self::Contains(value) => {
+                builder.push("metadata @> ").push_bind(Json(value.clone()));
+            }
+            Self::PathPrefix(prefix) => {
+                builder
+                    .push("starts_with(path, ")
+                    .push_bind(prefix.clone())
+                    .push(")");
+            }
+        }
+    }
+}
+
+/// similarity search on the query embedding restricted to the nodes matching
+/// the filters, e.g. the ones of a workspace when one table holds many
+#[derive(Debug, Clone)]
+pub struct FilteredSimilarity {
+    top_k: u64,
+    filters: Vec<MetadataFilter>,
+}
+
+impl FilteredSimilarity {
+    pub fn new(top_k: u64) -> Self {
+        Self {
+            top_k,
+            filters: Vec::new(),
+        }
+    }
+
+    pub fn with_top_k(mut self, top_k: u64) -> Self {
+        self.top_k = top_k;
+        self
+    }
+
+    pub fn with_filter(mut self, filter: MetadataFilter) -> Self {
+        self.filters.push(filter);
+        self
+    }
+
+    pub fn top_k(&self) -> u64 {
+        self.top_k
+    }
+
+    pub fn filters(&self) -> &[MetadataFilter] {
+        &self.filters
+    }
+}
+
+impl Default for FilteredSimilarity {
+    fn default() -> Self {
+        Self::new(crate::retrieve::DEFAULT_LIMIT as _)
+    }
+}
+
+impl SearchStrategy for FilteredSimilarity {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn filters_should_be_bound_as_parameters() {
+        let strategy = FilteredSimilarity::default()
+            .with_filter(MetadataFilter::eq("ws_id", 1))
+            .with_filter(MetadataFilter::contains(json!({"chat_id": 2})))
+            .with_filter(MetadataFilter::path_prefix("src/"));
+        assert_eq!(strategy.top_k(), 5);
+
+        let mut builder = QueryBuilder::new("SELECT id, chunk FROM t WHERE TRUE");
+        for filter in strategy.filters() {
+            builder.push(" AND ");
+            filter.push_sql(&mut builder);
+        }
+        assert_eq!(
+            builder.sql(),
+            "SELECT id, chunk FROM t WHERE TRUE AND metadata @> $1 AND metadata @> $2 AND starts_with(path, $3)"
+        );
+    }
+}